@@ -0,0 +1,374 @@
+use crate::db;
+use crate::migrations::{self, MIGRATIONS};
+use crate::services::crypto::SECRET_KEY_LEN;
+use crate::services::emails::{self, TEMPLATES};
+use crate::services::mail::build_message;
+use crate::services::templates::{TemplateRegistry, TEMPLATE_DIR};
+use crate::settings::secrets::{encrypt_value, master_key};
+use crate::settings::Settings;
+use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dotenvy::dotenv;
+use lettre::message::Mailbox;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io::{Error, Read};
+use std::path::{Path, PathBuf};
+use tera::Context;
+
+const PREVIEW_FROM: &str = "auth-rs <no-reply@localhost>";
+const PREVIEW_TO: &str = "user@example.com";
+
+const PREVIEW_USAGE: &str = "usage: preview-email [--template NAME] [--locale LOCALE] \
+[--format eml|html] [--out DIR] [--serve ADDR]";
+
+const MIGRATE_USAGE: &str = "usage: migrate list | migrate apply [--dry-run]";
+
+const SECRETS_USAGE: &str = "usage: secrets keygen | secrets encrypt SETTING < value";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PreviewFormat {
+    Eml,
+    Html,
+}
+
+struct PreviewArgs {
+    template: Option<String>,
+    locale: Option<String>,
+    format: PreviewFormat,
+    out: PathBuf,
+    serve: Option<String>,
+}
+
+fn usage(message: &str) -> Error {
+    Error::other(format!("{}\n{}", message, PREVIEW_USAGE))
+}
+
+fn parse_preview_args(args: &[String]) -> Result<PreviewArgs, Error> {
+    let mut parsed = PreviewArgs {
+        template: None,
+        locale: None,
+        format: PreviewFormat::Eml,
+        out: PathBuf::from("target/email-preview"),
+        serve: None,
+    };
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| usage(&format!("missing value for {}", flag)))
+        };
+        match flag.as_str() {
+            "--template" => parsed.template = Some(value()?),
+            "--locale" => parsed.locale = Some(value()?),
+            "--out" => parsed.out = PathBuf::from(value()?),
+            "--serve" => parsed.serve = Some(value()?),
+            "--format" => {
+                parsed.format = match value()?.as_str() {
+                    "eml" => PreviewFormat::Eml,
+                    "html" => PreviewFormat::Html,
+                    other => return Err(usage(&format!("unknown format {}", other))),
+                }
+            }
+            other => return Err(usage(&format!("unknown argument {}", other))),
+        }
+    }
+
+    if let Some(template) = &parsed.template {
+        if !TEMPLATES.contains(&template.as_str()) {
+            return Err(usage(&format!(
+                "unknown template {}, expected one of {}",
+                template,
+                TEMPLATES.join(", ")
+            )));
+        }
+    }
+    Ok(parsed)
+}
+
+fn sample_context(template: &str) -> Result<Context, Error> {
+    emails::samples()
+        .map_err(|err| Error::other(format!("Error building sample data: {}", err)))?
+        .into_iter()
+        .find(|(name, _)| *name == template)
+        .map(|(_, context)| context)
+        .ok_or_else(|| Error::other(format!("no sample data for {}", template)))
+}
+
+/// Renders one email the way `MailService::send` does and returns it in
+/// `format`.
+pub fn render_preview(
+    templates: &TemplateRegistry,
+    template: &str,
+    locale: &str,
+    format: PreviewFormat,
+) -> Result<Vec<u8>, Error> {
+    let context = sample_context(template)?;
+    let rendered = templates
+        .render(Some(locale), template, &context)
+        .map_err(|err| Error::other(format!("Error rendering {}/{}: {}", locale, template, err)))?;
+
+    match format {
+        PreviewFormat::Html => Ok(rendered
+            .html
+            .unwrap_or_else(|| format!("<pre>{}</pre>", tera::escape_html(&rendered.text)))
+            .into_bytes()),
+        PreviewFormat::Eml => {
+            let from = PREVIEW_FROM.parse::<Mailbox>().unwrap();
+            let message = build_message(&from, PREVIEW_TO, rendered).map_err(|err| {
+                Error::other(format!("Error building {}/{}: {}", locale, template, err))
+            })?;
+            Ok(message.formatted())
+        }
+    }
+}
+
+/// Writes `<out>/<locale>/<template>.<eml|html>` for every selected email
+/// and returns the paths written.
+pub fn write_previews(
+    templates: &TemplateRegistry,
+    template: Option<&str>,
+    locale: Option<&str>,
+    format: PreviewFormat,
+    out: &Path,
+) -> Result<Vec<PathBuf>, Error> {
+    let extension = match format {
+        PreviewFormat::Eml => "eml",
+        PreviewFormat::Html => "html",
+    };
+    let locales: Vec<&str> = match locale {
+        Some(locale) => vec![templates
+            .supported(locale)
+            .ok_or_else(|| Error::other(format!("no templates for locale {}", locale)))?],
+        None => templates.locales().collect(),
+    };
+
+    let mut written = Vec::new();
+    for locale in locales {
+        std::fs::create_dir_all(out.join(locale))?;
+        for name in TEMPLATES
+            .iter()
+            .filter(|name| template.is_none_or(|template| template == **name))
+        {
+            let path = out.join(locale).join(format!("{}.{}", name, extension));
+            std::fs::write(&path, render_preview(templates, name, locale, format)?)?;
+            written.push(path);
+        }
+    }
+    Ok(written)
+}
+
+#[get("/")]
+async fn preview_index(templates: web::Data<TemplateRegistry>) -> impl Responder {
+    let mut rows = String::new();
+    for name in TEMPLATES {
+        let links = templates
+            .locales()
+            .map(|locale| {
+                format!(
+                    "<a href=\"/{locale}/{name}.html\">{locale}</a> \
+                     (<a href=\"/{locale}/{name}.eml\">eml</a>)"
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        rows.push_str(&format!("<li>{} &middot; {}</li>\n", name, links));
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"UTF-8\" /><title>Email previews</title></head>\n<body>\n<ul>\n{}</ul>\n</body>\n</html>\n",
+            rows
+        ))
+}
+
+#[get("/{locale}/{file}")]
+async fn preview_email(
+    path: web::Path<(String, String)>,
+    templates: web::Data<TemplateRegistry>,
+) -> impl Responder {
+    let (locale, file) = path.into_inner();
+    let (name, format, content_type) = match file.rsplit_once('.') {
+        Some((name, "html")) => (name, PreviewFormat::Html, "text/html; charset=utf-8"),
+        Some((name, "eml")) => (name, PreviewFormat::Eml, "text/plain; charset=utf-8"),
+        _ => return HttpResponse::NotFound().body("not found"),
+    };
+    if !TEMPLATES.contains(&name) {
+        return HttpResponse::NotFound().body("not found");
+    }
+
+    match render_preview(&templates, name, &locale, format) {
+        Ok(body) => HttpResponse::Ok().content_type(content_type).body(body),
+        Err(err) => HttpResponse::InternalServerError()
+            .content_type("text/plain; charset=utf-8")
+            .body(err.to_string()),
+    }
+}
+
+/// `preview-email`: renders the email catalogue with sample data to files,
+/// or serves a live preview index when `--serve` is given. Any template that
+/// fails to render makes the command fail.
+pub async fn preview_email_command(args: &[String]) -> Result<(), Error> {
+    let args = parse_preview_args(args)?;
+    let hot_reload = args.serve.is_some();
+    let templates = TemplateRegistry::load(TEMPLATE_DIR, hot_reload)
+        .map_err(|err| Error::other(format!("Error loading email templates: {}", err)))?;
+
+    let written = write_previews(
+        &templates,
+        args.template.as_deref(),
+        args.locale.as_deref(),
+        args.format,
+        &args.out,
+    )?;
+    println!(
+        "Rendered {} emails into {}",
+        written.len(),
+        args.out.display()
+    );
+
+    if let Some(addr) = args.serve {
+        println!("Serving email previews on http://{}", addr);
+        let templates = web::Data::new(templates);
+        HttpServer::new(move || {
+            App::new()
+                .app_data(templates.clone())
+                .service(preview_index)
+                .service(preview_email)
+        })
+        .bind(addr)?
+        .run()
+        .await?;
+    }
+    Ok(())
+}
+
+/// `migrate`: lists every migration with whether it has been applied, or
+/// applies the pending ones. `apply --dry-run` only prints what would run.
+pub async fn migrate_command(args: &[String]) -> Result<(), Error> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let dry_run = match args.as_slice() {
+        ["list"] => None,
+        ["apply"] => Some(false),
+        ["apply", "--dry-run"] => Some(true),
+        _ => return Err(Error::other(MIGRATE_USAGE)),
+    };
+    let settings =
+        Settings::load().map_err(|err| Error::other(format!("Error loading settings: {}", err)))?;
+    let (_, db) = db::mongo_client(&settings.mongo)
+        .await
+        .map_err(|err| Error::other(format!("Error connecting to MongoDB: {}", err)))?;
+
+    let Some(dry_run) = dry_run else {
+        let applied = migrations::applied(&db)
+            .await
+            .map_err(|err| Error::other(format!("Error loading migrations: {}", err)))?;
+        for migration in MIGRATIONS {
+            let state = match applied
+                .iter()
+                .find(|record| record.version == migration.version)
+            {
+                Some(record) => format!(
+                    "applied {}",
+                    record
+                        .applied_at
+                        .try_to_rfc3339_string()
+                        .unwrap_or_default()
+                ),
+                None => "pending".to_string(),
+            };
+            println!(
+                "{:>4}  {:<24} {:<32} {}",
+                migration.version, migration.name, state, migration.description
+            );
+        }
+        for record in applied
+            .iter()
+            .filter(|record| !MIGRATIONS.iter().any(|m| m.version == record.version))
+        {
+            println!(
+                "{:>4}  {:<24} applied by a newer release",
+                record.version, record.name
+            );
+        }
+        return Ok(());
+    };
+
+    let pending = migrations::apply(&db, dry_run)
+        .await
+        .map_err(|err| Error::other(format!("Error applying migrations: {}", err)))?;
+    if pending.is_empty() {
+        println!("No pending migrations");
+    }
+    for migration in pending {
+        println!(
+            "{} {} {}: {}",
+            if dry_run { "Would apply" } else { "Applied" },
+            migration.version,
+            migration.name,
+            migration.description
+        );
+    }
+    Ok(())
+}
+
+/// `secrets`: generates a `SECRETS_KEY`, or encrypts a value read from
+/// stdin for one setting of the secrets file, e.g. `secrets encrypt
+/// smtp.password`.
+pub fn secrets_command(args: &[String]) -> Result<(), Error> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["keygen"] => {
+            let mut key = [0u8; SECRET_KEY_LEN];
+            SystemRandom::new()
+                .fill(&mut key)
+                .map_err(|_| Error::other("Error generating key"))?;
+            println!("{}", STANDARD.encode(key));
+            Ok(())
+        }
+        ["encrypt", setting] => {
+            dotenv().ok();
+            let key = master_key(&|name| std::env::var(name).ok())
+                .map_err(|err| Error::other(err.to_string()))?
+                .ok_or_else(|| Error::other("SECRETS_KEY is not set"))?;
+            let mut value = String::new();
+            std::io::stdin().read_to_string(&mut value)?;
+            let ciphertext = encrypt_value(&key, setting, value.trim_end_matches(['\r', '\n']))
+                .map_err(|err| Error::other(format!("Error encrypting {}: {}", setting, err)))?;
+            println!("{}", ciphertext);
+            Ok(())
+        }
+        _ => Err(Error::other(SECRETS_USAGE)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_previews_renders_catalogue() {
+        let templates = TemplateRegistry::load(TEMPLATE_DIR, false).unwrap();
+        let out = std::env::temp_dir().join(format!("auth-rs-preview-{}", std::process::id()));
+
+        let written =
+            write_previews(&templates, None, Some("ja"), PreviewFormat::Eml, &out).unwrap();
+        assert_eq!(written.len(), TEMPLATES.len());
+        let eml = std::fs::read_to_string(out.join("ja/confirm_email.eml")).unwrap();
+        assert!(eml.contains("multipart/alternative"));
+
+        let written = write_previews(
+            &templates,
+            Some("account_locked"),
+            None,
+            PreviewFormat::Html,
+            &out,
+        )
+        .unwrap();
+        assert_eq!(written.len(), templates.locales().count());
+
+        std::fs::remove_dir_all(&out).unwrap();
+    }
+}
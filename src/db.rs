@@ -1,31 +1,28 @@
-use dotenvy::dotenv;
+use crate::settings::MongoSettings;
 use mongodb::{error::Error as MongoError, options::ClientOptions, Client, Database};
-use std::{env, io};
+use std::io;
 
-pub async fn mongo_client() -> Result<(Client, Database), MongoError> {
-    dotenv().ok();
-    let mongo_uri = env::var("MONGO_URI").map_err(|err| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to get MONGO_URI: {}", err),
-        )
-    })?;
-    let database = env::var("MONGO_DATABASE").map_err(|err| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to get MONGO_DATABASE: {}", err),
-        )
-    })?;
-
-    let client_options = ClientOptions::parse(&mongo_uri).await.map_err(|err| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to parse client options: {}", err),
-        )
-    })?;
+pub async fn mongo_client(settings: &MongoSettings) -> Result<(Client, Database), MongoError> {
+    let client_options = ClientOptions::parse(settings.uri.expose())
+        .await
+        .map_err(|err| io::Error::other(format!("Failed to parse client options: {}", err)))?;
 
     let client = Client::with_options(client_options)?;
-    let db = client.database(&database);
+    let db = client.database(&settings.database);
 
     Ok((client, db))
 }
+
+/// A span around one MongoDB call, logged with its duration when debug
+/// logging is enabled.
+pub fn span(collection: &'static str, operation: &'static str) -> tracing::Span {
+    tracing::debug_span!("mongo", collection, operation)
+}
+
+/// Whether a write failed on a unique index.
+pub fn is_duplicate_key(err: &MongoError) -> bool {
+    matches!(
+        err.kind.as_ref(),
+        mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(e)) if e.code == 11000
+    )
+}
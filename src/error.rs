@@ -0,0 +1,329 @@
+use crate::repositories::RepositoryError;
+use crate::services::mail::EmailError;
+use crate::services::mfa::MfaError;
+use crate::services::otp::OtpError;
+use crate::services::token::TokenError;
+use crate::services::webauthn::WebauthnError;
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use serde_json::json;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use validator::ValidationErrors;
+
+/// An error returned by a handler. Clients get a stable machine-readable
+/// `code` and a short message; the detail of internal errors is only logged.
+#[derive(Debug)]
+pub enum ApiError {
+    Validation(ValidationErrors),
+    Unauthorized,
+    Forbidden,
+    /// What was not found, e.g. "user not found".
+    NotFound(&'static str),
+    InvalidCredentials,
+    InvalidPassword,
+    InvalidOtp,
+    InvalidMfaToken,
+    MfaTokenRequired,
+    InvalidTotpCode,
+    TotpCodeRequired,
+    TotpNotEnabled,
+    NoTotpEnrollment,
+    TotpEnrollmentChanged,
+    NoSecondFactor,
+    InvalidRecoveryCode,
+    NoPasskeyRegistered,
+    PasskeyAlreadyRegistered,
+    InvalidPasskey,
+    InvalidWebauthnChallenge,
+    InvalidWebauthnResponse(String),
+    InvalidRefreshToken,
+    InvalidResetToken,
+    InvalidUnlockToken,
+    InvalidUserId,
+    InvalidEmailReport(String),
+    EmailUndeliverable,
+    TooManyAttempts {
+        retry_after: i64,
+    },
+    /// A rate limit rule matched; `retry_after` is in seconds.
+    RateLimited {
+        retry_after: u64,
+    },
+    /// Anything the client cannot act on. `context` says what the handler
+    /// was doing and `detail` holds the underlying error.
+    Internal {
+        context: &'static str,
+        detail: String,
+    },
+}
+
+impl ApiError {
+    pub fn internal(context: &'static str, err: impl Display) -> Self {
+        ApiError::Internal {
+            context,
+            detail: err.to_string(),
+        }
+    }
+
+    fn with_context(self, context: &'static str) -> Self {
+        match self {
+            ApiError::Internal { detail, .. } => ApiError::Internal { context, detail },
+            other => other,
+        }
+    }
+
+    /// The stable code clients match on. Never change an existing one.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Forbidden => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::InvalidPassword => "invalid_password",
+            ApiError::InvalidOtp => "invalid_otp",
+            ApiError::InvalidMfaToken => "invalid_mfa_token",
+            ApiError::MfaTokenRequired => "mfa_token_required",
+            ApiError::InvalidTotpCode => "invalid_totp_code",
+            ApiError::TotpCodeRequired => "totp_code_required",
+            ApiError::TotpNotEnabled => "totp_not_enabled",
+            ApiError::NoTotpEnrollment => "no_totp_enrollment",
+            ApiError::TotpEnrollmentChanged => "totp_enrollment_changed",
+            ApiError::NoSecondFactor => "no_second_factor",
+            ApiError::InvalidRecoveryCode => "invalid_recovery_code",
+            ApiError::NoPasskeyRegistered => "no_passkey_registered",
+            ApiError::PasskeyAlreadyRegistered => "passkey_already_registered",
+            ApiError::InvalidPasskey => "invalid_passkey",
+            ApiError::InvalidWebauthnChallenge => "invalid_webauthn_challenge",
+            ApiError::InvalidWebauthnResponse(_) => "invalid_webauthn_response",
+            ApiError::InvalidRefreshToken => "invalid_refresh_token",
+            ApiError::InvalidResetToken => "invalid_reset_token",
+            ApiError::InvalidUnlockToken => "invalid_unlock_token",
+            ApiError::InvalidUserId => "invalid_user_id",
+            ApiError::InvalidEmailReport(_) => "invalid_email_report",
+            ApiError::EmailUndeliverable => "email_undeliverable",
+            ApiError::TooManyAttempts { .. } => "too_many_attempts",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::Internal { .. } => "internal_error",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            ApiError::Validation(_) => "invalid request",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Forbidden => "forbidden",
+            ApiError::NotFound(message) => message,
+            ApiError::InvalidCredentials => "invalid email or password",
+            ApiError::InvalidPassword => "invalid password",
+            ApiError::InvalidOtp => "invalid or expired otp",
+            ApiError::InvalidMfaToken => "invalid or expired mfa token",
+            ApiError::MfaTokenRequired => "mfa token required",
+            ApiError::InvalidTotpCode => "invalid totp code",
+            ApiError::TotpCodeRequired => "totp code required",
+            ApiError::TotpNotEnabled => "totp is not enabled",
+            ApiError::NoTotpEnrollment => "no totp enrollment in progress",
+            ApiError::TotpEnrollmentChanged => "totp enrollment changed, please enroll again",
+            ApiError::NoSecondFactor => "no second factor is enabled",
+            ApiError::InvalidRecoveryCode => "invalid recovery code",
+            ApiError::NoPasskeyRegistered => "no passkey registered",
+            ApiError::PasskeyAlreadyRegistered => "passkey already registered",
+            ApiError::InvalidPasskey => "invalid passkey",
+            ApiError::InvalidWebauthnChallenge => "invalid or expired webauthn challenge",
+            ApiError::InvalidWebauthnResponse(message) => message,
+            ApiError::InvalidRefreshToken => "invalid refresh token",
+            ApiError::InvalidResetToken => "invalid or expired reset token",
+            ApiError::InvalidUnlockToken => "invalid or expired unlock token",
+            ApiError::InvalidUserId => "invalid user id",
+            ApiError::InvalidEmailReport(message) => message,
+            ApiError::EmailUndeliverable => "email address is not deliverable",
+            ApiError::TooManyAttempts { .. } => "too many failed login attempts",
+            ApiError::RateLimited { .. } => "too many requests",
+            ApiError::Internal { .. } => "internal server error",
+        }
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            ApiError::Internal { context, detail } => write!(f, "{}: {}", context, detail),
+            ApiError::Validation(errors) => write!(f, "{}: {}", self.code(), errors),
+            other => write!(f, "{}: {}", other.code(), other.message()),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Validation(_)
+            | ApiError::InvalidOtp
+            | ApiError::MfaTokenRequired
+            | ApiError::TotpCodeRequired
+            | ApiError::TotpNotEnabled
+            | ApiError::NoTotpEnrollment
+            | ApiError::NoSecondFactor
+            | ApiError::NoPasskeyRegistered
+            | ApiError::InvalidWebauthnResponse(_)
+            | ApiError::InvalidResetToken
+            | ApiError::InvalidUnlockToken
+            | ApiError::InvalidUserId
+            | ApiError::InvalidEmailReport(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized
+            | ApiError::InvalidCredentials
+            | ApiError::InvalidPassword
+            | ApiError::InvalidMfaToken
+            | ApiError::InvalidTotpCode
+            | ApiError::InvalidRecoveryCode
+            | ApiError::InvalidPasskey
+            | ApiError::InvalidWebauthnChallenge
+            | ApiError::InvalidRefreshToken => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::TotpEnrollmentChanged | ApiError::PasskeyAlreadyRegistered => {
+                StatusCode::CONFLICT
+            }
+            ApiError::EmailUndeliverable => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::TooManyAttempts { .. } | ApiError::RateLimited { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            ApiError::Internal { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut body = json!({
+            "code": self.code(),
+            "error": self.message(),
+        });
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            ApiError::Validation(errors) => body["details"] = json!(errors),
+            ApiError::TooManyAttempts { retry_after } => {
+                body["retry_after"] = json!(retry_after);
+                response.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+            }
+            ApiError::RateLimited { retry_after } => {
+                body["retry_after"] = json!(retry_after);
+                response.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+            }
+            ApiError::Internal { context, detail } => {
+                tracing::error!(context, detail = %detail, "Internal error")
+            }
+            _ => {}
+        }
+        response.json(body)
+    }
+}
+
+impl From<ValidationErrors> for ApiError {
+    fn from(errors: ValidationErrors) -> Self {
+        ApiError::Validation(errors)
+    }
+}
+
+impl From<mongodb::error::Error> for ApiError {
+    fn from(err: mongodb::error::Error) -> Self {
+        ApiError::internal("database error", err)
+    }
+}
+
+impl From<RepositoryError> for ApiError {
+    fn from(err: RepositoryError) -> Self {
+        ApiError::internal("database error", err)
+    }
+}
+
+impl From<bcrypt::BcryptError> for ApiError {
+    fn from(err: bcrypt::BcryptError) -> Self {
+        ApiError::internal("password hashing error", err)
+    }
+}
+
+impl From<OtpError> for ApiError {
+    fn from(err: OtpError) -> Self {
+        ApiError::internal("otp error", err)
+    }
+}
+
+impl From<EmailError> for ApiError {
+    fn from(err: EmailError) -> Self {
+        match err {
+            EmailError::Suppressed(_) => ApiError::EmailUndeliverable,
+            err => ApiError::internal("email error", err),
+        }
+    }
+}
+
+impl From<MfaError> for ApiError {
+    fn from(err: MfaError) -> Self {
+        match err {
+            MfaError::NotEnrolled => ApiError::TotpNotEnabled,
+            err => ApiError::internal("mfa error", err),
+        }
+    }
+}
+
+impl From<TokenError> for ApiError {
+    fn from(err: TokenError) -> Self {
+        match err {
+            TokenError::InvalidToken | TokenError::ReuseDetected => ApiError::InvalidRefreshToken,
+            err => ApiError::internal("token error", err),
+        }
+    }
+}
+
+impl From<WebauthnError> for ApiError {
+    fn from(err: WebauthnError) -> Self {
+        match err {
+            WebauthnError::InvalidResponse(message) => ApiError::InvalidWebauthnResponse(message),
+            err => ApiError::internal("webauthn error", err),
+        }
+    }
+}
+
+/// Says what a handler was doing when an unexpected error occurred, for the
+/// server log.
+pub trait Context<T> {
+    fn context(self, context: &'static str) -> Result<T, ApiError>;
+}
+
+impl<T, E: Into<ApiError>> Context<T> for Result<T, E> {
+    fn context(self, context: &'static str) -> Result<T, ApiError> {
+        self.map_err(|err| err.into().with_context(context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+
+    #[actix_web::test]
+    async fn test_internal_detail_is_not_sent_to_client() {
+        let err: Result<(), _> = Err(OtpError::ChronoError("secret detail".to_string()));
+        let err = err.context("failed to insert otp").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to insert otp: ChronoError: secret detail"
+        );
+
+        let response = err.error_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "code": "internal_error", "error": "internal server error" })
+        );
+
+        let response =
+            ApiError::from(EmailError::Suppressed("a@example.com".into())).error_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let response = ApiError::TooManyAttempts { retry_after: 30 }.error_response();
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "30");
+    }
+}
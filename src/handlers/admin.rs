@@ -0,0 +1,62 @@
+use crate::error::{ApiError, Context};
+use crate::middleware::auth::Principal;
+use crate::models::user::{RoleType, User};
+use crate::services::lockout::{self, LoginFailures};
+use crate::services::security_event::{SecurityEvent, SecurityEventKind};
+use crate::AppState;
+use actix_web::{post, web, HttpResponse};
+use mongodb::bson::oid::ObjectId;
+use serde_json::json;
+
+/// Loads the caller and checks they are an administrator.
+async fn require_admin(data: &AppState, principal: &Principal) -> Result<User, ApiError> {
+    match data
+        .repos
+        .users
+        .find_by_id(principal.user_id)
+        .await
+        .context("failed to find user")?
+    {
+        Some(user) if user.role == Some(RoleType::Admin) => Ok(user),
+        _ => Err(ApiError::Forbidden),
+    }
+}
+
+#[post("/admin/users/{user_id}/unlock")]
+async fn unlock_user(
+    principal: Principal,
+    user_id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    require_admin(&data, &principal).await?;
+
+    let user_id = ObjectId::parse_str(user_id.as_str()).map_err(|_| ApiError::InvalidUserId)?;
+    let user = data
+        .repos
+        .users
+        .find_by_id(user_id)
+        .await
+        .context("failed to find user")?
+        .ok_or(ApiError::NotFound("user not found"))?;
+
+    LoginFailures::clear(
+        data.repos.login_failures.as_ref(),
+        &lockout::account_key(&user.email),
+    )
+    .await
+    .context("failed to unlock account")?;
+
+    let mut event = SecurityEvent::new(SecurityEventKind::AccountUnlocked);
+    event.email = Some(user.email);
+    event.user_id = Some(user_id);
+    event.actor_id = Some(principal.user_id);
+    event.emit(data.repos.security_events.as_ref()).await;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "account unlocked successfully"
+    })))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(unlock_user);
+}
@@ -1,265 +1,1244 @@
-use crate::{
-    models::user::{ForgotPassword, ResendOtp, User}, services, AppState
+use crate::error::{ApiError, Context};
+use crate::repositories::RepositoryError;
+use crate::services::{
+    emails::{self, AccountLocked, PasswordChanged},
+    lockout::{self, LoginFailures, ACCOUNT_POLICY, IP_POLICY, LOCKOUT_MINUTES},
+    mail::EmailError,
+    mfa::{self, MfaChallenge},
+    otp::{Otp, OtpError},
+    password_reset::{PasswordReset, PASSWORD_RESET_TTL_MINUTES},
+    security_event::{SecurityEvent, SecurityEventKind},
+    session::{SESSION_EMAIL_KEY, SESSION_USER_KEY},
+    token::RefreshToken,
+    webauthn::{self, Ceremony, WebauthnChallenge},
 };
-use crate::services::{mail, otp::Otp};
-use actix_web::{
-    cookie::{time::Duration as CookieDuration, Cookie},
-    post, web, HttpResponse, Responder,
+use crate::{
+    models::user::{
+        ForgotPassword, Login, MfaLogin, PasskeyLogin, PasskeyLoginOptions, RecoveryLogin,
+        RefreshTokenRequest, ResendOtp, ResetPassword, UnlockAccount, User, VerifyOtp,
+    },
+    AppState,
 };
-use bcrypt;
-use mongodb::{bson::doc, error::Error, options::IndexOptions, Collection, Database, IndexModel};
+use actix_session::Session;
+use actix_web::{http::header, post, web, HttpRequest, HttpResponse};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use mongodb::bson::oid::ObjectId;
 use serde_json::json;
 use validator::Validate;
 
+/// Delivers a verification code. A suppressed address is not mailed, but the
+/// response does not say so: that would tell whether it bounced before.
+async fn send_otp(
+    data: &AppState,
+    email: &str,
+    locale: Option<&str>,
+    code: u32,
+) -> Result<(), ApiError> {
+    match data
+        .otp_delivery
+        .deliver(&data.mail, email, locale, code)
+        .await
+    {
+        Err(EmailError::Suppressed(_)) => {
+            tracing::info!("Skipped verification email to a suppressed address");
+            Ok(())
+        }
+        result => result.context("failed to send email"),
+    }
+}
+
 #[post("/register")]
-async fn register(user: web::Json<User>, data: web::Data<AppState>) -> impl Responder {
+async fn register(
+    req: HttpRequest,
+    user: web::Json<User>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
     let user_data = user.into_inner();
+    user_data.validate()?;
+
+    let mut user =
+        User::new(user_data.email, user_data.password).context("failed to create user")?;
+
+    // An explicit locale wins over the browser's Accept-Language.
+    let templates = data.mail.templates();
+    user.locale = user_data
+        .locale
+        .as_deref()
+        .and_then(|locale| templates.supported(locale))
+        .or_else(|| {
+            req.headers()
+                .get(header::ACCEPT_LANGUAGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| templates.negotiate(value))
+        })
+        .map(str::to_string);
+
+    data.repos
+        .users
+        .insert(&user)
+        .await
+        .context("failed to insert user")?;
+
+    let (otp, code) =
+        Otp::new(user.email.clone(), &data.otp_hasher).context("failed to create otp")?;
+    otp.insert_otp(data.repos.otps.as_ref())
+        .await
+        .context("failed to insert otp")?;
+
+    send_otp(&data, &user.email, user.locale.as_deref(), code).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "user registered successfully"
+    })))
+}
+
+#[post("/verify")]
+async fn verify(
+    otp: web::Json<VerifyOtp>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let otp_data = otp.into_inner();
+    otp_data.validate()?;
 
-    if let Err(errors) = user_data.validate() {
-        return HttpResponse::BadRequest().json(json!({
-            "error": errors
-        }));
+    let is_valid = Otp::verify_otp(
+        otp_data.code,
+        otp_data.email.clone(),
+        &data.otp_hasher,
+        data.repos.otps.as_ref(),
+    )
+    .await
+    .context("failed to verify otp")?;
+    if !is_valid {
+        return Err(ApiError::InvalidOtp);
     }
 
-    let user = match User::new(user_data.email, user_data.password) {
-        Ok(user) => user,
-        Err(_) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": "failed to create user"
-            }))
+    data.repos
+        .users
+        .mark_verified(&otp_data.email.trim().to_lowercase())
+        .await
+        .context("failed to update user")?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "OTP verified successfully"
+    })))
+}
+
+#[post("/login")]
+async fn login(
+    req: HttpRequest,
+    user: web::Json<Login>,
+    session: Session,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_data: Login = user.into_inner();
+    user_data.validate()?;
+
+    let email = user_data.email.trim().to_lowercase();
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string());
+    check_login_attempts(&data, &email, ip.as_deref()).await?;
+
+    let user = match data
+        .repos
+        .users
+        .find_by_email(&email)
+        .await
+        .context("failed to find user")?
+    {
+        Some(user) => user,
+        None => {
+            record_login_failure(&data, &email, ip, None).await;
+            return Err(ApiError::InvalidCredentials);
         }
     };
 
-    match create_unique_index(&data.db).await {
-        Ok(_) => {}
-        Err(_) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": "failed to create unique index"
-            }))
-        }
+    if !bcrypt::verify(user_data.password, &user.password).context("failed to verify password")? {
+        record_login_failure(&data, &email, ip, Some(&user)).await;
+        return Err(ApiError::InvalidCredentials);
+    }
+
+    LoginFailures::clear(
+        data.repos.login_failures.as_ref(),
+        &lockout::account_key(&email),
+    )
+    .await
+    .context("failed to reset login attempts")?;
+
+    let user_id = user
+        .id
+        .ok_or_else(|| ApiError::internal("failed to find user", "user has no id"))?;
+
+    let issue_tokens = user_data.issue_tokens.unwrap_or(false);
+    let mfa_methods = user.mfa_methods();
+    if !mfa_methods.is_empty() {
+        let mfa_token =
+            MfaChallenge::create(data.repos.mfa_challenges.as_ref(), user_id, issue_tokens)
+                .await
+                .context("failed to create mfa challenge")?;
+        return Ok(HttpResponse::Ok().json(json!({
+            "message": "second factor required",
+            "mfa_required": true,
+            "mfa_methods": mfa_methods,
+            "mfa_token": mfa_token
+        })));
     }
 
-    let collection: Collection<User> = data.db.collection("users");
-    match collection.insert_one(&user, None).await {
-        Ok(result) => result,
-        Err(_) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": "failed to insert user"
-            }));
+    complete_login(user_id, &user.email, issue_tokens, &session, &data).await
+}
+
+/// Loads the user a pending MFA challenge belongs to.
+async fn challenge_user(data: &AppState, user_id: ObjectId) -> Result<User, ApiError> {
+    data.repos
+        .users
+        .find_by_id(user_id)
+        .await
+        .context("failed to find user")?
+        .ok_or(ApiError::InvalidMfaToken)
+}
+
+#[post("/login/mfa")]
+async fn login_mfa(
+    body: web::Json<MfaLogin>,
+    session: Session,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    body.validate()?;
+
+    let challenge =
+        MfaChallenge::begin_attempt(data.repos.mfa_challenges.as_ref(), &body.mfa_token)
+            .await
+            .context("failed to find mfa challenge")?
+            .ok_or(ApiError::InvalidMfaToken)?;
+    let user = challenge_user(&data, challenge.user_id).await?;
+
+    if !mfa::verify_user_totp(data.repos.users.as_ref(), &data.cipher, &user, &body.code)
+        .await
+        .context("failed to verify totp code")?
+    {
+        return Err(ApiError::InvalidTotpCode);
+    }
+
+    MfaChallenge::complete(data.repos.mfa_challenges.as_ref(), &body.mfa_token)
+        .await
+        .context("failed to complete mfa challenge")?;
+
+    complete_login(
+        challenge.user_id,
+        &user.email,
+        challenge.issue_tokens,
+        &session,
+        &data,
+    )
+    .await
+}
+
+#[post("/login/recovery")]
+async fn login_recovery(
+    body: web::Json<RecoveryLogin>,
+    session: Session,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    body.validate()?;
+
+    let challenge =
+        MfaChallenge::begin_attempt(data.repos.mfa_challenges.as_ref(), &body.mfa_token)
+            .await
+            .context("failed to find mfa challenge")?
+            .ok_or(ApiError::InvalidMfaToken)?;
+
+    if !mfa::redeem_recovery_code(
+        data.repos.users.as_ref(),
+        challenge.user_id,
+        &body.recovery_code,
+    )
+    .await
+    .context("failed to verify recovery code")?
+    {
+        return Err(ApiError::InvalidRecoveryCode);
+    }
+
+    let user = challenge_user(&data, challenge.user_id).await?;
+
+    MfaChallenge::complete(data.repos.mfa_challenges.as_ref(), &body.mfa_token)
+        .await
+        .context("failed to complete mfa challenge")?;
+
+    complete_login(
+        challenge.user_id,
+        &user.email,
+        challenge.issue_tokens,
+        &session,
+        &data,
+    )
+    .await
+}
+
+#[post("/login/passkey/options")]
+async fn login_passkey_options(
+    body: web::Json<PasskeyLoginOptions>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    body.validate()?;
+
+    // As a second factor the ceremony is bound to the user whose password
+    // was accepted; otherwise any discoverable credential may answer it.
+    let (user_id, issue_tokens, passkeys) = match &body.mfa_token {
+        Some(mfa_token) => {
+            let challenge = MfaChallenge::find(data.repos.mfa_challenges.as_ref(), mfa_token)
+                .await
+                .context("failed to find mfa challenge")?
+                .ok_or(ApiError::InvalidMfaToken)?;
+            let passkeys = data
+                .repos
+                .users
+                .find_by_id(challenge.user_id)
+                .await
+                .context("failed to find user")?
+                .and_then(|user| user.passkeys)
+                .unwrap_or_default();
+            if passkeys.is_empty() {
+                return Err(ApiError::NoPasskeyRegistered);
+            }
+            (Some(challenge.user_id), challenge.issue_tokens, passkeys)
         }
+        None => (None, body.issue_tokens.unwrap_or(false), Vec::new()),
     };
 
-    let otp = match Otp::new(user.email.clone()) {
-        Ok(otp) => otp,
-        Err(err) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": format!("failed to create otp: {}", err)
-            }));
-        }
+    let challenge = WebauthnChallenge::create(
+        data.repos.webauthn_challenges.as_ref(),
+        Ceremony::Authentication,
+        user_id,
+        issue_tokens,
+    )
+    .await
+    .context("failed to create webauthn challenge")?;
+    Ok(HttpResponse::Ok().json(json!({
+        "publicKey": data.rp.request_options(challenge, &passkeys)
+    })))
+}
+
+#[post("/login/passkey")]
+async fn login_passkey(
+    body: web::Json<PasskeyLogin>,
+    session: Session,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    body.validate()?;
+
+    let challenge = webauthn::client_challenge(&body.credential.response.client_data_json)?;
+    let record = WebauthnChallenge::take(
+        data.repos.webauthn_challenges.as_ref(),
+        &challenge,
+        Ceremony::Authentication,
+    )
+    .await
+    .context("failed to find webauthn challenge")?
+    .ok_or(ApiError::InvalidWebauthnChallenge)?;
+
+    let user = data
+        .repos
+        .users
+        .find_by_passkey(&body.credential.id)
+        .await
+        .context("failed to find user")?
+        .ok_or(ApiError::InvalidPasskey)?;
+    let (user_id, passkey) = match (
+        user.id,
+        user.passkeys
+            .iter()
+            .flatten()
+            .find(|passkey| passkey.credential_id == body.credential.id),
+    ) {
+        (Some(user_id), Some(passkey)) => (user_id, passkey.clone()),
+        _ => return Err(ApiError::InvalidPasskey),
     };
 
-    match otp.insert_otp(&data.db).await {
-        Ok(_) => {}
-        Err(err) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": format!("failed to insert otp: {}", err)
-            }));
-        }
+    let user_handle = body.credential.response.user_handle.as_deref();
+    if record.user_id.is_some_and(|expected| expected != user_id)
+        || user_handle.is_some_and(|handle| handle != URL_SAFE_NO_PAD.encode(user_id.bytes()))
+    {
+        return Err(ApiError::InvalidPasskey);
     }
 
-    match mail::send_email_confirmation(&user.email, &otp.code.to_string()).await {
-        Ok(_) => {
-            return HttpResponse::Ok().json(json!({
-                "message": "user registered successfully",
-                "otp": otp.code
-            }));
-        }
-        Err(err) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": format!("failed to send email: {}", err)
-            }));
+    // A second factor must come with the login it completes, which also
+    // counts the attempt against that login.
+    let mut issue_tokens = record.issue_tokens;
+    if record.user_id.is_some() {
+        let mfa_token = body.mfa_token.as_ref().ok_or(ApiError::MfaTokenRequired)?;
+        match MfaChallenge::begin_attempt(data.repos.mfa_challenges.as_ref(), mfa_token)
+            .await
+            .context("failed to find mfa challenge")?
+        {
+            Some(mfa_challenge) if mfa_challenge.user_id == user_id => {
+                issue_tokens = mfa_challenge.issue_tokens;
+            }
+            _ => return Err(ApiError::InvalidMfaToken),
         }
     }
-}
 
-#[post("/verify")]
-async fn verify(otp: web::Json<Otp>, data: web::Data<AppState>) -> impl Responder {
-    let otp_data = otp.into_inner();
+    let require_user_verification = record.user_id.is_none();
+    let sign_count = data
+        .rp
+        .verify_authentication(
+            &passkey,
+            &body.credential,
+            &challenge,
+            require_user_verification,
+        )
+        .map_err(|_| ApiError::InvalidPasskey)?;
+
+    if !data
+        .repos
+        .users
+        .record_passkey_use(user_id, &passkey, sign_count)
+        .await
+        .context("failed to update passkey")?
+    {
+        return Err(ApiError::InvalidPasskey);
+    }
+
+    if let (Some(_), Some(mfa_token)) = (record.user_id, &body.mfa_token) {
+        MfaChallenge::complete(data.repos.mfa_challenges.as_ref(), mfa_token)
+            .await
+            .context("failed to complete mfa challenge")?;
+    }
 
-    if let Err(errors) = otp_data.validate() {
-        return HttpResponse::BadRequest().json(json!({
-            "error": errors
-        }));
+    complete_login(user_id, &user.email, issue_tokens, &session, &data).await
+}
+
+/// Turns the attempt away while the account or the client address has to
+/// wait after earlier failed passwords.
+pub(crate) async fn check_login_attempts(
+    data: &AppState,
+    email: &str,
+    ip: Option<&str>,
+) -> Result<(), ApiError> {
+    let mut checks = vec![(lockout::account_key(email), &ACCOUNT_POLICY)];
+    if let Some(ip) = ip {
+        checks.push((lockout::ip_key(ip), &IP_POLICY));
+    }
+    for (key, policy) in &checks {
+        if let Some(retry_after) =
+            LoginFailures::check(data.repos.login_failures.as_ref(), key, policy)
+                .await
+                .context("failed to check login attempts")?
+        {
+            return Err(ApiError::TooManyAttempts { retry_after });
+        }
     }
+    Ok(())
+}
 
-    match Otp::verify_otp(otp_data.code, otp_data.email.clone(), &data.db).await {
-        Ok(is_valid) => {
-            if is_valid {
-                let collection: Collection<User> = data.db.collection("users");
-                let filter = doc! {
-                    "email": otp_data.email.clone()
-                };
-                let update = doc! {
-                    "$set": { "is_verified": true }
-                };
-                match collection.update_one(filter, update, None).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        return HttpResponse::InternalServerError().json(json!({
-                            "error": "failed to update user"
-                        }));
+/// Counts a failed password against the account and the client address. A
+/// new account lockout emails the owner an unlock link; every lockout is
+/// reported as a security event.
+pub(crate) async fn record_login_failure(
+    data: &AppState,
+    email: &str,
+    ip: Option<String>,
+    user: Option<&User>,
+) {
+    match LoginFailures::record_failure(
+        data.repos.login_failures.as_ref(),
+        &lockout::account_key(email),
+        &ACCOUNT_POLICY,
+    )
+    .await
+    {
+        Ok(Some(token)) => {
+            let mut event = SecurityEvent::new(SecurityEventKind::AccountLocked);
+            event.email = Some(email.to_string());
+            event.user_id = user.and_then(|user| user.id);
+            event.ip = ip.clone();
+            event.emit(data.repos.security_events.as_ref()).await;
+
+            if let Some(user) = user {
+                let to = email.to_string();
+                let locale = user.locale.clone();
+                let unlock_link = format!("{}/unlock-account?token={}", data.app_url, token);
+                let mail = data.mail.clone();
+                actix_web::rt::spawn(async move {
+                    let email = AccountLocked {
+                        unlock_link,
+                        locked_minutes: LOCKOUT_MINUTES,
+                    };
+                    if let Err(err) = mail.send(&to, locale.as_deref(), &email).await {
+                        tracing::error!(error = %err, "Error sending account unlock email");
                     }
-                }
-
-                return HttpResponse::Ok().json(json!({
-                    "message": "OTP verified successfully"
-                }));
-            } else {
-                return HttpResponse::BadRequest().json(json!({
-                    "error": "Invalid or expired OTP"
-                }));
+                });
             }
         }
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": format!("Failed to verify OTP: {}", e)
-            }));
+        Ok(None) => {}
+        Err(err) => tracing::error!(error = %err, "Error recording failed login"),
+    }
+
+    if let Some(ip) = ip {
+        match LoginFailures::record_failure(
+            data.repos.login_failures.as_ref(),
+            &lockout::ip_key(&ip),
+            &IP_POLICY,
+        )
+        .await
+        {
+            Ok(Some(_)) => {
+                let mut event = SecurityEvent::new(SecurityEventKind::IpLocked);
+                event.ip = Some(ip);
+                event.emit(data.repos.security_events.as_ref()).await;
+            }
+            Ok(None) => {}
+            Err(err) => tracing::error!(error = %err, "Error recording failed login"),
         }
     }
 }
 
-#[post("/login")]
-async fn login(user: web::Json<User>, data: web::Data<AppState>) -> impl Responder {
-    let user_data: User = user.into_inner();
-    let rust_env = if data.rust_env == "production" {
-        true
-    } else {
-        false
-    };
+#[post("/unlock-account")]
+async fn unlock_account(
+    body: web::Json<UnlockAccount>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    body.validate()?;
 
-    if let Err(errors) = user_data.validate() {
-        return HttpResponse::BadRequest().json(json!({
-            "error": errors
-        }));
-    }
+    let key = LoginFailures::unlock(data.repos.login_failures.as_ref(), &body.token)
+        .await
+        .context("failed to unlock account")?
+        .ok_or(ApiError::InvalidUnlockToken)?;
 
-    let filter = doc! {
-        "email": user_data.email.trim().to_lowercase()
-    };
+    let mut event = SecurityEvent::new(SecurityEventKind::AccountUnlocked);
+    event.email = key.strip_prefix("account:").map(str::to_string);
+    event.emit(data.repos.security_events.as_ref()).await;
 
-    let collection: Collection<User> = data.db.collection("users");
-    match collection.find_one(filter, None).await {
-        Ok(result) => match result {
-            Some(user) => match bcrypt::verify(user_data.password, &user.password) {
-                Ok(_) => {
-                    let auth_cookie = Cookie::build("logged_in", "true")
-                        .path("/")
-                        .secure(rust_env)
-                        .http_only(true)
-                        .max_age(CookieDuration::minutes(10))
-                        .finish();
-                    return HttpResponse::Ok().cookie(auth_cookie).json(json!({
-                        "message": "user logged in successfully"
-                    }));
-                }
-                Err(_) => {
-                    return HttpResponse::BadRequest().json(json!({
-                        "error": "invalid password"
-                    }))
-                }
-            },
-            None => {
-                return HttpResponse::BadRequest().json(json!({
-                    "error": "user not found"
-                }))
-            }
-        },
-        Err(_) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": "failed to find user"
-            }))
-        }
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "account unlocked successfully"
+    })))
+}
+
+/// Finishes a successful authentication, either by starting a cookie session
+/// or, for bearer clients, by issuing an access and refresh token pair.
+async fn complete_login(
+    user_id: ObjectId,
+    email: &str,
+    issue_tokens: bool,
+    session: &Session,
+    data: &AppState,
+) -> Result<HttpResponse, ApiError> {
+    if issue_tokens {
+        let tokens = RefreshToken::issue(
+            data.repos.refresh_tokens.as_ref(),
+            &data.keys,
+            user_id,
+            email,
+        )
+        .await
+        .context("failed to issue tokens")?;
+        return Ok(HttpResponse::Ok().json(json!({
+            "message": "user logged in successfully",
+            "token_type": "Bearer",
+            "access_token": tokens.access_token,
+            "refresh_token": tokens.refresh_token,
+            "expires_in": tokens.expires_in
+        })));
     }
+
+    // Issue a fresh session key on privilege change to prevent fixation.
+    session.renew();
+    session
+        .insert(SESSION_USER_KEY, user_id.to_hex())
+        .and_then(|_| session.insert(SESSION_EMAIL_KEY, email))
+        .map_err(|err| ApiError::internal("failed to create session", err))?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "user logged in successfully"
+    })))
+}
+
+#[post("/token/refresh")]
+async fn refresh_token(
+    body: web::Json<RefreshTokenRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    body.validate()?;
+
+    let tokens = RefreshToken::rotate(
+        data.repos.refresh_tokens.as_ref(),
+        &data.keys,
+        &body.refresh_token,
+    )
+    .await
+    .context("failed to refresh token")?;
+    Ok(HttpResponse::Ok().json(json!({
+        "token_type": "Bearer",
+        "access_token": tokens.access_token,
+        "refresh_token": tokens.refresh_token,
+        "expires_in": tokens.expires_in
+    })))
 }
 
 #[post("/resend-otp")]
-async fn resend_otp(email: web::Json<ResendOtp>, data: web::Data<AppState>) -> impl Responder {
+async fn resend_otp(
+    email: web::Json<ResendOtp>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
     let email_data = email.into_inner();
-    if let Err(errors) = email_data.validate() {
-        return HttpResponse::BadRequest().json(json!({
-            "error": errors
-        }));
-    }
+    email_data.validate()?;
 
+    let response = HttpResponse::Ok().json(json!({
+        "message": "otp resent successfully"
+    }));
+
+    // Unknown and already verified emails get the same response, but no
+    // code is stored or sent for them.
     let email = email_data.email.trim().to_lowercase();
+    let user = match data
+        .repos
+        .users
+        .find_by_email(&email)
+        .await
+        .context("failed to find user")?
+    {
+        Some(user) if !user.is_verified.unwrap_or(false) => user,
+        _ => return Ok(response),
+    };
 
-    match Otp::update_otp(email.clone(), &data.db).await {
-        Ok((email, code)) => {
-            match services::mail::send_email_confirmation(&email, &code.to_string()).await {
-                Ok(_) => {
-                    return HttpResponse::Ok().json(json!({
-                        "message": "otp resent successfully",
-                        "otp": code
-                    }));
-                }
-                Err(err) =>
-                    return HttpResponse::InternalServerError().json(json!({
-                        "error": format!("failed to send email: {}", err)
-                    }))
-            }
-        }
-        Err(err) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": format!("failed to update otp: {}", err)
-            }))
+    let otps = data.repos.otps.as_ref();
+    let (email, code) = match Otp::update_otp(email.clone(), &data.otp_hasher, otps).await {
+        Err(OtpError::RepositoryError(RepositoryError::NotFound(_))) => {
+            // The pending code has already expired and been removed.
+            let (otp, code) = Otp::new(email, &data.otp_hasher).context("failed to create otp")?;
+            otp.insert_otp(otps).await.context("failed to insert otp")?;
+            (otp.email, code)
         }
-    }
+        result => result.context("failed to update otp")?,
+    };
+    send_otp(&data, &email, user.locale.as_deref(), code).await?;
+
+    Ok(response)
 }
 
 #[post("/logout")]
-async fn logout() -> impl Responder {
-    let cookie = Cookie::build("logged_in", "")
-        .path("/")
-        .max_age(CookieDuration::seconds(0))
-        .finish();
-    let mut response = HttpResponse::Ok().json(json!({
+async fn logout(session: Session) -> HttpResponse {
+    // Purging removes the server-side record and expires the cookie.
+    session.purge();
+    HttpResponse::Ok().json(json!({
         "message": "user logged out successfully"
-    }));
-    if let Err(_) = response.add_cookie(&cookie) {
-        return HttpResponse::InternalServerError().json(json!({
-            "error": "failed to add cookie"
-        }));
-    }
-    response
+    }))
 }
 
-#[post("/forgot-password/{uuid}")]
+#[post("/forgot-password")]
 async fn forgot_password(
-    uuid: web::Path<String>,
-    user: web::Json<ForgotPassword>,
-) -> impl Responder {
-    let _user_data = user.into_inner();
+    body: web::Json<ForgotPassword>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    body.validate()?;
 
-    HttpResponse::Ok().json(json!({
-        "uuid": uuid.to_string(),
-    }))
+    // The response is the same whether or not the email is registered, and
+    // the email is sent in the background so timing does not tell either.
+    let response = HttpResponse::Ok().json(json!({
+        "message": "if the email is registered, a reset link has been sent"
+    }));
+
+    let user = match data
+        .repos
+        .users
+        .find_by_email(&body.email.trim().to_lowercase())
+        .await
+        .context("failed to find user")?
+    {
+        Some(user) => user,
+        None => return Ok(response),
+    };
+    let user_id = match user.id {
+        Some(id) => id,
+        None => return Ok(response),
+    };
+
+    let token = PasswordReset::create(data.repos.password_resets.as_ref(), user_id, &user.email)
+        .await
+        .context("failed to create reset token")?;
+
+    let reset_link = format!("{}/reset-password?token={}", data.app_url, token);
+    let mail = data.mail.clone();
+    actix_web::rt::spawn(async move {
+        let email = emails::PasswordReset {
+            reset_link,
+            expires_in_minutes: PASSWORD_RESET_TTL_MINUTES,
+        };
+        if let Err(err) = mail.send(&user.email, user.locale.as_deref(), &email).await {
+            tracing::error!(error = %err, "Error sending password reset email");
+        }
+    });
+
+    Ok(response)
+}
+
+#[post("/reset-password")]
+async fn reset_password(
+    req: HttpRequest,
+    body: web::Json<ResetPassword>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    body.validate()?;
+
+    let reset = PasswordReset::redeem(data.repos.password_resets.as_ref(), &body.token)
+        .await
+        .context("failed to find reset token")?
+        .ok_or(ApiError::InvalidResetToken)?;
+
+    let password =
+        bcrypt::hash(&body.password, bcrypt::DEFAULT_COST).context("failed to hash password")?;
+
+    let user = data
+        .repos
+        .users
+        .set_password(reset.user_id, &password)
+        .await
+        .context("failed to update user")?
+        .ok_or_else(|| ApiError::internal("failed to update user", "user not found"))?;
+
+    // Whoever held the old password may still be signed in, and the owner
+    // should not stay locked out by their earlier guesses.
+    LoginFailures::clear(
+        data.repos.login_failures.as_ref(),
+        &lockout::account_key(&reset.email),
+    )
+    .await
+    .context("failed to reset login attempts")?;
+    data.repos
+        .sessions
+        .delete_for_user(reset.user_id)
+        .await
+        .context("failed to end existing sessions")?;
+    RefreshToken::revoke_for_user(data.repos.refresh_tokens.as_ref(), reset.user_id)
+        .await
+        .context("failed to revoke refresh tokens")?;
+
+    let email = PasswordChanged {
+        changed_at: Utc::now().format("%Y-%m-%d %H:%M UTC").to_string(),
+        ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+        reset_link: format!("{}/forgot-password", data.app_url),
+    };
+    if let Err(err) = data
+        .mail
+        .send(&user.email, user.locale.as_deref(), &email)
+        .await
+    {
+        tracing::error!(error = %err, "Error sending password changed email");
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "password reset successfully"
+    })))
 }
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(register);
     cfg.service(verify);
-    // cfg.service(login);
+    cfg.service(login);
+    cfg.service(login_mfa);
+    cfg.service(login_recovery);
+    cfg.service(login_passkey_options);
+    cfg.service(login_passkey);
     cfg.service(resend_otp);
-    // cfg.service(logout);
-    // cfg.service(forgot_password);
+    cfg.service(logout);
+    cfg.service(refresh_token);
+    cfg.service(forgot_password);
+    cfg.service(reset_password);
+    cfg.service(unlock_account);
 }
 
-async fn create_unique_index(db: &Database) -> Result<(), Error> {
-    let collection: Collection<User> = db.collection("users");
-    let model = IndexModel::builder()
-        .keys(doc! { "email": 1 })
-        .options(IndexOptions::builder().unique(true).build())
-        .build();
-    collection.create_index(model, None).await?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::testing::{self, session_cookie};
+    use crate::repositories::{session::MemorySessionStore, Repositories};
+    use crate::services::otp::CapturedOtps;
+    use crate::services::session::SESSION_TTL_HOURS;
+    use actix_web::cookie::{time::Duration, Cookie};
+    use actix_web::{http::StatusCode, test};
+    use mongodb::bson::DateTime;
+    use serde_json::Value;
+    use sha2::{Digest, Sha256};
+
+    #[actix_web::test]
+    async fn test_resend_and_verify_otp_in_memory() {
+        let sessions = MemorySessionStore::new();
+        let repos = Repositories::memory(sessions.clone());
+        let user: User = serde_json::from_value(json!({
+            "email": "user@example.com",
+            "password": "unused",
+            "is_verified": false,
+            "created_at": null,
+            "updated_at": null
+        }))
+        .unwrap();
+        repos.users.insert(&user).await.unwrap();
+        assert!(matches!(
+            repos.users.insert(&user).await,
+            Err(crate::repositories::RepositoryError::Duplicate(_))
+        ));
+
+        let captured = CapturedOtps::new();
+        let state = testing::state(repos.clone(), captured.clone());
+        let app = test::init_service(testing::app(state, sessions)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/resend-otp")
+            .set_json(json!({ "email": "User@example.com" }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let code = captured.latest("user@example.com").unwrap().code;
+
+        // Unregistered emails get the same response but no code.
+        let req = test::TestRequest::post()
+            .uri("/resend-otp")
+            .set_json(json!({ "email": "nobody@example.com" }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        assert!(captured.latest("nobody@example.com").is_none());
+
+        let verify_request = || {
+            test::TestRequest::post()
+                .uri("/verify")
+                .set_json(json!({ "email": "user@example.com", "code": code }))
+                .to_request()
+        };
+        assert!(test::call_service(&app, verify_request())
+            .await
+            .status()
+            .is_success());
+        let user = repos.users.find_by_email("user@example.com").await.unwrap();
+        assert_eq!(user.unwrap().is_verified, Some(true));
+
+        let resp = test::call_service(&app, verify_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_register_verify_and_login() {
+        let sessions = MemorySessionStore::new();
+        let repos = Repositories::memory(sessions.clone());
+        let captured = CapturedOtps::new();
+        let app = test::init_service(testing::app(
+            testing::state(repos.clone(), captured.clone()),
+            sessions,
+        ))
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/register")
+            .set_json(json!({ "email": "User@example.com", "password": "password123" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::post()
+            .uri("/register")
+            .set_json(json!({ "email": "user@example.com", "password": "password123" }))
+            .to_request();
+        assert!(!test::call_service(&app, req).await.status().is_success());
+
+        let code = captured.latest("user@example.com").unwrap().code;
+        let req = test::TestRequest::post()
+            .uri("/verify")
+            .set_json(json!({ "email": "user@example.com", "code": code }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let login_request = |password: &str| {
+            test::TestRequest::post()
+                .uri("/login")
+                .set_json(json!({ "email": "user@example.com", "password": password }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, login_request("password124")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "invalid_credentials");
+
+        let resp = test::call_service(&app, login_request("password123")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = test::TestRequest::get()
+            .uri("/user/profile")
+            .cookie(session_cookie(&resp).unwrap())
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["email"], "user@example.com");
+        assert_eq!(body["is_verified"], true);
+    }
+
+    #[actix_web::test]
+    async fn test_failed_logins_back_off_until_unlocked() {
+        let sessions = MemorySessionStore::new();
+        let repos = Repositories::memory(sessions.clone());
+        testing::insert_user(&repos, "user@example.com", "password123").await;
+        let app = test::init_service(testing::app(
+            testing::state(repos.clone(), CapturedOtps::new()),
+            sessions,
+        ))
+        .await;
+
+        let login_request = |password: &str| {
+            test::TestRequest::post()
+                .uri("/login")
+                .set_json(json!({ "email": "user@example.com", "password": password }))
+                .to_request()
+        };
+        for _ in 0..2 {
+            let resp = test::call_service(&app, login_request("wrong-password1")).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        let resp = test::call_service(&app, login_request("password123")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "too_many_attempts");
+
+        // Further failures lock the account until the emailed token is used.
+        let key = lockout::account_key("user@example.com");
+        let mut token = None;
+        for _ in 2..ACCOUNT_POLICY.lockout_after {
+            token =
+                LoginFailures::record_failure(repos.login_failures.as_ref(), &key, &ACCOUNT_POLICY)
+                    .await
+                    .unwrap();
+        }
+        let token = token.unwrap();
+        let resp = test::call_service(&app, login_request("password123")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let unlock_request = || {
+            test::TestRequest::post()
+                .uri("/unlock-account")
+                .set_json(json!({ "token": &token }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, unlock_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, unlock_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = test::call_service(&app, login_request("password123")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_login_with_totp_second_factor() {
+        let sessions = MemorySessionStore::new();
+        let repos = Repositories::memory(sessions.clone());
+        testing::insert_user(&repos, "user@example.com", "password123").await;
+        let app = test::init_service(testing::app(
+            testing::state(repos, CapturedOtps::new()),
+            sessions,
+        ))
+        .await;
+
+        let login_request = || {
+            test::TestRequest::post()
+                .uri("/login")
+                .set_json(json!({
+                    "email": "user@example.com",
+                    "password": "password123",
+                    "issue_tokens": true
+                }))
+                .to_request()
+        };
+        let body: Value = test::call_and_read_body_json(&app, login_request()).await;
+        let access_token = body["access_token"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::post()
+            .uri("/mfa/totp/enroll")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", access_token)))
+            .set_json(json!({ "password": "password123" }))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let totp = totp_rs::TOTP::from_url(body["otpauth_url"].as_str().unwrap()).unwrap();
+        let now = Utc::now().timestamp() as u64;
+        let req = test::TestRequest::post()
+            .uri("/mfa/totp/confirm")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", access_token)))
+            .set_json(json!({ "code": totp.generate(now) }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        // The password alone now only yields a challenge.
+        let body: Value = test::call_and_read_body_json(&app, login_request()).await;
+        assert_eq!(body["mfa_required"], true);
+        assert_eq!(body["mfa_methods"], json!(["totp", "recovery_code"]));
+        assert!(body.get("access_token").is_none());
+        let mfa_token = body["mfa_token"].as_str().unwrap().to_string();
+
+        let mfa_request = |code: String| {
+            test::TestRequest::post()
+                .uri("/login/mfa")
+                .set_json(json!({ "mfa_token": &mfa_token, "code": code }))
+                .to_request()
+        };
+        // The code that confirmed enrollment cannot be replayed.
+        let resp = test::call_service(&app, mfa_request(totp.generate(now))).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "invalid_totp_code");
+
+        let next = totp.generate(now + mfa::TOTP_STEP);
+        let resp = test::call_service(&app, mfa_request(next.clone())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(resp).await;
+        assert!(body["access_token"].is_string());
+        assert!(body["refresh_token"].is_string());
+
+        // The challenge is spent once it succeeds.
+        let resp = test::call_service(&app, mfa_request(next)).await;
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "invalid_mfa_token");
+    }
+
+    #[actix_web::test]
+    async fn test_refresh_token_rotates_and_detects_reuse() {
+        let sessions = MemorySessionStore::new();
+        let repos = Repositories::memory(sessions.clone());
+        testing::insert_user(&repos, "user@example.com", "password123").await;
+        let app = test::init_service(testing::app(
+            testing::state(repos, CapturedOtps::new()),
+            sessions,
+        ))
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_json(json!({
+                "email": "user@example.com",
+                "password": "password123",
+                "issue_tokens": true
+            }))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["token_type"], "Bearer");
+        let first = body["refresh_token"].as_str().unwrap().to_string();
+
+        let refresh_request = |token: &str| {
+            test::TestRequest::post()
+                .uri("/token/refresh")
+                .set_json(json!({ "refresh_token": token }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, refresh_request(&first)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(resp).await;
+        let second = body["refresh_token"].as_str().unwrap().to_string();
+        assert_ne!(first, second);
+
+        let req = test::TestRequest::get()
+            .uri("/user/profile")
+            .insert_header((
+                header::AUTHORIZATION,
+                format!("Bearer {}", body["access_token"].as_str().unwrap()),
+            ))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        // Presenting the used token again revokes the whole family, so the
+        // token it was exchanged for stops working too.
+        let resp = test::call_service(&app, refresh_request(&first)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "invalid_refresh_token");
+        let resp = test::call_service(&app, refresh_request(&second)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = test::call_service(&app, refresh_request("unknown-token")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_login_renews_session_and_logout_destroys_it() {
+        let sessions = MemorySessionStore::new();
+        let repos = Repositories::memory(sessions.clone());
+        testing::insert_user(&repos, "user@example.com", "password123").await;
+        let state = testing::state(repos, CapturedOtps::new());
+        let app = test::init_service(testing::app(state, sessions)).await;
+
+        let login_request = |cookie: Option<Cookie<'static>>| {
+            let mut req = test::TestRequest::post()
+                .uri("/login")
+                .set_json(json!({ "email": "user@example.com", "password": "password123" }));
+            if let Some(cookie) = cookie {
+                req = req.cookie(cookie);
+            }
+            req.to_request()
+        };
+        let profile_request = |cookie: &Cookie<'static>| {
+            test::TestRequest::get()
+                .uri("/user/profile")
+                .cookie(cookie.clone())
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, login_request(None)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let first = session_cookie(&resp).unwrap();
+        assert_eq!(first.max_age(), Some(Duration::hours(SESSION_TTL_HOURS)));
+        let resp = test::call_service(&app, profile_request(&first)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Logging in again replaces the session key and drops the old record.
+        let resp = test::call_service(&app, login_request(Some(first.clone()))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let second = session_cookie(&resp).unwrap();
+        assert_ne!(first.value(), second.value());
+        let resp = test::call_service(&app, profile_request(&first)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, profile_request(&second)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/logout")
+            .cookie(second.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(session_cookie(&resp).unwrap().value(), "");
+
+        // A client that kept the cookie is no longer signed in.
+        let resp = test::call_service(&app, profile_request(&second)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_forgot_password_answers_unknown_emails_alike() {
+        let sessions = MemorySessionStore::new();
+        let repos = Repositories::memory(sessions.clone());
+        testing::insert_user(&repos, "user@example.com", "password123").await;
+        let app = test::init_service(testing::app(
+            testing::state(repos.clone(), CapturedOtps::new()),
+            sessions,
+        ))
+        .await;
+
+        let forgot_request = |email: &str| {
+            test::TestRequest::post()
+                .uri("/forgot-password")
+                .set_json(json!({ "email": email }))
+                .to_request()
+        };
+        let known = test::call_service(&app, forgot_request("user@example.com")).await;
+        let unknown = test::call_service(&app, forgot_request("nobody@example.com")).await;
+        assert_eq!(known.status(), StatusCode::OK);
+        assert_eq!(known.status(), unknown.status());
+        assert_eq!(test::read_body(known).await, test::read_body(unknown).await);
+
+        // Only the registered address is mailed, in the background.
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(50)).await;
+        let lease_until = DateTime::from_millis(DateTime::now().timestamp_millis() + 60_000);
+        let message = repos.outbox.claim(lease_until).await.unwrap().unwrap();
+        assert_eq!(message.to, vec!["user@example.com".to_string()]);
+        assert!(repos.outbox.claim(lease_until).await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_reset_password_is_single_use_and_ends_every_login() {
+        let sessions = MemorySessionStore::new();
+        let repos = Repositories::memory(sessions.clone());
+        let user_id = testing::insert_user(&repos, "user@example.com", "password123").await;
+        let app = test::init_service(testing::app(
+            testing::state(repos.clone(), CapturedOtps::new()),
+            sessions,
+        ))
+        .await;
+
+        let login_request = |password: &str, issue_tokens: bool| {
+            test::TestRequest::post()
+                .uri("/login")
+                .set_json(json!({
+                    "email": "user@example.com",
+                    "password": password,
+                    "issue_tokens": issue_tokens
+                }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, login_request("password123", false)).await;
+        let cookie = session_cookie(&resp).unwrap();
+        let body: Value =
+            test::call_and_read_body_json(&app, login_request("password123", true)).await;
+        let old_refresh_token = body["refresh_token"].as_str().unwrap().to_string();
+
+        let token =
+            PasswordReset::create(repos.password_resets.as_ref(), user_id, "user@example.com")
+                .await
+                .unwrap();
+        let reset_request = |password: &str| {
+            test::TestRequest::post()
+                .uri("/reset-password")
+                .set_json(json!({ "token": &token, "password": password }))
+                .to_request()
+        };
+
+        // A weak password is refused without spending the token.
+        let resp = test::call_service(&app, reset_request("password")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "validation_failed");
+
+        let resp = test::call_service(&app, reset_request("newpassword1")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, reset_request("newpassword2")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "invalid_reset_token");
+
+        // The session and refresh token from before the reset are gone.
+        let req = test::TestRequest::get()
+            .uri("/user/profile")
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::post()
+            .uri("/token/refresh")
+            .set_json(json!({ "refresh_token": old_refresh_token }))
+            .to_request();
+        let body: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(body["code"], "invalid_refresh_token");
+
+        let resp = test::call_service(&app, login_request("password123", false)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, login_request("newpassword1", false)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_reset_password_rejects_expired_token() {
+        let sessions = MemorySessionStore::new();
+        let repos = Repositories::memory(sessions.clone());
+        let user_id = testing::insert_user(&repos, "user@example.com", "password123").await;
+        let expired = PasswordReset {
+            id: None,
+            token_hash: format!("{:x}", Sha256::digest(b"expired-token")),
+            user_id,
+            email: "user@example.com".to_string(),
+            is_used: false,
+            created_at: None,
+            used_at: None,
+            expires_at: DateTime::from_millis(DateTime::now().timestamp_millis() - 1000),
+        };
+        repos.password_resets.insert(&expired).await.unwrap();
+        let app = test::init_service(testing::app(
+            testing::state(repos, CapturedOtps::new()),
+            sessions,
+        ))
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/reset-password")
+            .set_json(json!({ "token": "expired-token", "password": "newpassword1" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "invalid_reset_token");
+    }
 }
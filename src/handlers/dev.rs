@@ -0,0 +1,29 @@
+use crate::error::ApiError;
+use crate::models::user::ResendOtp;
+use crate::AppState;
+use actix_web::{get, web, HttpResponse};
+use validator::Validate;
+
+/// Returns the last code captured for an address. Only mounted when OTP
+/// delivery is in capture mode, which is refused in production.
+#[get("/dev/otp")]
+async fn captured_otp(
+    query: web::Query<ResendOtp>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner();
+    query.validate()?;
+
+    let otp = data
+        .otp_delivery
+        .captured()
+        .ok_or(ApiError::NotFound("otp capture is disabled"))?
+        .latest(&query.email)
+        .ok_or(ApiError::NotFound("no otp captured for this email"))?;
+
+    Ok(HttpResponse::Ok().json(otp))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(captured_otp);
+}
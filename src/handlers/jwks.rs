@@ -0,0 +1,15 @@
+use crate::AppState;
+use actix_web::{get, http::header, web, HttpResponse, Responder};
+
+/// Publishes every key that may still verify a token, including keys that
+/// are scheduled but not yet signing, so verifiers can cache ahead of rotation.
+#[get("/.well-known/jwks.json")]
+async fn jwks(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=300"))
+        .json(data.keys.jwks())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(jwks);
+}
@@ -0,0 +1,267 @@
+use crate::error::{ApiError, Context};
+use crate::handlers::auth::{check_login_attempts, record_login_failure};
+use crate::middleware::auth::Principal;
+use crate::models::user::{TotpConfirm, TotpReauth, User};
+use crate::services::emails::{MfaDisabled, MfaEnabled, MfaMethod};
+use crate::services::lockout::{self, LoginFailures};
+use crate::services::mfa;
+use crate::AppState;
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use serde_json::json;
+use validator::Validate;
+
+/// Confirms the caller still holds the account: the password always, plus a
+/// current TOTP code when a second factor is already enabled. Wrong passwords
+/// count towards the same lockout as failed logins.
+pub(crate) async fn reauthenticate(
+    data: &AppState,
+    req: &HttpRequest,
+    principal: &Principal,
+    body: &TotpReauth,
+) -> Result<User, ApiError> {
+    body.validate()?;
+
+    let user = data
+        .repos
+        .users
+        .find_by_id(principal.user_id)
+        .await
+        .context("failed to find user")?
+        .ok_or(ApiError::Unauthorized)?;
+
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string());
+    check_login_attempts(data, &user.email, ip.as_deref()).await?;
+    if !bcrypt::verify(&body.password, &user.password).context("failed to verify password")? {
+        record_login_failure(data, &user.email, ip, Some(&user)).await;
+        return Err(ApiError::InvalidPassword);
+    }
+    LoginFailures::clear(
+        data.repos.login_failures.as_ref(),
+        &lockout::account_key(&user.email),
+    )
+    .await
+    .context("failed to reset login attempts")?;
+
+    if user.totp_enabled == Some(true) {
+        let code = body.code.as_ref().ok_or(ApiError::TotpCodeRequired)?;
+        if !mfa::verify_user_totp(data.repos.users.as_ref(), &data.cipher, &user, code)
+            .await
+            .context("failed to verify totp code")?
+        {
+            return Err(ApiError::InvalidTotpCode);
+        }
+    }
+
+    Ok(user)
+}
+
+#[post("/mfa/totp/enroll")]
+async fn totp_enroll(
+    req: HttpRequest,
+    principal: Principal,
+    body: web::Json<TotpReauth>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user = reauthenticate(&data, &req, &principal, &body).await?;
+
+    let (secret, enrollment) =
+        mfa::new_enrollment(&user.email).context("failed to generate totp secret")?;
+    let encrypted = mfa::encrypt_secret(&data.cipher, principal.user_id, &secret)
+        .context("failed to encrypt totp secret")?;
+
+    // The current secret, if any, stays active until the new one is confirmed.
+    data.repos
+        .users
+        .set_totp_pending_secret(principal.user_id, &encrypted)
+        .await
+        .context("failed to update user")?;
+
+    Ok(HttpResponse::Ok().json(enrollment))
+}
+
+#[post("/mfa/totp/confirm")]
+async fn totp_confirm(
+    principal: Principal,
+    body: web::Json<TotpConfirm>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    body.validate()?;
+
+    let user = data
+        .repos
+        .users
+        .find_by_id(principal.user_id)
+        .await
+        .context("failed to find user")?
+        .ok_or(ApiError::Unauthorized)?;
+
+    let pending = user
+        .totp_pending_secret
+        .clone()
+        .ok_or(ApiError::NoTotpEnrollment)?;
+    let step = mfa::decrypt_secret(&data.cipher, principal.user_id, &pending)
+        .and_then(|secret| {
+            mfa::verify_code(
+                &secret,
+                &user.email,
+                &body.code,
+                None,
+                Utc::now().timestamp() as u64,
+            )
+        })
+        .context("failed to verify totp code")?
+        .ok_or(ApiError::InvalidTotpCode)?;
+
+    // First enrollment comes with recovery codes; re-enrollment keeps the
+    // existing set.
+    let (recovery_codes, hashes) = match user.recovery_codes {
+        Some(_) => (None, None),
+        None => {
+            let (codes, hashes) = mfa::generate_recovery_codes(principal.user_id);
+            (Some(codes), Some(hashes))
+        }
+    };
+
+    if !data
+        .repos
+        .users
+        .enable_totp(principal.user_id, &pending, step, hashes.as_deref())
+        .await
+        .context("failed to update user")?
+    {
+        return Err(ApiError::TotpEnrollmentChanged);
+    }
+
+    let email = MfaEnabled {
+        method: MfaMethod::Totp,
+    };
+    if let Err(err) = data
+        .mail
+        .send(&user.email, user.locale.as_deref(), &email)
+        .await
+    {
+        tracing::error!(error = %err, "Error sending mfa enabled email");
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "totp enabled successfully",
+        "recovery_codes": recovery_codes
+    })))
+}
+
+#[post("/mfa/totp/disable")]
+async fn totp_disable(
+    req: HttpRequest,
+    principal: Principal,
+    body: web::Json<TotpReauth>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user = reauthenticate(&data, &req, &principal, &body).await?;
+
+    if user.totp_enabled != Some(true) {
+        return Err(ApiError::TotpNotEnabled);
+    }
+
+    // Recovery codes stay while a passkey still acts as a second factor.
+    let keep_recovery_codes = user
+        .passkeys
+        .as_ref()
+        .is_some_and(|passkeys| !passkeys.is_empty());
+    data.repos
+        .users
+        .disable_totp(principal.user_id, keep_recovery_codes)
+        .await
+        .context("failed to update user")?;
+
+    let email = MfaDisabled {
+        method: MfaMethod::Totp,
+    };
+    if let Err(err) = data
+        .mail
+        .send(&user.email, user.locale.as_deref(), &email)
+        .await
+    {
+        tracing::error!(error = %err, "Error sending mfa disabled email");
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "totp disabled successfully"
+    })))
+}
+
+#[post("/mfa/recovery-codes")]
+async fn recovery_codes_regenerate(
+    req: HttpRequest,
+    principal: Principal,
+    body: web::Json<TotpReauth>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user = reauthenticate(&data, &req, &principal, &body).await?;
+
+    if user.mfa_methods().is_empty() {
+        return Err(ApiError::NoSecondFactor);
+    }
+
+    let (codes, hashes) = mfa::generate_recovery_codes(principal.user_id);
+    data.repos
+        .users
+        .set_recovery_codes(principal.user_id, &hashes)
+        .await
+        .context("failed to update user")?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "recovery codes regenerated successfully",
+        "recovery_codes": codes
+    })))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(totp_enroll);
+    cfg.service(totp_confirm);
+    cfg.service(totp_disable);
+    cfg.service(recovery_codes_regenerate);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handlers::testing::{self, session_cookie};
+    use crate::repositories::{session::MemorySessionStore, Repositories};
+    use crate::services::otp::CapturedOtps;
+    use actix_web::{http::StatusCode, test};
+    use serde_json::json;
+
+    #[actix_web::test]
+    async fn test_wrong_reauth_passwords_count_towards_lockout() {
+        let sessions = MemorySessionStore::new();
+        let repos = Repositories::memory(sessions.clone());
+        testing::insert_user(&repos, "user@example.com", "password123").await;
+        let app = test::init_service(testing::app(
+            testing::state(repos, CapturedOtps::new()),
+            sessions,
+        ))
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_json(json!({ "email": "user@example.com", "password": "password123" }))
+            .to_request();
+        let cookie = session_cookie(&test::call_service(&app, req).await).unwrap();
+        let enroll = |password: &str| {
+            test::TestRequest::post()
+                .uri("/mfa/totp/enroll")
+                .cookie(cookie.clone())
+                .set_json(json!({ "password": password }))
+                .to_request()
+        };
+
+        for _ in 0..2 {
+            let resp = test::call_service(&app, enroll("wrong-password1")).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        // Past the free failure the account has to wait, even with the
+        // right password.
+        let resp = test::call_service(&app, enroll("password123")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -1,2 +1,12 @@
+pub mod admin;
 pub mod auth;
+pub mod dev;
+pub mod jwks;
+pub mod mfa;
 pub mod product;
+pub mod user;
+pub mod webauthn;
+pub mod webhook;
+
+#[cfg(test)]
+pub(crate) mod testing;
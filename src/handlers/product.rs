@@ -1,32 +1,36 @@
-use actix_web::{web, HttpResponse, Responder, post};
-use mongodb::bson::oid::ObjectId;
-use serde::{Deserialize, Serialize};
+use crate::error::{ApiError, Context};
+use crate::models::product::Product;
+use crate::{middleware::auth::Principal, AppState};
+use actix_web::{post, web, HttpResponse};
+use chrono::Utc;
+use serde_json::json;
 use validator::Validate;
 
-#[derive(Serialize, Validate, Deserialize)]
-struct Product {
-    user_id: ObjectId,
-    name: String,
-    description: String,
-    #[validate(range(min = 0))]
-    quantity: i32,
-    #[validate(range(min = 0))]
-    price: i64,
-    category_id: Vec<ObjectId>,
-    created_at: Option<u64>,
-    updated_at: Option<u64>,
-}
+#[post("/product/create")]
+async fn product_create(
+    principal: Principal,
+    product: web::Json<Product>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut product = product.into_inner();
+    product.validate()?;
 
-#[derive(Serialize, Deserialize)]
-struct Category {
-    name: String,
-    created_at: Option<u64>,
-    updated_at: Option<u64>,
-}
+    let current_time = Utc::now().timestamp() as u64;
+    product.user_id = Some(principal.user_id);
+    product.created_at = Some(current_time);
+    product.updated_at = Some(current_time);
 
-#[post("/product/create")]
-async fn product_create() -> impl Responder {
-    HttpResponse::Ok().body("Product created")
+    let id = data
+        .repos
+        .products
+        .insert(&product)
+        .await
+        .context("failed to insert product")?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "product created successfully",
+        "id": id
+    })))
 }
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
@@ -0,0 +1,115 @@
+//! The application as the handler tests see it: every handler behind the
+//! session and auth middleware, over in-memory repositories.
+
+use crate::middleware::auth::Auth;
+use crate::models::user::User;
+use crate::repositories::{session::MemorySessionStore, Repositories};
+use crate::services::bounce::BounceParsers;
+use crate::services::crypto::SecretBox;
+use crate::services::keys::{KeyRing, SigningKey};
+use crate::services::mail::MailService;
+use crate::services::otp::{CapturedOtps, OtpDelivery, OtpHasher};
+use crate::services::outbox::Outbox;
+use crate::services::session::{session_middleware, SESSION_COOKIE_NAME};
+use crate::services::templates::{TemplateRegistry, TEMPLATE_DIR};
+use crate::services::webauthn::RelyingParty;
+use crate::settings::Settings;
+use crate::AppState;
+use actix_web::body::MessageBody;
+use actix_web::cookie::{Cookie, Key};
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{web, App, Error};
+use chrono::Utc;
+use jsonwebtoken::Algorithm;
+use mongodb::bson::oid::ObjectId;
+use serde_json::json;
+use std::sync::Arc;
+
+pub const ORIGIN: &str = "http://localhost";
+
+/// State over `repos`, signing with a fresh key and capturing OTPs rather
+/// than mailing them.
+pub fn state(repos: Repositories, captured: CapturedOtps) -> AppState {
+    let cipher = SecretBox::new(&[1u8; 32]).unwrap();
+    let now = Utc::now().timestamp() as u64;
+    let key = SigningKey::generate(&cipher, Algorithm::ES256, 1, now).unwrap();
+
+    AppState {
+        mail: MailService::new(
+            TemplateRegistry::load(TEMPLATE_DIR, false).unwrap(),
+            Outbox::new(
+                repos.outbox.clone(),
+                "auth-rs <no-reply@localhost>".parse().unwrap(),
+                cipher.clone(),
+            ),
+            repos.suppressions.clone(),
+        ),
+        repos,
+        settings: Arc::new(
+            Settings::from_layers(None, None, |name| match name {
+                "MONGO_URI" => Some("mongodb://localhost:27017".to_string()),
+                "MONGO_DATABASE" => Some("test".to_string()),
+                _ => None,
+            })
+            .unwrap(),
+        ),
+        keys: KeyRing::from_keys(cipher.clone(), &[key], now).unwrap(),
+        cipher,
+        otp_hasher: OtpHasher::derive(&[1u8; 32]),
+        otp_delivery: OtpDelivery::Capture(captured),
+        rp: RelyingParty {
+            id: "localhost".to_string(),
+            name: "auth-rs".to_string(),
+            origin: ORIGIN.to_string(),
+        },
+        app_url: ORIGIN.to_string(),
+        email_webhook_secret: None,
+        bounce_parsers: BounceParsers::default(),
+    }
+}
+
+/// The handlers wrapped as in [`crate::run`], with `sessions` behind the
+/// session middleware.
+pub fn app(
+    state: AppState,
+    sessions: MemorySessionStore,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .app_data(web::Data::new(state))
+        .wrap(Auth::default())
+        .wrap(session_middleware(sessions, Key::from(&[7u8; 64]), false))
+        .configure(super::auth::configure)
+        .configure(super::mfa::configure)
+        .configure(super::user::configure)
+        .configure(super::webauthn::configure)
+}
+
+/// Stores a verified user and returns its id. The password is hashed at the
+/// lowest cost so logins stay fast.
+pub async fn insert_user(repos: &Repositories, email: &str, password: &str) -> ObjectId {
+    let user: User = serde_json::from_value(json!({
+        "email": email,
+        "password": bcrypt::hash(password, 4).unwrap(),
+        "is_verified": true,
+        "created_at": null,
+        "updated_at": null
+    }))
+    .unwrap();
+    repos.users.insert(&user).await.unwrap()
+}
+
+/// The session cookie set by a response, if any.
+pub fn session_cookie<B>(resp: &ServiceResponse<B>) -> Option<Cookie<'static>> {
+    resp.response()
+        .cookies()
+        .find(|cookie| cookie.name() == SESSION_COOKIE_NAME)
+        .map(|cookie| cookie.into_owned())
+}
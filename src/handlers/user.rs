@@ -0,0 +1,25 @@
+use crate::error::{ApiError, Context};
+use crate::middleware::auth::Principal;
+use crate::models::user::Profile;
+use crate::AppState;
+use actix_web::{get, web, HttpResponse};
+
+#[get("/user/profile")]
+async fn profile(
+    principal: Principal,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user = data
+        .repos
+        .users
+        .find_by_id(principal.user_id)
+        .await
+        .context("failed to find user")?
+        .ok_or(ApiError::Unauthorized)?;
+
+    Ok(HttpResponse::Ok().json(Profile::from(&user)))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(profile);
+}
@@ -0,0 +1,263 @@
+use crate::error::{ApiError, Context};
+use crate::handlers::mfa::reauthenticate;
+use crate::middleware::auth::Principal;
+use crate::models::user::{PasskeyRegistration, TotpReauth};
+use crate::repositories::RepositoryError;
+use crate::services::emails::{MfaEnabled, MfaMethod};
+use crate::services::mfa;
+use crate::services::webauthn::{self, Ceremony, WebauthnChallenge, WebauthnError};
+use crate::AppState;
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use serde_json::json;
+use validator::Validate;
+
+#[post("/webauthn/register/options")]
+async fn register_options(
+    req: HttpRequest,
+    principal: Principal,
+    body: web::Json<TotpReauth>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user = reauthenticate(&data, &req, &principal, &body).await?;
+
+    let challenge = WebauthnChallenge::create(
+        data.repos.webauthn_challenges.as_ref(),
+        Ceremony::Registration,
+        Some(principal.user_id),
+        false,
+    )
+    .await
+    .context("failed to create webauthn challenge")?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "publicKey": data.rp.creation_options(
+            principal.user_id,
+            &user.email,
+            challenge,
+            user.passkeys.as_deref().unwrap_or_default(),
+        )
+    })))
+}
+
+#[post("/webauthn/register")]
+async fn register(
+    principal: Principal,
+    body: web::Json<PasskeyRegistration>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    body.validate()?;
+
+    let challenge = webauthn::client_challenge(&body.credential.response.client_data_json)?;
+    match WebauthnChallenge::take(
+        data.repos.webauthn_challenges.as_ref(),
+        &challenge,
+        Ceremony::Registration,
+    )
+    .await
+    .context("failed to find webauthn challenge")?
+    {
+        Some(record) if record.user_id == Some(principal.user_id) => {}
+        _ => return Err(ApiError::InvalidWebauthnChallenge),
+    }
+
+    let mut passkey = data
+        .rp
+        .verify_registration(&body.credential, &challenge)
+        .map_err(|err| match err {
+            WebauthnError::InvalidResponse(message) => ApiError::InvalidWebauthnResponse(message),
+            err => ApiError::InvalidWebauthnResponse(err.to_string()),
+        })?;
+    passkey.name = body.name;
+
+    let user = data
+        .repos
+        .users
+        .find_by_id(principal.user_id)
+        .await
+        .context("failed to find user")?
+        .ok_or(ApiError::Unauthorized)?;
+
+    // The first second factor comes with recovery codes, as with TOTP.
+    let (recovery_codes, hashes) = if user.mfa_methods().is_empty() {
+        let (codes, hashes) = mfa::generate_recovery_codes(principal.user_id);
+        (Some(codes), Some(hashes))
+    } else {
+        (None, None)
+    };
+
+    match data
+        .repos
+        .users
+        .add_passkey(principal.user_id, &passkey, hashes.as_deref())
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err(ApiError::internal(
+                "failed to update user",
+                "user not found",
+            ))
+        }
+        Err(RepositoryError::Duplicate(_)) => return Err(ApiError::PasskeyAlreadyRegistered),
+        Err(err) => return Err(err).context("failed to update user"),
+    }
+
+    let email = MfaEnabled {
+        method: MfaMethod::Passkey,
+    };
+    if let Err(err) = data
+        .mail
+        .send(&user.email, user.locale.as_deref(), &email)
+        .await
+    {
+        tracing::error!(error = %err, "Error sending mfa enabled email");
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "passkey registered successfully",
+        "credential_id": passkey.credential_id,
+        "recovery_codes": recovery_codes
+    })))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(register_options);
+    cfg.service(register);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handlers::testing::{self, session_cookie, ORIGIN};
+    use crate::repositories::{session::MemorySessionStore, Repositories};
+    use crate::services::mfa::RECOVERY_CODE_COUNT;
+    use crate::services::otp::CapturedOtps;
+    use crate::services::webauthn::testing::SoftAuthenticator;
+    use actix_web::{http::StatusCode, test};
+    use serde_json::{json, Value};
+
+    #[actix_web::test]
+    async fn test_passkey_registration_and_logins_end_to_end() {
+        let sessions = MemorySessionStore::new();
+        let repos = Repositories::memory(sessions.clone());
+        testing::insert_user(&repos, "user@example.com", "password123").await;
+        let app = test::init_service(testing::app(
+            testing::state(repos, CapturedOtps::new()),
+            sessions,
+        ))
+        .await;
+        let mut authenticator = SoftAuthenticator::new();
+
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_json(json!({ "email": "user@example.com", "password": "password123" }))
+            .to_request();
+        let cookie = session_cookie(&test::call_service(&app, req).await).unwrap();
+
+        // Registration: options, then the authenticator's attestation.
+        let req = test::TestRequest::post()
+            .uri("/webauthn/register/options")
+            .cookie(cookie.clone())
+            .set_json(json!({ "password": "password123" }))
+            .to_request();
+        let options: Value = test::call_and_read_body_json(&app, req).await;
+        let challenge = options["publicKey"]["challenge"].as_str().unwrap();
+        let credential = authenticator.create("localhost", challenge, ORIGIN);
+        let req = test::TestRequest::post()
+            .uri("/webauthn/register")
+            .cookie(cookie.clone())
+            .set_json(json!({ "name": "laptop", "credential": credential }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body["recovery_codes"].as_array().unwrap().len(),
+            RECOVERY_CODE_COUNT
+        );
+
+        // Passwordless: any discoverable credential answers the challenge.
+        let req = test::TestRequest::post()
+            .uri("/login/passkey/options")
+            .set_json(json!({ "issue_tokens": true }))
+            .to_request();
+        let options: Value = test::call_and_read_body_json(&app, req).await;
+        let challenge = options["publicKey"]["challenge"].as_str().unwrap();
+        let assertion = authenticator.get("localhost", challenge, ORIGIN);
+        let req = test::TestRequest::post()
+            .uri("/login/passkey")
+            .set_json(json!({ "credential": assertion }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(resp).await;
+        assert!(body["access_token"].is_string());
+
+        // The same assertion cannot be replayed.
+        let req = test::TestRequest::post()
+            .uri("/login/passkey")
+            .set_json(json!({ "credential": assertion }))
+            .to_request();
+        assert!(test::call_service(&app, req)
+            .await
+            .status()
+            .is_client_error());
+
+        // Second factor: the password login asks for the passkey.
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_json(json!({ "email": "user@example.com", "password": "password123" }))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["mfa_required"], true);
+        assert!(body["mfa_methods"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("passkey")));
+        let mfa_token = body["mfa_token"].as_str().unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/login/passkey/options")
+            .set_json(json!({ "mfa_token": mfa_token }))
+            .to_request();
+        let options: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            options["publicKey"]["allowCredentials"][0]["id"],
+            json!(assertion.id)
+        );
+        let challenge = options["publicKey"]["challenge"].as_str().unwrap();
+        let assertion = authenticator.get("localhost", challenge, ORIGIN);
+
+        // The ceremony is bound to the password login that started it.
+        let req = test::TestRequest::post()
+            .uri("/login/passkey")
+            .set_json(json!({ "credential": &assertion }))
+            .to_request();
+        assert!(test::call_service(&app, req)
+            .await
+            .status()
+            .is_client_error());
+
+        let req = test::TestRequest::post()
+            .uri("/login/passkey/options")
+            .set_json(json!({ "mfa_token": mfa_token }))
+            .to_request();
+        let options: Value = test::call_and_read_body_json(&app, req).await;
+        let challenge = options["publicKey"]["challenge"].as_str().unwrap();
+        let assertion = authenticator.get("localhost", challenge, ORIGIN);
+        let req = test::TestRequest::post()
+            .uri("/login/passkey")
+            .set_json(json!({ "mfa_token": mfa_token, "credential": assertion }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let cookie = session_cookie(&resp).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/user/profile")
+            .cookie(cookie)
+            .to_request();
+        let profile: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(profile["passkey_count"], 1);
+    }
+}
@@ -0,0 +1,99 @@
+use crate::error::{ApiError, Context};
+use crate::telemetry;
+use crate::AppState;
+use actix_web::{http::header, post, web, HttpRequest, HttpResponse};
+use ring::constant_time::verify_slices_are_equal;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Deserialize)]
+struct WebhookToken {
+    token: Option<String>,
+}
+
+/// The secret from `?token=` or an `Authorization: Bearer` header. Providers
+/// that post through SNS can only be configured with a URL.
+fn presented_token(req: &HttpRequest) -> Option<String> {
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    bearer.or_else(|| {
+        web::Query::<WebhookToken>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.into_inner().token)
+    })
+}
+
+/// Records hard bounces and complaints reported by the mail provider.
+/// `provider` selects the parser, e.g. `ses`, `sendgrid` or `dsn` for raw
+/// bounce messages. Only mounted when `EMAIL_WEBHOOK_SECRET` is set.
+#[post("/webhooks/email/{provider}")]
+async fn email_webhook(
+    req: HttpRequest,
+    provider: web::Path<String>,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let authorized = match (&data.email_webhook_secret, presented_token(&req)) {
+        (Some(secret), Some(token)) => {
+            verify_slices_are_equal(secret.expose().as_bytes(), token.as_bytes()).is_ok()
+        }
+        _ => false,
+    };
+    if !authorized {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let parser = data
+        .bounce_parsers
+        .get(&provider)
+        .ok_or(ApiError::NotFound("unknown provider"))?;
+    let events = match parser.parse(&body) {
+        Ok(events) => events,
+        Err(err) => {
+            // Reports can carry subscription tokens and signatures.
+            if let Ok(payload) = serde_json::from_slice::<Value>(&body) {
+                tracing::debug!(
+                    provider = parser.name(),
+                    payload = %telemetry::redact(&payload),
+                    "Unparseable email report"
+                );
+            }
+            return Err(ApiError::InvalidEmailReport(format!(
+                "failed to parse report: {}",
+                err
+            )));
+        }
+    };
+
+    for event in &events {
+        tracing::info!(
+            email = %event.email,
+            kind = ?event.kind,
+            provider = parser.name(),
+            reason = ?event.reason,
+            "Suppressing address"
+        );
+        data.repos
+            .suppressions
+            .record(event, parser.name())
+            .await
+            .context("failed to record report")?;
+        data.repos
+            .users
+            .mark_undeliverable(&event.email)
+            .await
+            .context("failed to update user")?;
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "recorded": events.len()
+    })))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(email_webhook);
+}
@@ -1,54 +1,252 @@
-use actix_web::{web, App, HttpServer};
-use dotenvy::dotenv;
-use io::{Error, ErrorKind};
+use actix_web::{cookie::Key, web, App, HttpServer};
+use io::Error;
 use mongodb::Database;
-use std::{env, io};
+use repositories::Repositories;
+use services::bounce::BounceParsers;
+use services::crypto::{SecretBox, SECRET_KEY_LEN};
+use services::keys::{KeyRing, KEY_REFRESH_SECS};
+use services::mail::MailService;
+use services::otp::{CapturedOtps, OtpDelivery, OtpHasher};
+use services::outbox::Outbox;
+use services::rate_limit::{MemoryStore, MongoStore, RateLimitStore};
+use services::templates::{TemplateRegistry, TEMPLATE_DIR};
+use services::webauthn::RelyingParty;
+use settings::{OtpDeliveryMode, RateLimitBackend, Secret, Settings};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
 
+pub mod cli;
 pub mod db;
+pub mod error;
 pub mod handlers;
 pub mod middleware;
+pub mod migrations;
 pub mod models;
+pub mod repositories;
 pub mod services;
+pub mod settings;
+pub mod telemetry;
 
-fn load_server_env() -> Result<(u16, String, String), Error> {
-    dotenv().ok();
-    let port = env::var("SERVER_PORT")
-        .map_err(|err| Error::new(ErrorKind::Other, format!("Error loading PORT: {}", err)))
-        .and_then(|port_str| {
-            port_str
-                .parse::<u16>()
-                .map_err(|err| Error::new(ErrorKind::Other, format!("Error parsing PORT: {}", err)))
-        })?;
-    let host = env::var("SERVER_HOST").map_err(|err| {
-        Error::new(
-            ErrorKind::Other,
-            format!("Error loading SERVER_HOST: {}", err),
-        )
-    });
-    let rust_env = env::var("RUST_ENV").unwrap_or("development".to_string());
+fn load_session_key(settings: &Settings) -> Key {
+    match &settings.session_secret {
+        Some(secret) => Key::from(secret.expose().as_bytes()),
+        None => {
+            tracing::warn!("SESSION_SECRET not set, sessions will not survive a restart");
+            Key::generate()
+        }
+    }
+}
+
+fn load_encryption_key(settings: &Settings) -> Result<(SecretBox, OtpHasher), Error> {
+    let key = match &settings.encryption_key {
+        Some(key) => key.expose().clone(),
+        None => {
+            tracing::warn!("ENCRYPTION_KEY not set, encrypted values will not survive a restart");
+            rand::random::<[u8; SECRET_KEY_LEN]>().to_vec()
+        }
+    };
+    let cipher = SecretBox::new(&key)
+        .map_err(|err| Error::other(format!("Error loading ENCRYPTION_KEY: {}", err)))?;
+    Ok((cipher, OtpHasher::derive(&key)))
+}
+
+fn load_relying_party(settings: &Settings) -> RelyingParty {
+    let name = settings.webauthn.rp_name.clone();
+    match (&settings.webauthn.rp_id, &settings.webauthn.rp_origin) {
+        (Some(id), Some(origin)) => RelyingParty {
+            id: id.clone(),
+            name,
+            origin: origin.clone(),
+        },
+        _ => {
+            tracing::warn!(
+                "WEBAUTHN_RP_ID or WEBAUTHN_RP_ORIGIN not set, passkeys bound to localhost"
+            );
+            RelyingParty {
+                id: "localhost".to_string(),
+                name,
+                origin: format!("http://localhost:{}", settings.server.port),
+            }
+        }
+    }
+}
+
+fn load_app_url(settings: &Settings) -> String {
+    settings
+        .app_url
+        .clone()
+        .unwrap_or_else(|| format!("http://localhost:{}", settings.server.port))
+}
+
+fn load_otp_delivery(settings: &Settings) -> OtpDelivery {
+    match settings.otp_delivery {
+        OtpDeliveryMode::Email => OtpDelivery::Email,
+        OtpDeliveryMode::Capture => {
+            tracing::warn!("OTP_DELIVERY is capture, codes are served from GET /dev/otp");
+            OtpDelivery::Capture(CapturedOtps::new())
+        }
+    }
+}
 
-    Ok((port, host.unwrap(), rust_env))
+/// The shared secret for the bounce webhook. Without one the webhook is not
+/// mounted.
+fn load_email_webhook_secret(settings: &Settings) -> Option<Secret> {
+    if settings.email_webhook_secret.is_none() {
+        tracing::warn!(
+            "EMAIL_WEBHOOK_SECRET not set, bounce and complaint reports are not received"
+        );
+    }
+    settings.email_webhook_secret.clone()
+}
+
+fn load_rate_limit_store(settings: &Settings, db: &Database) -> Arc<dyn RateLimitStore> {
+    match settings.rate_limit_store {
+        RateLimitBackend::Memory => Arc::new(MemoryStore::new()),
+        RateLimitBackend::Mongo => Arc::new(MongoStore::new(db)),
+    }
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    db: Database,
-    rust_env: String,
+    repos: Repositories,
+    settings: Arc<Settings>,
+    keys: KeyRing,
+    cipher: SecretBox,
+    otp_hasher: OtpHasher,
+    otp_delivery: OtpDelivery,
+    mail: MailService,
+    rp: RelyingParty,
+    /// Public base URL of the front end, used for links in emails.
+    app_url: String,
+    email_webhook_secret: Option<Secret>,
+    bounce_parsers: BounceParsers,
 }
 
 pub async fn run() -> Result<(), Error> {
-    let (port, host, rust_env) = load_server_env().unwrap();
-    let (_, db) = db::mongo_client().await.unwrap();
-    let app_state = web::Data::new(AppState { db, rust_env });
+    let settings =
+        Settings::load().map_err(|err| Error::other(format!("Error loading settings: {}", err)))?;
+    telemetry::init(&settings.log)
+        .map_err(|err| Error::other(format!("Error initializing logging: {}", err)))?;
+    let session_key = load_session_key(&settings);
+    let jwt_algorithm = settings.jwt_algorithm;
+    let (cipher, otp_hasher) = load_encryption_key(&settings)?;
+    let otp_delivery = load_otp_delivery(&settings);
+    let mailer = services::mail::load_mailer(&settings.mail)
+        .map_err(|err| Error::other(format!("Error loading mailer: {}", err)))?;
+    let sender = settings.mail.from.clone();
+    let dkim = services::mail::load_dkim(settings.mail.dkim.as_ref(), &sender)
+        .map_err(|err| Error::other(format!("Error loading DKIM key: {}", err)))?;
+    let templates = TemplateRegistry::load(TEMPLATE_DIR, !settings.is_production())
+        .map_err(|err| Error::other(format!("Error loading email templates: {}", err)))?;
+    let email_webhook_secret = load_email_webhook_secret(&settings);
+    let rp = load_relying_party(&settings);
+    let app_url = load_app_url(&settings);
+    let (_, db) = db::mongo_client(&settings.mongo)
+        .await
+        .map_err(|err| Error::other(format!("Error connecting to MongoDB: {}", err)))?;
+    for migration in migrations::apply(&db, false)
+        .await
+        .map_err(|err| Error::other(format!("Error applying migrations: {}", err)))?
+    {
+        tracing::info!(
+            version = migration.version,
+            name = migration.name,
+            "Applied migration"
+        );
+    }
+    let session_store = services::session::MongoSessionStore::new(&db);
+    let rate_limit_store = load_rate_limit_store(&settings, &db);
+    let keys =
+        KeyRing::from_keys(cipher.clone(), &[], 0).map_err(|err| Error::other(err.to_string()))?;
+    keys.refresh(&db, jwt_algorithm)
+        .await
+        .map_err(|err| Error::other(format!("Error loading signing keys: {}", err)))?;
+
+    let refresh_db = db.clone();
+    let refresh_keys = keys.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(KEY_REFRESH_SECS));
+        loop {
+            interval.tick().await;
+            if let Err(err) = refresh_keys.refresh(&refresh_db, jwt_algorithm).await {
+                tracing::error!(error = %err, "Error refreshing signing keys");
+            }
+        }
+    });
+
+    let repos = Repositories::mongo(&db, session_store.clone());
+    actix_web::rt::spawn(services::outbox::run_worker(
+        repos.outbox.clone(),
+        mailer,
+        cipher.clone(),
+    ));
+
+    let app_state = web::Data::new(AppState {
+        mail: MailService::new(
+            templates,
+            Outbox::new(repos.outbox.clone(), sender, cipher.clone()),
+            repos.suppressions.clone(),
+        )
+        .dkim(dkim),
+        repos,
+        settings: Arc::new(settings),
+        keys,
+        cipher,
+        otp_hasher,
+        otp_delivery,
+        rp,
+        app_url,
+        email_webhook_secret,
+        bounce_parsers: BounceParsers::default(),
+    });
+
+    let dev_routes = app_state.otp_delivery.captured().is_some();
+    let (bind_host, bind_port) = (
+        app_state.settings.server.host.clone(),
+        app_state.settings.server.port,
+    );
+    let webhook_routes = app_state.email_webhook_secret.is_some();
 
     HttpServer::new(move || {
+        let mut auth = middleware::auth::Auth::new(middleware::auth::PUBLIC_ROUTES);
+        if dev_routes {
+            auth = auth.allow("/dev/*");
+        }
+        if webhook_routes {
+            auth = auth.allow("/webhooks/*");
+        }
+
         App::new()
             .app_data(app_state.clone())
-            .wrap(middleware::auth::Auth)
+            .wrap(middleware::rate_limit::RateLimit::new(
+                rate_limit_store.clone(),
+                middleware::rate_limit::default_rules(),
+            ))
+            .wrap(auth)
+            .wrap(services::session::session_middleware(
+                session_store.clone(),
+                session_key.clone(),
+                app_state.settings.is_production(),
+            ))
+            .wrap(middleware::request_id::RequestId)
             .configure(handlers::auth::configure)
             .configure(handlers::product::configure)
+            .configure(handlers::jwks::configure)
+            .configure(handlers::mfa::configure)
+            .configure(handlers::user::configure)
+            .configure(handlers::webauthn::configure)
+            .configure(handlers::admin::configure)
+            .configure(|cfg| {
+                if dev_routes {
+                    handlers::dev::configure(cfg);
+                }
+                if webhook_routes {
+                    handlers::webhook::configure(cfg);
+                }
+            })
     })
-    .bind(format!("{}:{}", host, port))?
+    .bind((bind_host, bind_port))?
     .run()
     .await
 }
@@ -2,7 +2,8 @@ use actix_web::{
     cookie::{self, time::Duration},
     get, HttpResponse, Responder,
 };
-use hello_world::run;
+use hello_world::{cli, run};
+use std::env;
 use std::io::Error;
 
 #[get("/")]
@@ -18,7 +19,17 @@ async fn index() -> impl Responder {
 
 #[actix_web::main]
 async fn main() -> Result<(), Error> {
-    run().await
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => run().await,
+        Some("preview-email") => cli::preview_email_command(&args[1..]).await,
+        Some("migrate") => cli::migrate_command(&args[1..]).await,
+        Some("secrets") => cli::secrets_command(&args[1..]),
+        Some(other) => Err(Error::other(format!(
+            "unknown command {}, expected preview-email, migrate or secrets",
+            other
+        ))),
+    }
 }
 
 #[cfg(test)]
@@ -28,10 +39,10 @@ mod tests {
 
     #[actix_web::test]
     async fn test_index() {
-        let mut app = test::init_service(App::new().service(index)).await;
+        let app = test::init_service(App::new().service(index)).await;
 
         let req = test::TestRequest::get().uri("/").to_request();
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), 200);
     }
 }
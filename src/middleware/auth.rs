@@ -1,10 +1,88 @@
+use crate::error::ApiError;
+use crate::services::session::{SESSION_EMAIL_KEY, SESSION_USER_KEY};
+use crate::services::token::Claims;
+use crate::AppState;
 use actix_service::forward_ready;
-use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
-use actix_web::Error;
+use actix_session::SessionExt;
+use actix_web::body::EitherBody;
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest, ResponseError};
 use futures::future::LocalBoxFuture;
+use mongodb::bson::oid::ObjectId;
 use std::future::{ready, Ready};
+use std::rc::Rc;
 
-pub struct Auth;
+/// Routes reachable without an authenticated principal. A trailing `*`
+/// matches any path sharing the prefix.
+pub const PUBLIC_ROUTES: &[&str] = &[
+    "/",
+    "/register",
+    "/verify",
+    "/resend-otp",
+    "/login",
+    "/login/mfa",
+    "/login/recovery",
+    "/login/passkey",
+    "/login/passkey/options",
+    "/logout",
+    "/forgot-password",
+    "/reset-password",
+    "/unlock-account",
+    "/token/refresh",
+    "/.well-known/*",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMethod {
+    Session,
+    Bearer,
+}
+
+/// The authenticated caller, attached to request extensions by [`Auth`].
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub user_id: ObjectId,
+    pub email: String,
+    pub method: AuthMethod,
+}
+
+impl FromRequest for Principal {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Principal>()
+                .cloned()
+                .ok_or(ApiError::Unauthorized),
+        )
+    }
+}
+
+pub struct Auth {
+    public_routes: Rc<Vec<String>>,
+}
+
+impl Auth {
+    pub fn new(public_routes: &[&str]) -> Self {
+        Self {
+            public_routes: Rc::new(public_routes.iter().map(|r| r.to_string()).collect()),
+        }
+    }
+
+    pub fn allow(mut self, route: &str) -> Self {
+        Rc::make_mut(&mut self.public_routes).push(route.to_string());
+        self
+    }
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self::new(PUBLIC_ROUTES)
+    }
+}
 
 impl<S, B> Transform<S, ServiceRequest> for Auth
 where
@@ -12,19 +90,65 @@ where
     S::Future: 'static,
     B: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
     type InitError = ();
     type Transform = AuthMiddleware<S>;
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(AuthMiddleware { service }))
+        ready(Ok(AuthMiddleware {
+            service,
+            public_routes: self.public_routes.clone(),
+        }))
     }
 }
 
 pub struct AuthMiddleware<S> {
     service: S,
+    public_routes: Rc<Vec<String>>,
+}
+
+impl<S> AuthMiddleware<S> {
+    fn is_public(&self, path: &str) -> bool {
+        self.public_routes
+            .iter()
+            .any(|route| match route.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == route,
+            })
+    }
+
+    fn resolve_principal(req: &ServiceRequest) -> Option<Principal> {
+        match req.headers().get(header::AUTHORIZATION) {
+            Some(value) => Self::resolve_bearer(req, value.to_str().ok()?),
+            None => Self::resolve_session(req),
+        }
+    }
+
+    fn resolve_bearer(req: &ServiceRequest, authorization: &str) -> Option<Principal> {
+        let token = authorization.strip_prefix("Bearer ")?;
+        let data = req.app_data::<web::Data<AppState>>()?;
+        let claims = Claims::verify(&data.keys, token.trim()).ok()?;
+
+        Some(Principal {
+            user_id: ObjectId::parse_str(claims.sub).ok()?,
+            email: claims.email,
+            method: AuthMethod::Bearer,
+        })
+    }
+
+    fn resolve_session(req: &ServiceRequest) -> Option<Principal> {
+        let session = req.get_session();
+        let user_id = session.get::<String>(SESSION_USER_KEY).ok().flatten()?;
+        let email = session.get::<String>(SESSION_EMAIL_KEY).ok().flatten()?;
+
+        Some(Principal {
+            user_id: ObjectId::parse_str(user_id).ok()?,
+            email,
+            method: AuthMethod::Session,
+        })
+    }
 }
 
 impl<S, B> Service<ServiceRequest> for AuthMiddleware<S>
@@ -33,19 +157,60 @@ where
     S::Future: 'static,
     B: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        println!("Hi from start. You requested: {}", &req.path());
+        match Self::resolve_principal(&req) {
+            Some(principal) => {
+                tracing::Span::current().record("user_id", principal.user_id.to_hex());
+                req.extensions_mut().insert(principal);
+            }
+            None if !self.is_public(req.path()) => {
+                tracing::debug!("rejecting unauthenticated request");
+                let res = req.into_response(ApiError::Unauthorized.error_response());
+                return Box::pin(async move { Ok(res.map_into_right_body()) });
+            }
+            None => {}
+        }
+
         let fut = self.service.call(req);
 
         Box::pin(async move {
             let res = fut.await?;
-            Ok(res)
+            Ok(res.map_into_left_body())
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_web::test]
+    async fn test_protected_route_requires_principal() {
+        let app = test::init_service(
+            App::new()
+                .wrap(Auth::default())
+                .route("/register", web::post().to(HttpResponse::Ok))
+                .route("/product/create", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/register").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        let req = test::TestRequest::post()
+            .uri("/product/create")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "unauthorized");
+    }
+}
@@ -0,0 +1,294 @@
+use crate::error::ApiError;
+use crate::middleware::auth::Principal;
+use crate::services::rate_limit::{RateLimitStore, Strategy};
+use actix_service::forward_ready;
+use actix_web::body::EitherBody;
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::PayloadError;
+use actix_web::http::Method;
+use actix_web::web::Bytes;
+use actix_web::{Error, HttpMessage, ResponseError};
+use chrono::Utc;
+use futures::future::LocalBoxFuture;
+use futures::Stream;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::future::{ready, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// What a rule counts requests by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyBy {
+    /// The client's socket address.
+    Ip,
+    /// The `email` field of a JSON body, so one address cannot be targeted
+    /// from many clients.
+    Email,
+    /// The authenticated user.
+    Principal,
+}
+
+/// A limit applied to one route. A trailing `*` in `route` matches any path
+/// sharing the prefix, as in [`crate::middleware::auth::PUBLIC_ROUTES`].
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub method: Option<Method>,
+    pub route: String,
+    pub key_by: KeyBy,
+    pub strategy: Strategy,
+}
+
+impl Rule {
+    pub fn new(route: &str, key_by: KeyBy, strategy: Strategy) -> Self {
+        Self {
+            method: None,
+            route: route.to_string(),
+            key_by,
+            strategy,
+        }
+    }
+
+    pub fn method(mut self, method: Method) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        self.method.as_ref().is_none_or(|m| m == method)
+            && match self.route.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == self.route,
+            }
+    }
+}
+
+fn per_window(limit: u32, window_secs: u64) -> Strategy {
+    Strategy::SlidingWindow { limit, window_secs }
+}
+
+/// Limits for the unauthenticated endpoints that send email or check
+/// secrets.
+pub fn default_rules() -> Vec<Rule> {
+    vec![
+        Rule::new("/register", KeyBy::Ip, per_window(10, 3600)).method(Method::POST),
+        Rule::new("/verify", KeyBy::Ip, per_window(30, 600)).method(Method::POST),
+        Rule::new("/verify", KeyBy::Email, per_window(10, 600)).method(Method::POST),
+        Rule::new("/resend-otp", KeyBy::Ip, per_window(20, 3600)).method(Method::POST),
+        Rule::new(
+            "/resend-otp",
+            KeyBy::Email,
+            Strategy::TokenBucket {
+                capacity: 3,
+                refill_per_sec: 1.0 / 300.0,
+            },
+        )
+        .method(Method::POST),
+        Rule::new(
+            "/login",
+            KeyBy::Ip,
+            Strategy::TokenBucket {
+                capacity: 20,
+                refill_per_sec: 1.0,
+            },
+        )
+        .method(Method::POST),
+        Rule::new("/forgot-password", KeyBy::Ip, per_window(20, 3600)).method(Method::POST),
+        Rule::new("/forgot-password", KeyBy::Email, per_window(3, 3600)).method(Method::POST),
+        Rule::new("/mfa/*", KeyBy::Principal, per_window(20, 60)).method(Method::POST),
+    ]
+}
+
+/// Rate-limit middleware. Wrap it inside [`crate::middleware::auth::Auth`]
+/// so principal-keyed rules can see the caller.
+pub struct RateLimit {
+    store: Arc<dyn RateLimitStore>,
+    rules: Rc<Vec<Rule>>,
+}
+
+impl RateLimit {
+    pub fn new(store: Arc<dyn RateLimitStore>, rules: Vec<Rule>) -> Self {
+        Self {
+            store,
+            rules: Rc::new(rules),
+        }
+    }
+
+    pub fn rule(mut self, rule: Rule) -> Self {
+        Rc::make_mut(&mut self.rules).push(rule);
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            store: self.store.clone(),
+            rules: self.rules.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    store: Arc<dyn RateLimitStore>,
+    rules: Rc<Vec<Rule>>,
+}
+
+/// Reads the `email` field of a JSON body and puts the body back for the
+/// handler.
+async fn body_email(req: &mut ServiceRequest) -> Option<String> {
+    let body = req.extract::<Bytes>().await.ok()?;
+    let email = serde_json::from_slice::<Value>(&body)
+        .ok()
+        .and_then(|value| value.get("email")?.as_str().map(str::to_string));
+
+    let stream: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+        Box::pin(futures::stream::once(async move { Ok(body) }));
+    req.set_payload(Payload::from(stream));
+
+    email.map(|email| format!("{:x}", Sha256::digest(email.trim().to_lowercase())))
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let store = self.store.clone();
+        let rules = self.rules.clone();
+
+        Box::pin(async move {
+            let now_ms = Utc::now().timestamp_millis();
+            let mut email = None;
+            let mut retry_after = None;
+
+            let matching: Vec<&Rule> = rules
+                .iter()
+                .filter(|rule| rule.matches(req.method(), req.path()))
+                .collect();
+            for rule in matching {
+                let value = match rule.key_by {
+                    KeyBy::Ip => req.peer_addr().map(|addr| addr.ip().to_string()),
+                    KeyBy::Email => {
+                        if email.is_none() {
+                            email = Some(body_email(&mut req).await);
+                        }
+                        email.clone().flatten()
+                    }
+                    KeyBy::Principal => req
+                        .extensions()
+                        .get::<Principal>()
+                        .map(|principal| principal.user_id.to_hex()),
+                };
+                let value = match value {
+                    Some(value) => value,
+                    None => continue,
+                };
+
+                let key = format!(
+                    "{}:{}:{:?}:{}",
+                    rule.method.as_ref().map_or("*", Method::as_str),
+                    rule.route,
+                    rule.key_by,
+                    value
+                );
+                // Limits protect the service; a failing store must not take
+                // it down, so errors let the request through.
+                match store.hit(&key, &rule.strategy, now_ms).await {
+                    Ok(Some(wait)) => retry_after = retry_after.max(Some(wait)),
+                    Ok(None) => {}
+                    Err(err) => tracing::error!(error = %err, "Error checking rate limit"),
+                }
+            }
+
+            if let Some(retry_after) = retry_after {
+                let res = req.into_response(ApiError::RateLimited { retry_after }.error_response());
+                return Ok(res.map_into_right_body());
+            }
+
+            let res = service.call(req).await?;
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::rate_limit::MemoryStore;
+    use actix_web::http::header;
+    use actix_web::{test, web, App, HttpResponse};
+    use serde_json::json;
+
+    #[actix_web::test]
+    async fn test_email_rule_limits_and_preserves_body() {
+        let rule = Rule::new(
+            "/resend-otp",
+            KeyBy::Email,
+            Strategy::SlidingWindow {
+                limit: 2,
+                window_secs: 60,
+            },
+        );
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(Arc::new(MemoryStore::new()), vec![rule]))
+                .route(
+                    "/resend-otp",
+                    web::post().to(|body: web::Json<Value>| async move {
+                        HttpResponse::Ok().json(body.into_inner())
+                    }),
+                ),
+        )
+        .await;
+
+        for _ in 0..2 {
+            let req = test::TestRequest::post()
+                .uri("/resend-otp")
+                .set_json(json!({ "email": "User@example.com" }))
+                .to_request();
+            let body: Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body["email"], "User@example.com");
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/resend-otp")
+            .set_json(json!({ "email": "user@example.com " }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 429);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "rate_limited");
+
+        let req = test::TestRequest::post()
+            .uri("/resend-otp")
+            .set_json(json!({ "email": "other@example.com" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::telemetry;
+use actix_service::forward_ready;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::LocalBoxFuture;
+use std::future::{ready, Ready};
+use tracing::field::Empty;
+use tracing::Instrument;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest `X-Request-Id` accepted from a client.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Gives every request an id, taken from `X-Request-Id` when the client or a
+/// proxy sent a usable one and a new UUID otherwise. The id is returned in
+/// the `X-Request-Id` response header, and everything logged while handling
+/// the request runs inside a `request` span carrying it. Wrap it outermost
+/// so rejected requests are covered too.
+pub struct RequestId;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware { service }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+/// Ids end up in logs, so only short values of URL-safe characters are
+/// taken as they are.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(&REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_valid(id))
+            .map(str::to_string)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        let span = tracing::info_span!(
+            "request",
+            request_id = %request_id,
+            method = %req.method(),
+            path = req.path(),
+            query = Empty,
+            user_id = Empty,
+            status = Empty,
+        );
+        if !req.query_string().is_empty() {
+            span.record(
+                "query",
+                telemetry::redact_query(req.query_string()).as_str(),
+            );
+        }
+
+        let fut = span.in_scope(|| self.service.call(req));
+        let request_span = span.clone();
+        Box::pin(
+            async move {
+                let mut res = fut.await?;
+                request_span.record("status", res.status().as_u16());
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    res.headers_mut().insert(REQUEST_ID_HEADER, value);
+                }
+                Ok(res)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_web::test]
+    async fn test_request_id_is_echoed_or_generated() {
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestId)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((REQUEST_ID_HEADER, "edge-1234"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get(&REQUEST_ID_HEADER).unwrap(), "edge-1234");
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((REQUEST_ID_HEADER, "bad id"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let id = resp.headers().get(&REQUEST_ID_HEADER).unwrap();
+        assert!(Uuid::parse_str(id.to_str().unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,392 @@
+//! Versioned schema migrations. Each migration runs once per database and is
+//! recorded in `_migrations`; all of them are written to be safe to run again,
+//! since instances booting together may race to apply the same one.
+
+use crate::db::{self, is_duplicate_key};
+use chrono::{Duration, Utc};
+use futures::future::LocalBoxFuture;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::options::IndexOptions;
+use mongodb::{Collection, Database, IndexModel};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::time::Duration as StdDuration;
+use tracing::Instrument;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    MongoError(mongodb::error::Error),
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            MigrationError::MongoError(err) => write!(f, "MongoError: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<mongodb::error::Error> for MigrationError {
+    fn from(err: mongodb::error::Error) -> Self {
+        MigrationError::MongoError(err)
+    }
+}
+
+type MigrationFn = for<'a> fn(&'a Database) -> LocalBoxFuture<'a, Result<(), MigrationError>>;
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub description: &'static str,
+    up: MigrationFn,
+}
+
+/// Every migration, in the order they are applied. Append only: never
+/// renumber or edit one that has shipped.
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_indexes",
+        description: "unique, lookup and TTL indexes for every collection",
+        up: create_indexes,
+    },
+    Migration {
+        version: 2,
+        name: "backfill_user_flags",
+        description: "default is_verified and email_deliverable on older users",
+        up: backfill_user_flags,
+    },
+    Migration {
+        version: 3,
+        name: "purge_legacy_otps",
+        description: "delete codes stored in plaintext or without a date expiry",
+        up: purge_legacy_otps,
+    },
+    Migration {
+        version: 4,
+        name: "backfill_expiry",
+        description: "expire older outbox messages and drop records the TTL indexes cannot expire",
+        up: backfill_expiry,
+    },
+];
+
+/// The index specs are written out as they shipped rather than taken from the
+/// stores, so later changes to a store cannot alter what this migration does.
+/// New indexes belong in a new migration, not here: databases that already
+/// ran this one will not run it again.
+fn create_indexes(db: &Database) -> LocalBoxFuture<'_, Result<(), MigrationError>> {
+    fn index(keys: Document) -> IndexModel {
+        IndexModel::builder().keys(keys).build()
+    }
+
+    fn unique(keys: Document) -> IndexModel {
+        IndexModel::builder()
+            .keys(keys)
+            .options(IndexOptions::builder().unique(true).build())
+            .build()
+    }
+
+    fn ttl(field: &str) -> IndexModel {
+        IndexModel::builder()
+            .keys(doc! { field: 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(StdDuration::from_secs(0))
+                    .build(),
+            )
+            .build()
+    }
+
+    Box::pin(async move {
+        let specs = vec![
+            (
+                "users",
+                vec![
+                    unique(doc! { "email": 1 }),
+                    IndexModel::builder()
+                        .keys(doc! { "passkeys.credential_id": 1 })
+                        .options(
+                            IndexOptions::builder()
+                                .unique(true)
+                                .partial_filter_expression(
+                                    doc! { "passkeys.credential_id": { "$exists": true } },
+                                )
+                                .build(),
+                        )
+                        .build(),
+                ],
+            ),
+            ("otp", vec![index(doc! { "email": 1 }), ttl("expired_at")]),
+            (
+                "sessions",
+                vec![ttl("expires_at"), index(doc! { "user_id": 1 })],
+            ),
+            (
+                "refresh_tokens",
+                vec![
+                    unique(doc! { "token_hash": 1 }),
+                    index(doc! { "family_id": 1 }),
+                    ttl("expires_at"),
+                ],
+            ),
+            (
+                "mfa_challenges",
+                vec![unique(doc! { "token_hash": 1 }), ttl("expires_at")],
+            ),
+            (
+                "login_failures",
+                vec![
+                    IndexModel::builder()
+                        .keys(doc! { "unlock_token_hash": 1 })
+                        .options(IndexOptions::builder().sparse(true).build())
+                        .build(),
+                    ttl("expires_at"),
+                ],
+            ),
+            (
+                "security_events",
+                vec![
+                    index(doc! { "kind": 1, "created_at": -1 }),
+                    index(doc! { "email": 1, "created_at": -1 }),
+                ],
+            ),
+            (
+                "outbox",
+                vec![
+                    index(doc! { "status": 1, "next_attempt_at": 1 }),
+                    ttl("expires_at"),
+                ],
+            ),
+            ("email_suppressions", vec![unique(doc! { "email": 1 })]),
+            (
+                "password_resets",
+                vec![
+                    unique(doc! { "token_hash": 1 }),
+                    index(doc! { "user_id": 1 }),
+                    ttl("expires_at"),
+                ],
+            ),
+            (
+                "webauthn_challenges",
+                vec![unique(doc! { "challenge_hash": 1 }), ttl("expires_at")],
+            ),
+            (
+                "signing_keys",
+                vec![unique(doc! { "kid": 1 }), unique(doc! { "generation": 1 })],
+            ),
+            ("rate_limits", vec![ttl("expires_at")]),
+        ];
+        for (name, models) in specs {
+            db.collection::<Document>(name)
+                .create_indexes(models, None)
+                .instrument(db::span(name, "create_indexes"))
+                .await?;
+        }
+        Ok(())
+    })
+}
+
+fn backfill_user_flags(db: &Database) -> LocalBoxFuture<'_, Result<(), MigrationError>> {
+    Box::pin(async move {
+        let users: Collection<Document> = db.collection("users");
+        users
+            .update_many(
+                doc! { "is_verified": { "$exists": false } },
+                doc! { "$set": { "is_verified": false } },
+                None,
+            )
+            .instrument(db::span("users", "update_many"))
+            .await?;
+
+        // Addresses suppressed before the flag existed stay undeliverable.
+        let suppressed: Vec<String> = db
+            .collection::<Document>("email_suppressions")
+            .distinct("email", None, None)
+            .instrument(db::span("email_suppressions", "distinct"))
+            .await?
+            .into_iter()
+            .filter_map(|email| email.as_str().map(str::to_string))
+            .collect();
+        users
+            .update_many(
+                doc! { "email_deliverable": { "$exists": false }, "email": { "$in": &suppressed } },
+                doc! { "$set": { "email_deliverable": false } },
+                None,
+            )
+            .instrument(db::span("users", "update_many"))
+            .await?;
+        users
+            .update_many(
+                doc! { "email_deliverable": { "$exists": false } },
+                doc! { "$set": { "email_deliverable": true } },
+                None,
+            )
+            .instrument(db::span("users", "update_many"))
+            .await?;
+        Ok(())
+    })
+}
+
+/// Codes from before hashing keep a plaintext `code` and an integer
+/// `expired_at`, which the TTL index ignores, so they would stay on disk
+/// until the address asked for a new one.
+fn purge_legacy_otps(db: &Database) -> LocalBoxFuture<'_, Result<(), MigrationError>> {
+    Box::pin(async move {
+        db.collection::<Document>("otp")
+            .delete_many(
+                doc! {
+                    "$or": [
+                        { "code": { "$exists": true } },
+                        { "expired_at": { "$not": { "$type": "date" } } },
+                    ]
+                },
+                None,
+            )
+            .instrument(db::span("otp", "delete_many"))
+            .await?;
+        Ok(())
+    })
+}
+
+/// Outbox messages queued before `raw` was encrypted keep it in plaintext
+/// once sent, and dead ones never got an `expires_at`, so the TTL index would
+/// keep them forever; they get the 30 days a message now dies with. Sessions
+/// and password resets have always carried a date `expires_at`, but any
+/// record without one is deleted rather than left outside the TTL index.
+fn backfill_expiry(db: &Database) -> LocalBoxFuture<'_, Result<(), MigrationError>> {
+    Box::pin(async move {
+        let outbox: Collection<Document> = db.collection("outbox");
+        outbox
+            .update_many(
+                doc! { "status": "sent", "raw": { "$exists": true } },
+                doc! { "$unset": { "raw": "" } },
+                None,
+            )
+            .instrument(db::span("outbox", "update_many"))
+            .await?;
+        let expires_at = DateTime::from_millis(
+            (Utc::now() + Duration::try_days(30).unwrap()).timestamp_millis(),
+        );
+        outbox
+            .update_many(
+                doc! { "status": "dead", "expires_at": { "$exists": false } },
+                doc! { "$set": { "expires_at": expires_at } },
+                None,
+            )
+            .instrument(db::span("outbox", "update_many"))
+            .await?;
+
+        for name in ["sessions", "password_resets"] {
+            db.collection::<Document>(name)
+                .delete_many(doc! { "expires_at": { "$not": { "$type": "date" } } }, None)
+                .instrument(db::span(name, "delete_many"))
+                .await?;
+        }
+        Ok(())
+    })
+}
+
+/// A migration applied to the database.
+#[derive(Serialize, Deserialize)]
+pub struct MigrationRecord {
+    #[serde(rename = "_id")]
+    pub version: u32,
+    pub name: String,
+    pub applied_at: DateTime,
+    pub duration_ms: i64,
+}
+
+fn collection(db: &Database) -> Collection<MigrationRecord> {
+    db.collection("_migrations")
+}
+
+/// The migrations in `migrations` not yet in `applied`, in order.
+pub fn pending<'a>(migrations: &'a [Migration], applied: &[MigrationRecord]) -> Vec<&'a Migration> {
+    migrations
+        .iter()
+        .filter(|migration| {
+            !applied
+                .iter()
+                .any(|record| record.version == migration.version)
+        })
+        .collect()
+}
+
+/// The migrations recorded in the database. It may hold versions this build
+/// does not know when a newer release has run against it.
+pub async fn applied(db: &Database) -> Result<Vec<MigrationRecord>, MigrationError> {
+    Ok(collection(db)
+        .find(None, None)
+        .instrument(db::span("_migrations", "find"))
+        .await?
+        .try_collect()
+        .await?)
+}
+
+/// Applies the pending migrations in order and returns them. With `dry_run`
+/// nothing is changed and the migrations that would run are returned.
+pub async fn apply(
+    db: &Database,
+    dry_run: bool,
+) -> Result<Vec<&'static Migration>, MigrationError> {
+    let pending = pending(MIGRATIONS, &applied(db).await?);
+    if dry_run {
+        return Ok(pending);
+    }
+
+    for migration in &pending {
+        let started = Utc::now();
+        (migration.up)(db)
+            .instrument(tracing::info_span!(
+                "migration",
+                version = migration.version,
+                name = migration.name
+            ))
+            .await?;
+        let record = MigrationRecord {
+            version: migration.version,
+            name: migration.name.to_string(),
+            applied_at: DateTime::now(),
+            duration_ms: (Utc::now() - started).num_milliseconds(),
+        };
+        match collection(db)
+            .insert_one(&record, None)
+            .instrument(db::span("_migrations", "insert_one"))
+            .await
+        {
+            Ok(_) => {}
+            // Another instance finished the same migration first.
+            Err(err) if is_duplicate_key(&err) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_are_ordered_and_pending_skips_applied() {
+        assert!(MIGRATIONS
+            .windows(2)
+            .all(|pair| pair[0].version < pair[1].version));
+
+        let applied = vec![MigrationRecord {
+            version: 1,
+            name: "create_indexes".to_string(),
+            applied_at: DateTime::now(),
+            duration_ms: 0,
+        }];
+        let versions: Vec<u32> = pending(MIGRATIONS, &applied)
+            .iter()
+            .map(|migration| migration.version)
+            .collect();
+        assert_eq!(versions, vec![2, 3, 4]);
+        assert_eq!(pending(MIGRATIONS, &[]).len(), MIGRATIONS.len());
+    }
+}
@@ -1,2 +1,4 @@
+pub mod product;
 pub mod user;
-pub use user::*;
\ No newline at end of file
+pub use product::*;
+pub use user::*;
@@ -0,0 +1,26 @@
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+#[derive(Clone, Serialize, Validate, Deserialize)]
+pub struct Product {
+    /// Owner of the product, always taken from the authenticated principal.
+    #[serde(skip_deserializing)]
+    pub user_id: Option<ObjectId>,
+    pub name: String,
+    pub description: String,
+    #[validate(range(min = 0))]
+    pub quantity: i32,
+    #[validate(range(min = 0))]
+    pub price: i64,
+    pub category_id: Vec<ObjectId>,
+    pub created_at: Option<u64>,
+    pub updated_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Category {
+    pub name: String,
+    pub created_at: Option<u64>,
+    pub updated_at: Option<u64>,
+}
@@ -1,22 +1,63 @@
+use crate::services::webauthn::{AuthenticationCredential, RegistrationCredential};
 use bcrypt::{hash, DEFAULT_COST};
+use chrono::Utc;
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
-use validator::Validate;
-use chrono::{Duration, Utc};
+use validator::{Validate, ValidationError};
 
-#[derive(Serialize, Validate, Deserialize)]
+#[derive(Clone, Serialize, Validate, Deserialize)]
 pub struct User {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
     #[validate(email)]
     pub email: String,
     #[validate(length(min = 8))]
     pub password: String,
     pub is_verified: Option<bool>,
+    /// TOTP secret encrypted with the application secret box.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+    /// Secret awaiting confirmation with a first code during enrollment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_pending_secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_enabled: Option<bool>,
+    /// Last accepted TOTP time step, used to reject replayed codes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_last_step: Option<u64>,
+    /// Hashes of unused MFA recovery codes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recovery_codes: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passkeys: Option<Vec<Passkey>>,
+    /// Preferred email language, one of the template locales.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    /// Set to false once the address hard bounces or complains; nothing more
+    /// is sent to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_deliverable: Option<bool>,
+    /// Users without a role are regular users.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<RoleType>,
     // role_id: ObjectId,
     // organization_id: Option<ObjectId>,
     pub created_at: Option<u64>,
     pub updated_at: Option<u64>,
 }
 
+/// Password rules for new passwords: 8 to 72 bytes (bcrypt ignores anything
+/// longer) with at least one letter and one digit.
+pub fn validate_password(password: &str) -> Result<(), ValidationError> {
+    if password.len() < 8 || password.len() > 72 {
+        return Err(ValidationError::new("password_length"));
+    }
+    if !password.chars().any(char::is_alphabetic) || !password.chars().any(|c| c.is_ascii_digit()) {
+        return Err(ValidationError::new("password_too_weak"));
+    }
+    Ok(())
+}
+
 impl User {
     pub fn new(email: String, password: String) -> Result<Self, bcrypt::BcryptError> {
         let current_time = Utc::now().timestamp() as u64;
@@ -24,13 +65,92 @@ impl User {
         let trim_email = email.trim().to_lowercase();
 
         Ok(Self {
+            id: None,
             email: trim_email,
             password: hash_password,
             is_verified: Some(false),
+            totp_secret: None,
+            totp_pending_secret: None,
+            totp_enabled: None,
+            totp_last_step: None,
+            recovery_codes: None,
+            passkeys: None,
+            locale: None,
+            email_deliverable: Some(true),
+            role: None,
             created_at: Some(current_time),
             updated_at: Some(current_time),
         })
     }
+
+    /// The second factors a password login can be completed with. Empty when
+    /// the password alone is enough.
+    pub fn mfa_methods(&self) -> Vec<&'static str> {
+        let mut methods = Vec::new();
+        if self.totp_enabled == Some(true) {
+            methods.push("totp");
+        }
+        if self
+            .passkeys
+            .as_ref()
+            .is_some_and(|passkeys| !passkeys.is_empty())
+        {
+            methods.push("passkey");
+        }
+        if !methods.is_empty()
+            && self
+                .recovery_codes
+                .as_ref()
+                .is_some_and(|codes| !codes.is_empty())
+        {
+            methods.push("recovery_code");
+        }
+        methods
+    }
+}
+
+/// A WebAuthn credential registered to a user. Binary values are stored
+/// base64url encoded, as they travel in the ceremonies.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Passkey {
+    pub credential_id: String,
+    /// The credential public key as a COSE_Key.
+    pub public_key: String,
+    pub algorithm: i64,
+    pub sign_count: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transports: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub created_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<u64>,
+}
+
+/// The account as shown to its owner, without any secret material.
+#[derive(Serialize)]
+pub struct Profile {
+    pub email: String,
+    pub is_verified: bool,
+    pub email_deliverable: bool,
+    pub totp_enabled: bool,
+    pub recovery_codes_remaining: usize,
+    pub passkey_count: usize,
+    pub created_at: Option<u64>,
+}
+
+impl From<&User> for Profile {
+    fn from(user: &User) -> Self {
+        Self {
+            email: user.email.clone(),
+            is_verified: user.is_verified.unwrap_or(false),
+            email_deliverable: user.email_deliverable.unwrap_or(true),
+            totp_enabled: user.totp_enabled.unwrap_or(false),
+            recovery_codes_remaining: user.recovery_codes.as_ref().map_or(0, Vec::len),
+            passkey_count: user.passkeys.as_ref().map_or(0, Vec::len),
+            created_at: user.created_at,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Validate)]
@@ -42,6 +162,8 @@ pub struct Organization {
     updated_at: Option<u64>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
 pub enum RoleType {
     Admin,
     User,
@@ -54,47 +176,38 @@ pub enum PermissionType {
 }
 
 pub struct Role {
-    name: RoleType,
-    permissions: Vec<PermissionType>,
-    created_at: Option<u64>,
-    updated_at: Option<u64>,
+    pub name: RoleType,
+    pub permissions: Vec<PermissionType>,
+    pub created_at: Option<u64>,
+    pub updated_at: Option<u64>,
 }
 
 #[derive(Serialize, Validate, Deserialize)]
-pub struct Otp {
+pub struct VerifyOtp {
     #[validate(email)]
     pub email: String,
+    #[validate(range(min = 100000, max = 999999))]
     pub code: u32,
-    pub is_used: bool,
-    pub created_at: Option<u64>,
-    pub expired_at: Option<u64>,
-}
-
-impl Otp {
-    pub fn new(email: String, code: u32) -> Self {
-        let current_time = Utc::now().timestamp() as u64;
-        let expired_at = current_time + Duration::try_minutes(10).unwrap().num_seconds() as u64;
-
-        Self {
-            email,
-            code,
-            is_used: false,
-            created_at: Some(current_time),
-            expired_at: Some(expired_at),
-        }
-    }
 }
 
 #[derive(Serialize, Validate, Deserialize)]
 pub struct ForgotPassword {
     #[validate(email)]
     pub email: String,
-    #[validate(length(min = 8))]
+}
+
+#[derive(Serialize, Validate, Deserialize)]
+pub struct UnlockAccount {
+    #[validate(length(min = 1))]
+    pub token: String,
+}
+
+#[derive(Serialize, Validate, Deserialize)]
+pub struct ResetPassword {
+    #[validate(length(min = 1))]
+    pub token: String,
+    #[validate(custom(function = "validate_password"))]
     pub password: String,
-    pub uuid: String,
-    pub is_used: bool,
-    pub created_at: Option<u64>,
-    pub expired_at: Option<u64>,
 }
 
 #[derive(Serialize, Validate, Deserialize)]
@@ -102,3 +215,75 @@ pub struct ResendOtp {
     #[validate(email)]
     pub email: String,
 }
+
+#[derive(Serialize, Validate, Deserialize)]
+pub struct Login {
+    #[validate(email)]
+    pub email: String,
+    #[validate(length(min = 8))]
+    pub password: String,
+    /// Return a bearer access token and refresh token instead of starting a
+    /// cookie session.
+    pub issue_tokens: Option<bool>,
+}
+
+#[derive(Serialize, Validate, Deserialize)]
+pub struct RefreshTokenRequest {
+    #[validate(length(min = 1))]
+    pub refresh_token: String,
+}
+
+#[derive(Serialize, Validate, Deserialize)]
+pub struct MfaLogin {
+    #[validate(length(min = 1))]
+    pub mfa_token: String,
+    #[validate(length(equal = 6))]
+    pub code: String,
+}
+
+#[derive(Serialize, Validate, Deserialize)]
+pub struct RecoveryLogin {
+    #[validate(length(min = 1))]
+    pub mfa_token: String,
+    #[validate(length(min = 10, max = 32))]
+    pub recovery_code: String,
+}
+
+/// Re-authentication for changes to the second factor. `code` is required
+/// whenever TOTP is already enabled.
+#[derive(Serialize, Validate, Deserialize)]
+pub struct TotpReauth {
+    #[validate(length(min = 8))]
+    pub password: String,
+    #[validate(length(equal = 6))]
+    pub code: Option<String>,
+}
+
+#[derive(Serialize, Validate, Deserialize)]
+pub struct TotpConfirm {
+    #[validate(length(equal = 6))]
+    pub code: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct PasskeyRegistration {
+    #[validate(length(min = 1, max = 64))]
+    pub name: Option<String>,
+    pub credential: RegistrationCredential,
+}
+
+/// Starts a passkey login. With `mfa_token` the passkey is the second factor
+/// of a password login; without it the login is passwordless.
+#[derive(Deserialize, Validate)]
+pub struct PasskeyLoginOptions {
+    #[validate(length(min = 1))]
+    pub mfa_token: Option<String>,
+    pub issue_tokens: Option<bool>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct PasskeyLogin {
+    #[validate(length(min = 1))]
+    pub mfa_token: Option<String>,
+    pub credential: AuthenticationCredential,
+}
@@ -0,0 +1,267 @@
+use super::RepositoryError;
+use crate::db;
+use crate::services::lockout::LoginFailures;
+use futures::future::LocalBoxFuture;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use mongodb::{Collection, Database};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::Instrument;
+
+/// Failed login counters, keyed by account or IP address. A record is gone
+/// once its `expires_at` has passed.
+pub trait LoginFailureRepository: Send + Sync {
+    fn find<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<LoginFailures>, RepositoryError>>;
+
+    /// Counts a failure against `key`, starting a record when there is none,
+    /// and returns the record with the failure included.
+    fn increment<'a>(
+        &'a self,
+        key: &'a str,
+        expires_at: DateTime,
+    ) -> LocalBoxFuture<'a, Result<Option<LoginFailures>, RepositoryError>>;
+
+    /// Locks `key` and restarts its counter, provided it still has at least
+    /// `failures` failures. Returns false when a concurrent failure got there
+    /// first.
+    fn lock<'a>(
+        &'a self,
+        key: &'a str,
+        failures: u32,
+        locked_until: DateTime,
+        unlock_token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>>;
+
+    fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+
+    /// Removes and returns the record locked with `unlock_token_hash`.
+    fn take_by_unlock_token<'a>(
+        &'a self,
+        unlock_token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<LoginFailures>, RepositoryError>>;
+}
+
+pub struct MongoLoginFailureRepository {
+    collection: Collection<LoginFailures>,
+}
+
+impl MongoLoginFailureRepository {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("login_failures"),
+        }
+    }
+}
+
+impl LoginFailureRepository for MongoLoginFailureRepository {
+    fn find<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<LoginFailures>, RepositoryError>> {
+        Box::pin(async move {
+            Ok(self
+                .collection
+                .find_one(doc! { "_id": key }, None)
+                .instrument(db::span("login_failures", "find_one"))
+                .await?)
+        })
+    }
+
+    fn increment<'a>(
+        &'a self,
+        key: &'a str,
+        expires_at: DateTime,
+    ) -> LocalBoxFuture<'a, Result<Option<LoginFailures>, RepositoryError>> {
+        Box::pin(async move {
+            let update = doc! {
+                "$inc": { "failures": 1 },
+                "$set": { "last_failure_at": DateTime::now(), "expires_at": expires_at },
+            };
+            let options = FindOneAndUpdateOptions::builder()
+                .upsert(true)
+                .return_document(ReturnDocument::After)
+                .build();
+            Ok(self
+                .collection
+                .find_one_and_update(doc! { "_id": key }, update, options)
+                .instrument(db::span("login_failures", "find_one_and_update"))
+                .await?)
+        })
+    }
+
+    fn lock<'a>(
+        &'a self,
+        key: &'a str,
+        failures: u32,
+        locked_until: DateTime,
+        unlock_token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>> {
+        Box::pin(async move {
+            let filter = doc! { "_id": key, "failures": { "$gte": failures } };
+            let update = doc! {
+                "$set": {
+                    "failures": 0,
+                    "locked_until": locked_until,
+                    "unlock_token_hash": unlock_token_hash,
+                    "expires_at": locked_until,
+                }
+            };
+            let result = self
+                .collection
+                .update_one(filter, update, None)
+                .instrument(db::span("login_failures", "update_one"))
+                .await?;
+            Ok(result.modified_count == 1)
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            self.collection
+                .delete_one(doc! { "_id": key }, None)
+                .instrument(db::span("login_failures", "delete_one"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn take_by_unlock_token<'a>(
+        &'a self,
+        unlock_token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<LoginFailures>, RepositoryError>> {
+        Box::pin(async move {
+            Ok(self
+                .collection
+                .find_one_and_delete(doc! { "unlock_token_hash": unlock_token_hash }, None)
+                .instrument(db::span("login_failures", "find_one_and_delete"))
+                .await?)
+        })
+    }
+}
+
+/// Failure counters held in process memory, for tests and local runs.
+#[derive(Default)]
+pub struct MemoryLoginFailureRepository {
+    records: Mutex<HashMap<String, LoginFailures>>,
+}
+
+impl MemoryLoginFailureRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The live record for `key`, dropping it once expired as the TTL index
+    /// would.
+    fn live<'a>(
+        records: &'a mut HashMap<String, LoginFailures>,
+        key: &str,
+    ) -> Option<&'a mut LoginFailures> {
+        if records
+            .get(key)
+            .is_some_and(|record| record.expires_at <= DateTime::now())
+        {
+            records.remove(key);
+        }
+        records.get_mut(key)
+    }
+
+    fn increment_sync(&self, key: &str, expires_at: DateTime) -> LoginFailures {
+        let mut records = self.records.lock().unwrap();
+        if Self::live(&mut records, key).is_none() {
+            records.insert(
+                key.to_string(),
+                LoginFailures {
+                    key: key.to_string(),
+                    failures: 0,
+                    last_failure_at: DateTime::now(),
+                    locked_until: None,
+                    unlock_token_hash: None,
+                    expires_at,
+                },
+            );
+        }
+        let record = records.get_mut(key).unwrap();
+        record.failures += 1;
+        record.last_failure_at = DateTime::now();
+        record.expires_at = expires_at;
+        record.clone()
+    }
+
+    fn lock_sync(
+        &self,
+        key: &str,
+        failures: u32,
+        locked_until: DateTime,
+        unlock_token_hash: &str,
+    ) -> bool {
+        let mut records = self.records.lock().unwrap();
+        match Self::live(&mut records, key) {
+            Some(record) if record.failures >= failures => {
+                record.failures = 0;
+                record.locked_until = Some(locked_until);
+                record.unlock_token_hash = Some(unlock_token_hash.to_string());
+                record.expires_at = locked_until;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn take_by_unlock_token_sync(&self, unlock_token_hash: &str) -> Option<LoginFailures> {
+        let mut records = self.records.lock().unwrap();
+        let key = records
+            .values()
+            .find(|record| record.unlock_token_hash.as_deref() == Some(unlock_token_hash))
+            .map(|record| record.key.clone())?;
+        Self::live(&mut records, &key)?;
+        records.remove(&key)
+    }
+}
+
+impl LoginFailureRepository for MemoryLoginFailureRepository {
+    fn find<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<LoginFailures>, RepositoryError>> {
+        let record =
+            Self::live(&mut self.records.lock().unwrap(), key).map(|record| record.clone());
+        Box::pin(async move { Ok(record) })
+    }
+
+    fn increment<'a>(
+        &'a self,
+        key: &'a str,
+        expires_at: DateTime,
+    ) -> LocalBoxFuture<'a, Result<Option<LoginFailures>, RepositoryError>> {
+        let record = self.increment_sync(key, expires_at);
+        Box::pin(async move { Ok(Some(record)) })
+    }
+
+    fn lock<'a>(
+        &'a self,
+        key: &'a str,
+        failures: u32,
+        locked_until: DateTime,
+        unlock_token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>> {
+        let locked = self.lock_sync(key, failures, locked_until, unlock_token_hash);
+        Box::pin(async move { Ok(locked) })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        self.records.lock().unwrap().remove(key);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn take_by_unlock_token<'a>(
+        &'a self,
+        unlock_token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<LoginFailures>, RepositoryError>> {
+        let record = self.take_by_unlock_token_sync(unlock_token_hash);
+        Box::pin(async move { Ok(record) })
+    }
+}
@@ -0,0 +1,181 @@
+use super::RepositoryError;
+use crate::db;
+use crate::services::mfa::{MfaChallenge, MFA_MAX_ATTEMPTS};
+use futures::future::LocalBoxFuture;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use mongodb::{Collection, Database};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::Instrument;
+
+/// Pending second-factor steps, keyed by the hash of their token. A
+/// challenge is live while it is unexpired and has fewer than
+/// [`MFA_MAX_ATTEMPTS`] attempts.
+pub trait MfaChallengeRepository: Send + Sync {
+    fn insert<'a>(
+        &'a self,
+        challenge: &'a MfaChallenge,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+
+    /// The live challenge, without counting an attempt.
+    fn find<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<MfaChallenge>, RepositoryError>>;
+
+    /// Counts an attempt against the live challenge and returns it with the
+    /// attempt included.
+    fn begin_attempt<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<MfaChallenge>, RepositoryError>>;
+
+    fn delete<'a>(&'a self, token_hash: &'a str)
+        -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+}
+
+pub struct MongoMfaChallengeRepository {
+    collection: Collection<MfaChallenge>,
+}
+
+impl MongoMfaChallengeRepository {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("mfa_challenges"),
+        }
+    }
+}
+
+impl MfaChallengeRepository for MongoMfaChallengeRepository {
+    fn insert<'a>(
+        &'a self,
+        challenge: &'a MfaChallenge,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            self.collection
+                .insert_one(challenge, None)
+                .instrument(db::span("mfa_challenges", "insert_one"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn find<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<MfaChallenge>, RepositoryError>> {
+        Box::pin(async move {
+            let filter = doc! {
+                "token_hash": token_hash,
+                "attempts": { "$lt": MFA_MAX_ATTEMPTS },
+                "expires_at": { "$gt": DateTime::now() },
+            };
+            Ok(self
+                .collection
+                .find_one(filter, None)
+                .instrument(db::span("mfa_challenges", "find_one"))
+                .await?)
+        })
+    }
+
+    fn begin_attempt<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<MfaChallenge>, RepositoryError>> {
+        Box::pin(async move {
+            let filter = doc! {
+                "token_hash": token_hash,
+                "attempts": { "$lt": MFA_MAX_ATTEMPTS },
+                "expires_at": { "$gt": DateTime::now() },
+            };
+            let update = doc! { "$inc": { "attempts": 1 } };
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build();
+            Ok(self
+                .collection
+                .find_one_and_update(filter, update, options)
+                .instrument(db::span("mfa_challenges", "find_one_and_update"))
+                .await?)
+        })
+    }
+
+    fn delete<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            self.collection
+                .delete_one(doc! { "token_hash": token_hash }, None)
+                .instrument(db::span("mfa_challenges", "delete_one"))
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// Challenges held in process memory, for tests and local runs.
+#[derive(Default)]
+pub struct MemoryMfaChallengeRepository {
+    challenges: Mutex<HashMap<String, MfaChallenge>>,
+}
+
+impl MemoryMfaChallengeRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn update_live(
+        &self,
+        token_hash: &str,
+        apply: impl FnOnce(&mut MfaChallenge),
+    ) -> Option<MfaChallenge> {
+        let mut challenges = self.challenges.lock().unwrap();
+        let challenge = challenges.get_mut(token_hash).filter(|challenge| {
+            challenge.attempts < MFA_MAX_ATTEMPTS && challenge.expires_at > DateTime::now()
+        })?;
+        apply(challenge);
+        Some(challenge.clone())
+    }
+}
+
+impl MfaChallengeRepository for MemoryMfaChallengeRepository {
+    fn insert<'a>(
+        &'a self,
+        challenge: &'a MfaChallenge,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        let mut challenges = self.challenges.lock().unwrap();
+        let result = if challenges.contains_key(&challenge.token_hash) {
+            Err(RepositoryError::Duplicate(challenge.token_hash.clone()))
+        } else {
+            challenges.insert(challenge.token_hash.clone(), challenge.clone());
+            Ok(())
+        };
+        Box::pin(async move { result })
+    }
+
+    fn find<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<MfaChallenge>, RepositoryError>> {
+        let challenge = self.update_live(token_hash, |_| {});
+        Box::pin(async move { Ok(challenge) })
+    }
+
+    fn begin_attempt<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<MfaChallenge>, RepositoryError>> {
+        let challenge = self.update_live(token_hash, |challenge| challenge.attempts += 1);
+        Box::pin(async move { Ok(challenge) })
+    }
+
+    fn delete<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        self.challenges.lock().unwrap().remove(token_hash);
+        Box::pin(async move { Ok(()) })
+    }
+}
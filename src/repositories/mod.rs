@@ -0,0 +1,128 @@
+//! Storage behind the request handlers. Handlers reach every store through
+//! these traits rather than through collections, so the same flows run
+//! against MongoDB in production and against process memory in tests.
+
+pub mod login_failure;
+pub mod mfa_challenge;
+pub mod otp;
+pub mod outbox;
+pub mod password_reset;
+pub mod product;
+pub mod refresh_token;
+pub mod security_event;
+pub mod session;
+pub mod suppression;
+pub mod user;
+pub mod webauthn_challenge;
+
+use crate::services::session::MongoSessionStore;
+use login_failure::{
+    LoginFailureRepository, MemoryLoginFailureRepository, MongoLoginFailureRepository,
+};
+use mfa_challenge::{
+    MemoryMfaChallengeRepository, MfaChallengeRepository, MongoMfaChallengeRepository,
+};
+use mongodb::Database;
+use otp::{MemoryOtpRepository, MongoOtpRepository, OtpRepository};
+use outbox::{MemoryOutboxRepository, MongoOutboxRepository, OutboxRepository};
+use password_reset::{
+    MemoryPasswordResetRepository, MongoPasswordResetRepository, PasswordResetRepository,
+};
+use product::{MemoryProductRepository, MongoProductRepository, ProductRepository};
+use refresh_token::{
+    MemoryRefreshTokenRepository, MongoRefreshTokenRepository, RefreshTokenRepository,
+};
+use security_event::{
+    MemorySecurityEventRepository, MongoSecurityEventRepository, SecurityEventRepository,
+};
+use session::{MemorySessionStore, SessionRepository};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::Arc;
+use suppression::{MemorySuppressionRepository, MongoSuppressionRepository, SuppressionRepository};
+use user::{MemoryUserRepository, MongoUserRepository, UserRepository};
+use webauthn_challenge::{
+    MemoryWebauthnChallengeRepository, MongoWebauthnChallengeRepository,
+    WebauthnChallengeRepository,
+};
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    /// A record with the same unique key already exists.
+    Duplicate(String),
+    /// No record matched the given key.
+    NotFound(String),
+    MongoError(mongodb::error::Error),
+}
+
+impl Display for RepositoryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            RepositoryError::Duplicate(key) => write!(f, "Duplicate: {}", key),
+            RepositoryError::NotFound(key) => write!(f, "NotFound: {}", key),
+            RepositoryError::MongoError(err) => write!(f, "MongoError: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RepositoryError {}
+
+impl From<mongodb::error::Error> for RepositoryError {
+    fn from(err: mongodb::error::Error) -> Self {
+        RepositoryError::MongoError(err)
+    }
+}
+
+/// The repositories held in [`crate::AppState`].
+#[derive(Clone)]
+pub struct Repositories {
+    pub users: Arc<dyn UserRepository>,
+    pub otps: Arc<dyn OtpRepository>,
+    pub sessions: Arc<dyn SessionRepository>,
+    pub login_failures: Arc<dyn LoginFailureRepository>,
+    pub mfa_challenges: Arc<dyn MfaChallengeRepository>,
+    pub webauthn_challenges: Arc<dyn WebauthnChallengeRepository>,
+    pub refresh_tokens: Arc<dyn RefreshTokenRepository>,
+    pub password_resets: Arc<dyn PasswordResetRepository>,
+    pub suppressions: Arc<dyn SuppressionRepository>,
+    pub security_events: Arc<dyn SecurityEventRepository>,
+    pub outbox: Arc<dyn OutboxRepository>,
+    pub products: Arc<dyn ProductRepository>,
+}
+
+impl Repositories {
+    pub fn mongo(db: &Database, sessions: MongoSessionStore) -> Self {
+        Self {
+            users: Arc::new(MongoUserRepository::new(db)),
+            otps: Arc::new(MongoOtpRepository::new(db)),
+            sessions: Arc::new(sessions),
+            login_failures: Arc::new(MongoLoginFailureRepository::new(db)),
+            mfa_challenges: Arc::new(MongoMfaChallengeRepository::new(db)),
+            webauthn_challenges: Arc::new(MongoWebauthnChallengeRepository::new(db)),
+            refresh_tokens: Arc::new(MongoRefreshTokenRepository::new(db)),
+            password_resets: Arc::new(MongoPasswordResetRepository::new(db)),
+            suppressions: Arc::new(MongoSuppressionRepository::new(db)),
+            security_events: Arc::new(MongoSecurityEventRepository::new(db)),
+            outbox: Arc::new(MongoOutboxRepository::new(db)),
+            products: Arc::new(MongoProductRepository::new(db)),
+        }
+    }
+
+    /// Empty in-memory repositories. `sessions` should be the store given to
+    /// the session middleware so revoking a user's sessions is seen by it.
+    pub fn memory(sessions: MemorySessionStore) -> Self {
+        Self {
+            users: Arc::new(MemoryUserRepository::new()),
+            otps: Arc::new(MemoryOtpRepository::new()),
+            sessions: Arc::new(sessions),
+            login_failures: Arc::new(MemoryLoginFailureRepository::new()),
+            mfa_challenges: Arc::new(MemoryMfaChallengeRepository::new()),
+            webauthn_challenges: Arc::new(MemoryWebauthnChallengeRepository::new()),
+            refresh_tokens: Arc::new(MemoryRefreshTokenRepository::new()),
+            password_resets: Arc::new(MemoryPasswordResetRepository::new()),
+            suppressions: Arc::new(MemorySuppressionRepository::new()),
+            security_events: Arc::new(MemorySecurityEventRepository::new()),
+            outbox: Arc::new(MemoryOutboxRepository::new()),
+            products: Arc::new(MemoryProductRepository::new()),
+        }
+    }
+}
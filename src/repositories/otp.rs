@@ -0,0 +1,263 @@
+use super::RepositoryError;
+use crate::db;
+use crate::services::otp::{Otp, OTP_MAX_ATTEMPTS};
+use futures::future::LocalBoxFuture;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use mongodb::{Collection, Database};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::Instrument;
+
+/// Email confirmation codes. A code is live while it is unused, unexpired
+/// and has fewer than [`OTP_MAX_ATTEMPTS`] attempts.
+pub trait OtpRepository: Send + Sync {
+    fn insert<'a>(&'a self, otp: &'a Otp) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+
+    /// Counts an attempt against the live code for `email` and returns it
+    /// with the attempt included, or `None` when there is no live code.
+    fn begin_attempt<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<Otp>, RepositoryError>>;
+
+    /// Marks a code used. Returns false when it already was.
+    fn mark_used(&self, id: ObjectId) -> LocalBoxFuture<'_, Result<bool, RepositoryError>>;
+
+    /// Replaces the unused code for `email`, resetting its attempts and
+    /// expiry. Fails with [`RepositoryError::NotFound`] when there is none;
+    /// codes are only ever created for registered users.
+    fn replace<'a>(
+        &'a self,
+        email: &'a str,
+        code_hash: &'a str,
+        expired_at: DateTime,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+}
+
+pub struct MongoOtpRepository {
+    collection: Collection<Otp>,
+}
+
+impl MongoOtpRepository {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("otp"),
+        }
+    }
+}
+
+impl OtpRepository for MongoOtpRepository {
+    fn insert<'a>(&'a self, otp: &'a Otp) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            self.collection
+                .insert_one(otp, None)
+                .instrument(db::span("otp", "insert_one"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn begin_attempt<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<Otp>, RepositoryError>> {
+        Box::pin(async move {
+            let filter = doc! {
+                "email": email,
+                "is_used": false,
+                "attempts": { "$lt": OTP_MAX_ATTEMPTS },
+                "expired_at": { "$gt": DateTime::now() },
+            };
+            let update = doc! { "$inc": { "attempts": 1 } };
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build();
+            Ok(self
+                .collection
+                .find_one_and_update(filter, update, options)
+                .instrument(db::span("otp", "find_one_and_update"))
+                .await?)
+        })
+    }
+
+    fn mark_used(&self, id: ObjectId) -> LocalBoxFuture<'_, Result<bool, RepositoryError>> {
+        Box::pin(async move {
+            let filter = doc! { "_id": id, "is_used": false };
+            let update = doc! { "$set": { "is_used": true } };
+            let result = self
+                .collection
+                .update_one(filter, update, None)
+                .instrument(db::span("otp", "update_one"))
+                .await?;
+            Ok(result.modified_count == 1)
+        })
+    }
+
+    fn replace<'a>(
+        &'a self,
+        email: &'a str,
+        code_hash: &'a str,
+        expired_at: DateTime,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            let update = doc! {
+                "$set": {
+                    "code_hash": code_hash,
+                    "attempts": 0,
+                    "expired_at": expired_at,
+                },
+                "$unset": { "code": "" },
+            };
+            let result = self
+                .collection
+                .update_one(doc! { "email": email, "is_used": false }, update, None)
+                .instrument(db::span("otp", "update_one"))
+                .await?;
+            if result.matched_count == 0 {
+                return Err(RepositoryError::NotFound(email.to_string()));
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Codes held in process memory, for tests and local runs. Expired codes
+/// are never returned but, unlike with the TTL index, stay until replaced.
+#[derive(Default)]
+pub struct MemoryOtpRepository {
+    otps: Mutex<HashMap<ObjectId, Otp>>,
+}
+
+impl MemoryOtpRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn begin_attempt_sync(&self, email: &str) -> Option<Otp> {
+        let now = DateTime::now();
+        let mut otps = self.otps.lock().unwrap();
+        let otp = otps.values_mut().find(|otp| {
+            otp.email == email
+                && otp.is_used == Some(false)
+                && otp.attempts < OTP_MAX_ATTEMPTS
+                && otp.expired_at > now
+        })?;
+        otp.attempts += 1;
+        Some(otp.clone())
+    }
+
+    fn mark_used_sync(&self, id: ObjectId) -> bool {
+        match self.otps.lock().unwrap().get_mut(&id) {
+            Some(otp) if otp.is_used == Some(false) => {
+                otp.is_used = Some(true);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn replace_sync(&self, email: &str, code_hash: &str, expired_at: DateTime) -> bool {
+        let mut otps = self.otps.lock().unwrap();
+        match otps
+            .values_mut()
+            .find(|otp| otp.email == email && otp.is_used == Some(false))
+        {
+            Some(otp) => {
+                otp.code_hash = code_hash.to_string();
+                otp.attempts = 0;
+                otp.expired_at = expired_at;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl OtpRepository for MemoryOtpRepository {
+    fn insert<'a>(&'a self, otp: &'a Otp) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        let id = otp.id.unwrap_or_default();
+        let mut otp = otp.clone();
+        otp.id = Some(id);
+        self.otps.lock().unwrap().insert(id, otp);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn begin_attempt<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<Otp>, RepositoryError>> {
+        let otp = self.begin_attempt_sync(email);
+        Box::pin(async move { Ok(otp) })
+    }
+
+    fn mark_used(&self, id: ObjectId) -> LocalBoxFuture<'_, Result<bool, RepositoryError>> {
+        let marked = self.mark_used_sync(id);
+        Box::pin(async move { Ok(marked) })
+    }
+
+    fn replace<'a>(
+        &'a self,
+        email: &'a str,
+        code_hash: &'a str,
+        expired_at: DateTime,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        let result = if self.replace_sync(email, code_hash, expired_at) {
+            Ok(())
+        } else {
+            Err(RepositoryError::NotFound(email.to_string()))
+        };
+        Box::pin(async move { result })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::otp::OtpHasher;
+
+    #[actix_web::test]
+    async fn test_memory_repository_honors_attempts_and_expiry() {
+        let hasher = OtpHasher::derive(&[1u8; 32]);
+        let repo = MemoryOtpRepository::new();
+        let (otp, code) = Otp::new("user@example.com".to_string(), &hasher).unwrap();
+        otp.insert_otp(&repo).await.unwrap();
+
+        for _ in 0..OTP_MAX_ATTEMPTS {
+            let wrong = if code == 999999 { 100000 } else { code + 1 };
+            let verified = Otp::verify_otp(wrong, "user@example.com".into(), &hasher, &repo);
+            assert!(!verified.await.unwrap());
+        }
+        // Out of attempts, the right code no longer works either.
+        let verified = Otp::verify_otp(code, "user@example.com".into(), &hasher, &repo);
+        assert!(!verified.await.unwrap());
+
+        let (email, code) = Otp::update_otp("user@example.com".into(), &hasher, &repo)
+            .await
+            .unwrap();
+        assert!(Otp::verify_otp(code, email.clone(), &hasher, &repo)
+            .await
+            .unwrap());
+        // A code only works once.
+        assert!(!Otp::verify_otp(code, email, &hasher, &repo).await.unwrap());
+
+        // Only a pending code is replaced; none is created for other emails.
+        let expired_at = DateTime::from_millis(DateTime::now().timestamp_millis() - 1000);
+        let late_hash = hasher.hash("late@example.com", 123456);
+        assert!(matches!(
+            repo.replace("late@example.com", &late_hash, expired_at)
+                .await,
+            Err(RepositoryError::NotFound(_))
+        ));
+        let (otp, _) = Otp::new("late@example.com".to_string(), &hasher).unwrap();
+        otp.insert_otp(&repo).await.unwrap();
+        repo.replace("late@example.com", &late_hash, expired_at)
+            .await
+            .unwrap();
+        assert!(repo
+            .begin_attempt("late@example.com")
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,325 @@
+use super::RepositoryError;
+use crate::db;
+use crate::services::outbox::{OutboxMessage, OutboxStatus};
+use futures::future::LocalBoxFuture;
+use mongodb::bson::{doc, oid::ObjectId, DateTime, Document};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use mongodb::{Collection, Database};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::Instrument;
+
+/// Queued email. A message is due while it is pending, or sending with an
+/// expired lease, and its `next_attempt_at` has passed.
+///
+/// The updates after a delivery only apply while the message is still
+/// sending under the claimed `attempt`, so a worker whose lease expired
+/// cannot overwrite the outcome of the one that re-claimed the message.
+pub trait OutboxRepository: Send + Sync {
+    fn insert<'a>(
+        &'a self,
+        message: &'a OutboxMessage,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+
+    fn find(
+        &self,
+        id: ObjectId,
+    ) -> LocalBoxFuture<'_, Result<Option<OutboxMessage>, RepositoryError>>;
+
+    /// Takes the due message with the earliest `next_attempt_at`, leasing it
+    /// until `lease_until`, and returns it with the attempt counted.
+    fn claim(
+        &self,
+        lease_until: DateTime,
+    ) -> LocalBoxFuture<'_, Result<Option<OutboxMessage>, RepositoryError>>;
+
+    /// Marks the message sent and drops its contents.
+    fn mark_sent(
+        &self,
+        id: ObjectId,
+        attempt: u32,
+        expires_at: DateTime,
+    ) -> LocalBoxFuture<'_, Result<(), RepositoryError>>;
+
+    /// Puts the message back in the queue after a failed delivery.
+    fn retry<'a>(
+        &'a self,
+        id: ObjectId,
+        attempt: u32,
+        error: &'a str,
+        next_attempt_at: DateTime,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+
+    /// Gives up on the message. Its contents stay, for inspection, until it
+    /// expires.
+    fn mark_dead<'a>(
+        &'a self,
+        id: ObjectId,
+        attempt: u32,
+        error: &'a str,
+        expires_at: DateTime,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+}
+
+/// Matches the message while it is sending under `attempt`.
+fn leased(id: ObjectId, attempt: u32) -> Document {
+    doc! { "_id": id, "status": "sending", "attempts": attempt }
+}
+
+pub struct MongoOutboxRepository {
+    collection: Collection<OutboxMessage>,
+}
+
+impl MongoOutboxRepository {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("outbox"),
+        }
+    }
+}
+
+impl OutboxRepository for MongoOutboxRepository {
+    fn insert<'a>(
+        &'a self,
+        message: &'a OutboxMessage,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            self.collection
+                .insert_one(message, None)
+                .instrument(db::span("outbox", "insert_one"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn find(
+        &self,
+        id: ObjectId,
+    ) -> LocalBoxFuture<'_, Result<Option<OutboxMessage>, RepositoryError>> {
+        Box::pin(async move {
+            Ok(self
+                .collection
+                .find_one(doc! { "_id": id }, None)
+                .instrument(db::span("outbox", "find_one"))
+                .await?)
+        })
+    }
+
+    fn claim(
+        &self,
+        lease_until: DateTime,
+    ) -> LocalBoxFuture<'_, Result<Option<OutboxMessage>, RepositoryError>> {
+        Box::pin(async move {
+            let filter = doc! {
+                "status": { "$in": ["pending", "sending"] },
+                "next_attempt_at": { "$lte": DateTime::now() },
+            };
+            let update = doc! {
+                "$set": { "status": "sending", "next_attempt_at": lease_until },
+                "$inc": { "attempts": 1 },
+            };
+            let options = FindOneAndUpdateOptions::builder()
+                .sort(doc! { "next_attempt_at": 1 })
+                .return_document(ReturnDocument::After)
+                .build();
+            Ok(self
+                .collection
+                .find_one_and_update(filter, update, options)
+                .instrument(db::span("outbox", "find_one_and_update"))
+                .await?)
+        })
+    }
+
+    fn mark_sent(
+        &self,
+        id: ObjectId,
+        attempt: u32,
+        expires_at: DateTime,
+    ) -> LocalBoxFuture<'_, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            let update = doc! {
+                "$set": {
+                    "status": "sent",
+                    "sent_at": DateTime::now(),
+                    "last_error": null,
+                    "expires_at": expires_at,
+                },
+                "$unset": { "raw": "" },
+            };
+            self.collection
+                .update_one(leased(id, attempt), update, None)
+                .instrument(db::span("outbox", "update_one"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn retry<'a>(
+        &'a self,
+        id: ObjectId,
+        attempt: u32,
+        error: &'a str,
+        next_attempt_at: DateTime,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            let update = doc! {
+                "$set": {
+                    "status": "pending",
+                    "last_error": error,
+                    "next_attempt_at": next_attempt_at,
+                }
+            };
+            self.collection
+                .update_one(leased(id, attempt), update, None)
+                .instrument(db::span("outbox", "update_one"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn mark_dead<'a>(
+        &'a self,
+        id: ObjectId,
+        attempt: u32,
+        error: &'a str,
+        expires_at: DateTime,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            let update = doc! {
+                "$set": {
+                    "status": "dead",
+                    "last_error": error,
+                    "expires_at": expires_at,
+                }
+            };
+            self.collection
+                .update_one(leased(id, attempt), update, None)
+                .instrument(db::span("outbox", "update_one"))
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// Messages held in process memory, for tests and local runs.
+#[derive(Default)]
+pub struct MemoryOutboxRepository {
+    messages: Mutex<HashMap<ObjectId, OutboxMessage>>,
+}
+
+impl MemoryOutboxRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert_sync(&self, message: &OutboxMessage) -> Result<(), RepositoryError> {
+        let id = message.id.unwrap_or_default();
+        let mut messages = self.messages.lock().unwrap();
+        if messages.contains_key(&id) {
+            return Err(RepositoryError::Duplicate(id.to_hex()));
+        }
+        let mut message = message.clone();
+        message.id = Some(id);
+        messages.insert(id, message);
+        Ok(())
+    }
+
+    fn claim_sync(&self, lease_until: DateTime) -> Option<OutboxMessage> {
+        let now = DateTime::now();
+        let mut messages = self.messages.lock().unwrap();
+        let message = messages
+            .values_mut()
+            .filter(|message| {
+                matches!(
+                    message.status,
+                    OutboxStatus::Pending | OutboxStatus::Sending
+                ) && message.next_attempt_at <= now
+            })
+            .min_by_key(|message| message.next_attempt_at)?;
+        message.status = OutboxStatus::Sending;
+        message.next_attempt_at = lease_until;
+        message.attempts += 1;
+        Some(message.clone())
+    }
+
+    fn update(&self, id: ObjectId, attempt: u32, apply: impl FnOnce(&mut OutboxMessage)) {
+        let mut messages = self.messages.lock().unwrap();
+        if let Some(message) = messages.get_mut(&id).filter(|message| {
+            message.status == OutboxStatus::Sending && message.attempts == attempt
+        }) {
+            apply(message);
+        }
+    }
+}
+
+impl OutboxRepository for MemoryOutboxRepository {
+    fn insert<'a>(
+        &'a self,
+        message: &'a OutboxMessage,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        let result = self.insert_sync(message);
+        Box::pin(async move { result })
+    }
+
+    fn find(
+        &self,
+        id: ObjectId,
+    ) -> LocalBoxFuture<'_, Result<Option<OutboxMessage>, RepositoryError>> {
+        let message = self.messages.lock().unwrap().get(&id).cloned();
+        Box::pin(async move { Ok(message) })
+    }
+
+    fn claim(
+        &self,
+        lease_until: DateTime,
+    ) -> LocalBoxFuture<'_, Result<Option<OutboxMessage>, RepositoryError>> {
+        let message = self.claim_sync(lease_until);
+        Box::pin(async move { Ok(message) })
+    }
+
+    fn mark_sent(
+        &self,
+        id: ObjectId,
+        attempt: u32,
+        expires_at: DateTime,
+    ) -> LocalBoxFuture<'_, Result<(), RepositoryError>> {
+        self.update(id, attempt, |message| {
+            message.status = OutboxStatus::Sent;
+            message.sent_at = Some(DateTime::now());
+            message.last_error = None;
+            message.expires_at = Some(expires_at);
+            message.raw = String::new();
+        });
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn retry<'a>(
+        &'a self,
+        id: ObjectId,
+        attempt: u32,
+        error: &'a str,
+        next_attempt_at: DateTime,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        self.update(id, attempt, |message| {
+            message.status = OutboxStatus::Pending;
+            message.last_error = Some(error.to_string());
+            message.next_attempt_at = next_attempt_at;
+        });
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn mark_dead<'a>(
+        &'a self,
+        id: ObjectId,
+        attempt: u32,
+        error: &'a str,
+        expires_at: DateTime,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        self.update(id, attempt, |message| {
+            message.status = OutboxStatus::Dead;
+            message.last_error = Some(error.to_string());
+            message.expires_at = Some(expires_at);
+        });
+        Box::pin(async move { Ok(()) })
+    }
+}
@@ -0,0 +1,142 @@
+use super::RepositoryError;
+use crate::db;
+use crate::services::password_reset::PasswordReset;
+use chrono::Utc;
+use futures::future::LocalBoxFuture;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::{Collection, Database};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::Instrument;
+
+/// Password reset tokens, keyed by the hash of the token. A token is live
+/// while it is unused and unexpired.
+pub trait PasswordResetRepository: Send + Sync {
+    fn insert<'a>(
+        &'a self,
+        reset: &'a PasswordReset,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+
+    /// Removes every unused token issued to the user.
+    fn delete_unused(&self, user_id: ObjectId) -> LocalBoxFuture<'_, Result<(), RepositoryError>>;
+
+    /// Marks the live token used and returns it as it was. A token can be
+    /// redeemed at most once, even by concurrent requests.
+    fn redeem<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<PasswordReset>, RepositoryError>>;
+}
+
+pub struct MongoPasswordResetRepository {
+    collection: Collection<PasswordReset>,
+}
+
+impl MongoPasswordResetRepository {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("password_resets"),
+        }
+    }
+}
+
+impl PasswordResetRepository for MongoPasswordResetRepository {
+    fn insert<'a>(
+        &'a self,
+        reset: &'a PasswordReset,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            self.collection
+                .insert_one(reset, None)
+                .instrument(db::span("password_resets", "insert_one"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn delete_unused(&self, user_id: ObjectId) -> LocalBoxFuture<'_, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            self.collection
+                .delete_many(doc! { "user_id": user_id, "is_used": false }, None)
+                .instrument(db::span("password_resets", "delete_many"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn redeem<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<PasswordReset>, RepositoryError>> {
+        Box::pin(async move {
+            let filter = doc! {
+                "token_hash": token_hash,
+                "is_used": false,
+                "expires_at": { "$gt": DateTime::now() },
+            };
+            let update = doc! {
+                "$set": { "is_used": true, "used_at": Utc::now().timestamp() }
+            };
+            Ok(self
+                .collection
+                .find_one_and_update(filter, update, None)
+                .instrument(db::span("password_resets", "find_one_and_update"))
+                .await?)
+        })
+    }
+}
+
+/// Reset tokens held in process memory, for tests and local runs.
+#[derive(Default)]
+pub struct MemoryPasswordResetRepository {
+    resets: Mutex<HashMap<String, PasswordReset>>,
+}
+
+impl MemoryPasswordResetRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn redeem_sync(&self, token_hash: &str) -> Option<PasswordReset> {
+        let mut resets = self.resets.lock().unwrap();
+        let reset = resets
+            .get_mut(token_hash)
+            .filter(|reset| !reset.is_used && reset.expires_at > DateTime::now())?;
+        let unused = reset.clone();
+        reset.is_used = true;
+        reset.used_at = Some(Utc::now().timestamp() as u64);
+        Some(unused)
+    }
+}
+
+impl PasswordResetRepository for MemoryPasswordResetRepository {
+    fn insert<'a>(
+        &'a self,
+        reset: &'a PasswordReset,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        let mut resets = self.resets.lock().unwrap();
+        let result = if resets.contains_key(&reset.token_hash) {
+            Err(RepositoryError::Duplicate(reset.token_hash.clone()))
+        } else {
+            resets.insert(reset.token_hash.clone(), reset.clone());
+            Ok(())
+        };
+        Box::pin(async move { result })
+    }
+
+    fn delete_unused(&self, user_id: ObjectId) -> LocalBoxFuture<'_, Result<(), RepositoryError>> {
+        self.resets
+            .lock()
+            .unwrap()
+            .retain(|_, reset| reset.user_id != user_id || reset.is_used);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn redeem<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<PasswordReset>, RepositoryError>> {
+        let reset = self.redeem_sync(token_hash);
+        Box::pin(async move { Ok(reset) })
+    }
+}
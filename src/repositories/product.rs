@@ -0,0 +1,72 @@
+use super::RepositoryError;
+use crate::db;
+use crate::models::product::Product;
+use futures::future::LocalBoxFuture;
+use mongodb::bson::oid::ObjectId;
+use mongodb::{Collection, Database};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::Instrument;
+
+pub trait ProductRepository: Send + Sync {
+    /// Stores a new product and returns its id.
+    fn insert<'a>(
+        &'a self,
+        product: &'a Product,
+    ) -> LocalBoxFuture<'a, Result<ObjectId, RepositoryError>>;
+}
+
+pub struct MongoProductRepository {
+    collection: Collection<Product>,
+}
+
+impl MongoProductRepository {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("products"),
+        }
+    }
+}
+
+impl ProductRepository for MongoProductRepository {
+    fn insert<'a>(
+        &'a self,
+        product: &'a Product,
+    ) -> LocalBoxFuture<'a, Result<ObjectId, RepositoryError>> {
+        Box::pin(async move {
+            let result = self
+                .collection
+                .insert_one(product, None)
+                .instrument(db::span("products", "insert_one"))
+                .await?;
+            result.inserted_id.as_object_id().ok_or_else(|| {
+                RepositoryError::MongoError(mongodb::error::Error::custom(
+                    "inserted id is not an ObjectId",
+                ))
+            })
+        })
+    }
+}
+
+/// Products held in process memory, for tests and local runs.
+#[derive(Default)]
+pub struct MemoryProductRepository {
+    products: Mutex<HashMap<ObjectId, Product>>,
+}
+
+impl MemoryProductRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ProductRepository for MemoryProductRepository {
+    fn insert<'a>(
+        &'a self,
+        product: &'a Product,
+    ) -> LocalBoxFuture<'a, Result<ObjectId, RepositoryError>> {
+        let id = ObjectId::new();
+        self.products.lock().unwrap().insert(id, product.clone());
+        Box::pin(async move { Ok(id) })
+    }
+}
@@ -0,0 +1,221 @@
+use super::RepositoryError;
+use crate::db;
+use crate::services::token::RefreshToken;
+use futures::future::LocalBoxFuture;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::{Collection, Database};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::Instrument;
+
+/// Refresh tokens, keyed by the hash of the token. A token is gone once its
+/// `expires_at` has passed.
+pub trait RefreshTokenRepository: Send + Sync {
+    fn insert<'a>(
+        &'a self,
+        token: &'a RefreshToken,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+
+    /// Marks the token used and returns it as it was, provided it was
+    /// unused and not revoked. A token can be used at most once, even by
+    /// concurrent requests.
+    fn use_live<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<RefreshToken>, RepositoryError>>;
+
+    /// The token whatever its state.
+    fn find<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<RefreshToken>, RepositoryError>>;
+
+    fn revoke_family<'a>(
+        &'a self,
+        family_id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+
+    fn revoke_for_user(&self, user_id: ObjectId)
+        -> LocalBoxFuture<'_, Result<(), RepositoryError>>;
+}
+
+pub struct MongoRefreshTokenRepository {
+    collection: Collection<RefreshToken>,
+}
+
+impl MongoRefreshTokenRepository {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("refresh_tokens"),
+        }
+    }
+}
+
+impl RefreshTokenRepository for MongoRefreshTokenRepository {
+    fn insert<'a>(
+        &'a self,
+        token: &'a RefreshToken,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            self.collection
+                .insert_one(token, None)
+                .instrument(db::span("refresh_tokens", "insert_one"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn use_live<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<RefreshToken>, RepositoryError>> {
+        Box::pin(async move {
+            let filter = doc! {
+                "token_hash": token_hash,
+                "is_used": false,
+                "is_revoked": false,
+                "expires_at": { "$gt": DateTime::now() },
+            };
+            let update = doc! { "$set": { "is_used": true } };
+            Ok(self
+                .collection
+                .find_one_and_update(filter, update, None)
+                .instrument(db::span("refresh_tokens", "find_one_and_update"))
+                .await?)
+        })
+    }
+
+    fn find<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<RefreshToken>, RepositoryError>> {
+        Box::pin(async move {
+            Ok(self
+                .collection
+                .find_one(doc! { "token_hash": token_hash }, None)
+                .instrument(db::span("refresh_tokens", "find_one"))
+                .await?)
+        })
+    }
+
+    fn revoke_family<'a>(
+        &'a self,
+        family_id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            self.collection
+                .update_many(
+                    doc! { "family_id": family_id },
+                    doc! { "$set": { "is_revoked": true } },
+                    None,
+                )
+                .instrument(db::span("refresh_tokens", "update_many"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn revoke_for_user(
+        &self,
+        user_id: ObjectId,
+    ) -> LocalBoxFuture<'_, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            self.collection
+                .update_many(
+                    doc! { "user_id": user_id },
+                    doc! { "$set": { "is_revoked": true } },
+                    None,
+                )
+                .instrument(db::span("refresh_tokens", "update_many"))
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// Refresh tokens held in process memory, for tests and local runs.
+#[derive(Default)]
+pub struct MemoryRefreshTokenRepository {
+    tokens: Mutex<HashMap<String, RefreshToken>>,
+}
+
+impl MemoryRefreshTokenRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn find_sync(&self, token_hash: &str) -> Option<RefreshToken> {
+        self.tokens
+            .lock()
+            .unwrap()
+            .get(token_hash)
+            .filter(|token| token.expires_at > DateTime::now())
+            .cloned()
+    }
+
+    fn use_live_sync(&self, token_hash: &str) -> Option<RefreshToken> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let token = tokens.get_mut(token_hash).filter(|token| {
+            !token.is_used && !token.is_revoked && token.expires_at > DateTime::now()
+        })?;
+        let unused = token.clone();
+        token.is_used = true;
+        Some(unused)
+    }
+
+    fn revoke(&self, matches: impl Fn(&RefreshToken) -> bool) {
+        for token in self.tokens.lock().unwrap().values_mut() {
+            if matches(token) {
+                token.is_revoked = true;
+            }
+        }
+    }
+}
+
+impl RefreshTokenRepository for MemoryRefreshTokenRepository {
+    fn insert<'a>(
+        &'a self,
+        token: &'a RefreshToken,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let result = if tokens.contains_key(&token.token_hash) {
+            Err(RepositoryError::Duplicate(token.token_hash.clone()))
+        } else {
+            tokens.insert(token.token_hash.clone(), token.clone());
+            Ok(())
+        };
+        Box::pin(async move { result })
+    }
+
+    fn use_live<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<RefreshToken>, RepositoryError>> {
+        let token = self.use_live_sync(token_hash);
+        Box::pin(async move { Ok(token) })
+    }
+
+    fn find<'a>(
+        &'a self,
+        token_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<RefreshToken>, RepositoryError>> {
+        let token = self.find_sync(token_hash);
+        Box::pin(async move { Ok(token) })
+    }
+
+    fn revoke_family<'a>(
+        &'a self,
+        family_id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        self.revoke(|token| token.family_id == family_id);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn revoke_for_user(
+        &self,
+        user_id: ObjectId,
+    ) -> LocalBoxFuture<'_, Result<(), RepositoryError>> {
+        self.revoke(|token| token.user_id == user_id);
+        Box::pin(async move { Ok(()) })
+    }
+}
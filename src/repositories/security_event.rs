@@ -0,0 +1,68 @@
+use super::RepositoryError;
+use crate::db;
+use crate::services::security_event::SecurityEvent;
+use futures::future::LocalBoxFuture;
+use mongodb::{Collection, Database};
+use std::sync::Mutex;
+use tracing::Instrument;
+
+/// The security audit trail. Events are only ever appended.
+pub trait SecurityEventRepository: Send + Sync {
+    fn insert<'a>(
+        &'a self,
+        event: &'a SecurityEvent,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+}
+
+pub struct MongoSecurityEventRepository {
+    collection: Collection<SecurityEvent>,
+}
+
+impl MongoSecurityEventRepository {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("security_events"),
+        }
+    }
+}
+
+impl SecurityEventRepository for MongoSecurityEventRepository {
+    fn insert<'a>(
+        &'a self,
+        event: &'a SecurityEvent,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            self.collection
+                .insert_one(event, None)
+                .instrument(db::span("security_events", "insert_one"))
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// Events held in process memory, for tests and local runs.
+#[derive(Default)]
+pub struct MemorySecurityEventRepository {
+    events: Mutex<Vec<SecurityEvent>>,
+}
+
+impl MemorySecurityEventRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn events(&self) -> Vec<SecurityEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl SecurityEventRepository for MemorySecurityEventRepository {
+    fn insert<'a>(
+        &'a self,
+        event: &'a SecurityEvent,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        self.events.lock().unwrap().push(event.clone());
+        Box::pin(async move { Ok(()) })
+    }
+}
@@ -0,0 +1,228 @@
+use super::RepositoryError;
+use crate::services::session::{self, MongoSessionStore};
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::cookie::time::Duration;
+use futures::future::LocalBoxFuture;
+use mongodb::bson::{oid::ObjectId, DateTime};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Server-side sessions, as far as the handlers are concerned. Loading and
+/// saving them is left to the [`SessionStore`] given to the middleware.
+pub trait SessionRepository: Send + Sync {
+    /// Destroys every session owned by `user_id` and returns how many.
+    fn delete_for_user(
+        &self,
+        user_id: ObjectId,
+    ) -> LocalBoxFuture<'_, Result<u64, RepositoryError>>;
+}
+
+impl SessionRepository for MongoSessionStore {
+    fn delete_for_user(
+        &self,
+        user_id: ObjectId,
+    ) -> LocalBoxFuture<'_, Result<u64, RepositoryError>> {
+        Box::pin(async move { Ok(MongoSessionStore::delete_for_user(self, user_id).await?) })
+    }
+}
+
+struct MemorySession {
+    user_id: Option<ObjectId>,
+    state: HashMap<String, String>,
+    expires_at: DateTime,
+}
+
+/// Sessions held in process memory, keyed by the digest of the session key
+/// like [`MongoSessionStore`]. Clones share the same sessions, so one can be
+/// given to the middleware and another to [`super::Repositories`].
+#[derive(Clone, Default)]
+pub struct MemorySessionStore {
+    sessions: Arc<Mutex<HashMap<String, MemorySession>>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&self, session_key: &str, state: HashMap<String, String>, ttl: &Duration) {
+        let record = MemorySession {
+            user_id: session::user_id(&state),
+            state,
+            expires_at: session::expires_at(ttl),
+        };
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session::hash_key(session_key), record);
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(sessions
+            .get(&session::hash_key(session_key.as_ref()))
+            .filter(|record| record.expires_at > DateTime::now())
+            .map(|record| record.state.clone()))
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let session_key = session::generate_key();
+        self.insert(&session_key, session_state, ttl);
+        SessionKey::try_from(session_key).map_err(|err| SaveError::Other(err.into()))
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let exists = self
+            .sessions
+            .lock()
+            .unwrap()
+            .contains_key(&session::hash_key(session_key.as_ref()));
+        if !exists {
+            // Revoked between load and update; see MongoSessionStore::update.
+            return self
+                .save(session_state, ttl)
+                .await
+                .map_err(|err| UpdateError::Other(err.into()));
+        }
+        self.insert(session_key.as_ref(), session_state, ttl);
+        Ok(session_key)
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &Duration,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(record) = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut(&session::hash_key(session_key.as_ref()))
+        {
+            record.expires_at = session::expires_at(ttl);
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .remove(&session::hash_key(session_key.as_ref()));
+        Ok(())
+    }
+}
+
+impl SessionRepository for MemorySessionStore {
+    fn delete_for_user(
+        &self,
+        user_id: ObjectId,
+    ) -> LocalBoxFuture<'_, Result<u64, RepositoryError>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, record| record.user_id != Some(user_id));
+        let deleted = (before - sessions.len()) as u64;
+        Box::pin(async move { Ok(deleted) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::session::SESSION_TTL_HOURS;
+
+    fn state(user_id: Option<ObjectId>) -> HashMap<String, String> {
+        let mut state = HashMap::new();
+        state.insert("theme".to_string(), "\"dark\"".to_string());
+        if let Some(user_id) = user_id {
+            state.insert(
+                session::SESSION_USER_KEY.to_string(),
+                format!("\"{}\"", user_id.to_hex()),
+            );
+        }
+        state
+    }
+
+    #[actix_web::test]
+    async fn test_memory_store_saves_loads_updates_and_deletes() {
+        let store = MemorySessionStore::new();
+        let ttl = Duration::hours(SESSION_TTL_HOURS);
+
+        let key = store.save(state(None), &ttl).await.unwrap();
+        assert_eq!(store.load(&key).await.unwrap(), Some(state(None)));
+        // Only the digest of the key is kept.
+        assert!(!store.sessions.lock().unwrap().contains_key(key.as_ref()));
+
+        let user_id = ObjectId::new();
+        let key = store.update(key, state(Some(user_id)), &ttl).await.unwrap();
+        assert_eq!(store.load(&key).await.unwrap(), Some(state(Some(user_id))));
+
+        store.delete(&key).await.unwrap();
+        assert_eq!(store.load(&key).await.unwrap(), None);
+
+        // A session deleted under a live request comes back with a new key.
+        let renewed = store
+            .update(
+                SessionKey::try_from(key.as_ref().to_string()).unwrap(),
+                state(None),
+                &ttl,
+            )
+            .await
+            .unwrap();
+        assert_ne!(renewed, key);
+        assert_eq!(store.load(&key).await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_memory_store_expires_sessions() {
+        let store = MemorySessionStore::new();
+
+        let live = store
+            .save(state(None), &Duration::hours(SESSION_TTL_HOURS))
+            .await
+            .unwrap();
+        let expired = store
+            .save(state(None), &Duration::seconds(-1))
+            .await
+            .unwrap();
+        assert!(store.load(&live).await.unwrap().is_some());
+        assert_eq!(store.load(&expired).await.unwrap(), None);
+
+        // Extending the TTL keeps the session loadable past its old expiry.
+        store
+            .update_ttl(&expired, &Duration::hours(SESSION_TTL_HOURS))
+            .await
+            .unwrap();
+        assert!(store.load(&expired).await.unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_memory_store_deletes_every_session_of_a_user() {
+        let store = MemorySessionStore::new();
+        let ttl = Duration::hours(SESSION_TTL_HOURS);
+        let (user_id, other_id) = (ObjectId::new(), ObjectId::new());
+
+        let first = store.save(state(Some(user_id)), &ttl).await.unwrap();
+        let second = store.save(state(Some(user_id)), &ttl).await.unwrap();
+        let other = store.save(state(Some(other_id)), &ttl).await.unwrap();
+
+        assert_eq!(store.delete_for_user(user_id).await.unwrap(), 2);
+        assert_eq!(store.load(&first).await.unwrap(), None);
+        assert_eq!(store.load(&second).await.unwrap(), None);
+        assert!(store.load(&other).await.unwrap().is_some());
+    }
+}
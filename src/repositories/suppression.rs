@@ -0,0 +1,135 @@
+use super::RepositoryError;
+use crate::db;
+use crate::services::bounce::{BounceEvent, Suppression};
+use futures::future::LocalBoxFuture;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::UpdateOptions;
+use mongodb::{Collection, Database};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::Instrument;
+
+/// Suppressed addresses, unique by email. Emails are stored bare and
+/// lowercased and looked up as given.
+pub trait SuppressionRepository: Send + Sync {
+    fn find<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<Suppression>, RepositoryError>>;
+
+    /// Suppresses the event's address, or counts the event against an
+    /// existing suppression. `source` is the parser that reported it.
+    fn record<'a>(
+        &'a self,
+        event: &'a BounceEvent,
+        source: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+}
+
+pub struct MongoSuppressionRepository {
+    collection: Collection<Suppression>,
+}
+
+impl MongoSuppressionRepository {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("email_suppressions"),
+        }
+    }
+}
+
+impl SuppressionRepository for MongoSuppressionRepository {
+    fn find<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<Suppression>, RepositoryError>> {
+        Box::pin(async move {
+            Ok(self
+                .collection
+                .find_one(doc! { "email": email }, None)
+                .instrument(db::span("email_suppressions", "find_one"))
+                .await?)
+        })
+    }
+
+    fn record<'a>(
+        &'a self,
+        event: &'a BounceEvent,
+        source: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            let update = doc! {
+                "$set": {
+                    "kind": event.kind.as_str(),
+                    "reason": event.reason.as_deref(),
+                    "source": source,
+                    "updated_at": DateTime::now(),
+                },
+                "$inc": { "events": 1 },
+                "$setOnInsert": { "created_at": DateTime::now() },
+            };
+            self.collection
+                .update_one(
+                    doc! { "email": &event.email },
+                    update,
+                    UpdateOptions::builder().upsert(true).build(),
+                )
+                .instrument(db::span("email_suppressions", "update_one"))
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// Suppressions held in process memory, for tests and local runs.
+#[derive(Default)]
+pub struct MemorySuppressionRepository {
+    suppressions: Mutex<HashMap<String, Suppression>>,
+}
+
+impl MemorySuppressionRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record_sync(&self, event: &BounceEvent, source: &str) {
+        let now = DateTime::now();
+        let mut suppressions = self.suppressions.lock().unwrap();
+        let suppression = suppressions
+            .entry(event.email.clone())
+            .or_insert_with(|| Suppression {
+                id: None,
+                email: event.email.clone(),
+                kind: event.kind,
+                reason: None,
+                source: String::new(),
+                events: 0,
+                created_at: now,
+                updated_at: now,
+            });
+        suppression.kind = event.kind;
+        suppression.reason = event.reason.clone();
+        suppression.source = source.to_string();
+        suppression.events += 1;
+        suppression.updated_at = now;
+    }
+}
+
+impl SuppressionRepository for MemorySuppressionRepository {
+    fn find<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<Suppression>, RepositoryError>> {
+        let suppression = self.suppressions.lock().unwrap().get(email).cloned();
+        Box::pin(async move { Ok(suppression) })
+    }
+
+    fn record<'a>(
+        &'a self,
+        event: &'a BounceEvent,
+        source: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        self.record_sync(event, source);
+        Box::pin(async move { Ok(()) })
+    }
+}
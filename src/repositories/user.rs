@@ -0,0 +1,755 @@
+use super::RepositoryError;
+use crate::db::{self, is_duplicate_key};
+use crate::models::user::{Passkey, User};
+use chrono::Utc;
+use futures::future::LocalBoxFuture;
+use mongodb::bson::{doc, oid::ObjectId};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use mongodb::{Collection, Database};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::Instrument;
+
+/// Accounts, unique by email. Emails are stored trimmed and lowercased and
+/// looked up as given.
+pub trait UserRepository: Send + Sync {
+    fn find_by_id(&self, id: ObjectId)
+        -> LocalBoxFuture<'_, Result<Option<User>, RepositoryError>>;
+
+    fn find_by_email<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<User>, RepositoryError>>;
+
+    /// The user holding the passkey with `credential_id`.
+    fn find_by_passkey<'a>(
+        &'a self,
+        credential_id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<User>, RepositoryError>>;
+
+    /// Stores a new user and returns its id. Fails with
+    /// [`RepositoryError::Duplicate`] when the email is taken.
+    fn insert<'a>(
+        &'a self,
+        user: &'a User,
+    ) -> LocalBoxFuture<'a, Result<ObjectId, RepositoryError>>;
+
+    /// Marks the account registered to `email` as verified.
+    fn mark_verified<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+
+    /// Replaces the password hash and returns the updated user.
+    fn set_password<'a>(
+        &'a self,
+        id: ObjectId,
+        password_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<User>, RepositoryError>>;
+
+    /// Flags the account registered to `email` as undeliverable.
+    fn mark_undeliverable<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+
+    /// Records `step` as the last accepted TOTP step. Returns false when the
+    /// same or a later step was already recorded, so a code is accepted at
+    /// most once even by concurrent requests.
+    fn record_totp_step(
+        &self,
+        id: ObjectId,
+        step: u64,
+    ) -> LocalBoxFuture<'_, Result<bool, RepositoryError>>;
+
+    /// Stores a TOTP secret awaiting confirmation. The enabled secret, if
+    /// any, stays in use until then.
+    fn set_totp_pending_secret<'a>(
+        &'a self,
+        id: ObjectId,
+        secret: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+
+    /// Promotes the pending secret to the enabled one, with `step` as the last
+    /// accepted step and `recovery_codes` when given. Returns false when the
+    /// pending secret is no longer `pending`.
+    fn enable_totp<'a>(
+        &'a self,
+        id: ObjectId,
+        pending: &'a str,
+        step: u64,
+        recovery_codes: Option<&'a [String]>,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>>;
+
+    /// Removes the TOTP secrets, and the recovery codes unless
+    /// `keep_recovery_codes`.
+    fn disable_totp(
+        &self,
+        id: ObjectId,
+        keep_recovery_codes: bool,
+    ) -> LocalBoxFuture<'_, Result<(), RepositoryError>>;
+
+    /// Replaces the recovery code hashes, invalidating any previous set.
+    fn set_recovery_codes<'a>(
+        &'a self,
+        id: ObjectId,
+        hashes: &'a [String],
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+
+    /// Removes a recovery code hash. Returns false when the user does not
+    /// hold it, so a code can only ever be redeemed once.
+    fn redeem_recovery_code<'a>(
+        &'a self,
+        id: ObjectId,
+        hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>>;
+
+    /// Appends a passkey, storing `recovery_codes` with it when given.
+    /// Returns false when there is no such user, and fails with
+    /// [`RepositoryError::Duplicate`] when any user holds the credential.
+    fn add_passkey<'a>(
+        &'a self,
+        id: ObjectId,
+        passkey: &'a Passkey,
+        recovery_codes: Option<&'a [String]>,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>>;
+
+    /// Stores a passkey's new signature counter. Only matches while the
+    /// stored counter is still `passkey.sign_count`, so a concurrent replay
+    /// of the same assertion returns false.
+    fn record_passkey_use<'a>(
+        &'a self,
+        id: ObjectId,
+        passkey: &'a Passkey,
+        sign_count: u32,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>>;
+}
+
+pub struct MongoUserRepository {
+    collection: Collection<User>,
+}
+
+impl MongoUserRepository {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("users"),
+        }
+    }
+}
+
+impl UserRepository for MongoUserRepository {
+    fn find_by_id(
+        &self,
+        id: ObjectId,
+    ) -> LocalBoxFuture<'_, Result<Option<User>, RepositoryError>> {
+        Box::pin(async move {
+            Ok(self
+                .collection
+                .find_one(doc! { "_id": id }, None)
+                .instrument(db::span("users", "find_one"))
+                .await?)
+        })
+    }
+
+    fn find_by_email<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<User>, RepositoryError>> {
+        Box::pin(async move {
+            Ok(self
+                .collection
+                .find_one(doc! { "email": email }, None)
+                .instrument(db::span("users", "find_one"))
+                .await?)
+        })
+    }
+
+    fn find_by_passkey<'a>(
+        &'a self,
+        credential_id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<User>, RepositoryError>> {
+        Box::pin(async move {
+            Ok(self
+                .collection
+                .find_one(doc! { "passkeys.credential_id": credential_id }, None)
+                .instrument(db::span("users", "find_one"))
+                .await?)
+        })
+    }
+
+    fn insert<'a>(
+        &'a self,
+        user: &'a User,
+    ) -> LocalBoxFuture<'a, Result<ObjectId, RepositoryError>> {
+        Box::pin(async move {
+            match self
+                .collection
+                .insert_one(user, None)
+                .instrument(db::span("users", "insert_one"))
+                .await
+            {
+                Ok(result) => result.inserted_id.as_object_id().ok_or_else(|| {
+                    RepositoryError::MongoError(mongodb::error::Error::custom(
+                        "inserted id is not an ObjectId",
+                    ))
+                }),
+                Err(err) if is_duplicate_key(&err) => {
+                    Err(RepositoryError::Duplicate(user.email.clone()))
+                }
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+
+    fn mark_verified<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            let update = doc! { "$set": { "is_verified": true } };
+            self.collection
+                .update_one(doc! { "email": email }, update, None)
+                .instrument(db::span("users", "update_one"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn set_password<'a>(
+        &'a self,
+        id: ObjectId,
+        password_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<User>, RepositoryError>> {
+        Box::pin(async move {
+            let update = doc! {
+                "$set": { "password": password_hash, "updated_at": Utc::now().timestamp() }
+            };
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build();
+            Ok(self
+                .collection
+                .find_one_and_update(doc! { "_id": id }, update, options)
+                .instrument(db::span("users", "find_one_and_update"))
+                .await?)
+        })
+    }
+
+    fn mark_undeliverable<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            let update = doc! {
+                "$set": { "email_deliverable": false, "updated_at": Utc::now().timestamp() }
+            };
+            self.collection
+                .update_one(doc! { "email": email }, update, None)
+                .instrument(db::span("users", "update_one"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn record_totp_step(
+        &self,
+        id: ObjectId,
+        step: u64,
+    ) -> LocalBoxFuture<'_, Result<bool, RepositoryError>> {
+        Box::pin(async move {
+            let filter = doc! {
+                "_id": id,
+                "$or": [
+                    { "totp_last_step": null },
+                    { "totp_last_step": { "$lt": step as i64 } },
+                ],
+            };
+            let update = doc! { "$set": { "totp_last_step": step as i64 } };
+            let result = self
+                .collection
+                .update_one(filter, update, None)
+                .instrument(db::span("users", "update_one"))
+                .await?;
+            Ok(result.modified_count == 1)
+        })
+    }
+
+    fn set_totp_pending_secret<'a>(
+        &'a self,
+        id: ObjectId,
+        secret: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            let update = doc! {
+                "$set": {
+                    "totp_pending_secret": secret,
+                    "updated_at": Utc::now().timestamp(),
+                }
+            };
+            self.collection
+                .update_one(doc! { "_id": id }, update, None)
+                .instrument(db::span("users", "update_one"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn enable_totp<'a>(
+        &'a self,
+        id: ObjectId,
+        pending: &'a str,
+        step: u64,
+        recovery_codes: Option<&'a [String]>,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>> {
+        Box::pin(async move {
+            let mut set = doc! {
+                "totp_secret": pending,
+                "totp_enabled": true,
+                "totp_last_step": step as i64,
+                "updated_at": Utc::now().timestamp(),
+            };
+            if let Some(hashes) = recovery_codes {
+                set.insert("recovery_codes", hashes);
+            }
+            let filter = doc! { "_id": id, "totp_pending_secret": pending };
+            let update = doc! {
+                "$set": set,
+                "$unset": { "totp_pending_secret": "" },
+            };
+            let result = self
+                .collection
+                .update_one(filter, update, None)
+                .instrument(db::span("users", "update_one"))
+                .await?;
+            Ok(result.modified_count == 1)
+        })
+    }
+
+    fn disable_totp(
+        &self,
+        id: ObjectId,
+        keep_recovery_codes: bool,
+    ) -> LocalBoxFuture<'_, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            let mut unset = doc! {
+                "totp_secret": "",
+                "totp_pending_secret": "",
+                "totp_last_step": "",
+            };
+            if !keep_recovery_codes {
+                unset.insert("recovery_codes", "");
+            }
+            let update = doc! {
+                "$set": { "totp_enabled": false, "updated_at": Utc::now().timestamp() },
+                "$unset": unset,
+            };
+            self.collection
+                .update_one(doc! { "_id": id }, update, None)
+                .instrument(db::span("users", "update_one"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn set_recovery_codes<'a>(
+        &'a self,
+        id: ObjectId,
+        hashes: &'a [String],
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            let update = doc! {
+                "$set": {
+                    "recovery_codes": hashes,
+                    "updated_at": Utc::now().timestamp(),
+                }
+            };
+            self.collection
+                .update_one(doc! { "_id": id }, update, None)
+                .instrument(db::span("users", "update_one"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn redeem_recovery_code<'a>(
+        &'a self,
+        id: ObjectId,
+        hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>> {
+        Box::pin(async move {
+            let filter = doc! { "_id": id, "recovery_codes": hash };
+            let update = doc! { "$pull": { "recovery_codes": hash } };
+            let result = self
+                .collection
+                .update_one(filter, update, None)
+                .instrument(db::span("users", "update_one"))
+                .await?;
+            Ok(result.modified_count == 1)
+        })
+    }
+
+    fn add_passkey<'a>(
+        &'a self,
+        id: ObjectId,
+        passkey: &'a Passkey,
+        recovery_codes: Option<&'a [String]>,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>> {
+        Box::pin(async move {
+            let passkey_doc = mongodb::bson::to_bson(passkey)
+                .map_err(|err| RepositoryError::MongoError(err.into()))?;
+            let mut set = doc! { "updated_at": Utc::now().timestamp() };
+            if let Some(hashes) = recovery_codes {
+                set.insert("recovery_codes", hashes);
+            }
+            let update = doc! { "$push": { "passkeys": passkey_doc }, "$set": set };
+            match self
+                .collection
+                .update_one(doc! { "_id": id }, update, None)
+                .instrument(db::span("users", "update_one"))
+                .await
+            {
+                Ok(result) => Ok(result.modified_count == 1),
+                Err(err) if is_duplicate_key(&err) => {
+                    Err(RepositoryError::Duplicate(passkey.credential_id.clone()))
+                }
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+
+    fn record_passkey_use<'a>(
+        &'a self,
+        id: ObjectId,
+        passkey: &'a Passkey,
+        sign_count: u32,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>> {
+        Box::pin(async move {
+            let filter = doc! {
+                "_id": id,
+                "passkeys": {
+                    "$elemMatch": {
+                        "credential_id": &passkey.credential_id,
+                        "sign_count": passkey.sign_count as i64,
+                    }
+                },
+            };
+            let update = doc! {
+                "$set": {
+                    "passkeys.$.sign_count": sign_count as i64,
+                    "passkeys.$.last_used_at": Utc::now().timestamp(),
+                }
+            };
+            let result = self
+                .collection
+                .update_one(filter, update, None)
+                .instrument(db::span("users", "update_one"))
+                .await?;
+            Ok(result.modified_count == 1)
+        })
+    }
+}
+
+/// Users held in process memory, for tests and local runs.
+#[derive(Default)]
+pub struct MemoryUserRepository {
+    users: Mutex<HashMap<ObjectId, User>>,
+}
+
+impl MemoryUserRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn find(&self, predicate: impl Fn(&User) -> bool) -> Option<User> {
+        self.users
+            .lock()
+            .unwrap()
+            .values()
+            .find(|user| predicate(user))
+            .cloned()
+    }
+
+    fn insert_sync(&self, user: &User) -> Result<ObjectId, RepositoryError> {
+        let mut users = self.users.lock().unwrap();
+        if users.values().any(|existing| existing.email == user.email) {
+            return Err(RepositoryError::Duplicate(user.email.clone()));
+        }
+        let id = user.id.unwrap_or_default();
+        let mut user = user.clone();
+        user.id = Some(id);
+        users.insert(id, user);
+        Ok(id)
+    }
+
+    fn update(&self, id: ObjectId, apply: impl FnOnce(&mut User)) -> Option<User> {
+        let mut users = self.users.lock().unwrap();
+        let user = users.get_mut(&id)?;
+        apply(user);
+        Some(user.clone())
+    }
+
+    /// Applies `apply` when the user matches `filter`, like a conditional
+    /// `update_one`, and returns whether it did.
+    fn update_if(
+        &self,
+        id: ObjectId,
+        filter: impl FnOnce(&User) -> bool,
+        apply: impl FnOnce(&mut User),
+    ) -> bool {
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(&id) {
+            Some(user) if filter(user) => {
+                apply(user);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn add_passkey_sync(
+        &self,
+        id: ObjectId,
+        passkey: &Passkey,
+        recovery_codes: Option<&[String]>,
+    ) -> Result<bool, RepositoryError> {
+        let mut users = self.users.lock().unwrap();
+        if users.values().any(|user| {
+            user.passkeys
+                .iter()
+                .flatten()
+                .any(|existing| existing.credential_id == passkey.credential_id)
+        }) {
+            return Err(RepositoryError::Duplicate(passkey.credential_id.clone()));
+        }
+        let user = match users.get_mut(&id) {
+            Some(user) => user,
+            None => return Ok(false),
+        };
+        user.passkeys
+            .get_or_insert_with(Vec::new)
+            .push(passkey.clone());
+        if let Some(hashes) = recovery_codes {
+            user.recovery_codes = Some(hashes.to_vec());
+        }
+        user.updated_at = Some(Utc::now().timestamp() as u64);
+        Ok(true)
+    }
+}
+
+impl UserRepository for MemoryUserRepository {
+    fn find_by_id(
+        &self,
+        id: ObjectId,
+    ) -> LocalBoxFuture<'_, Result<Option<User>, RepositoryError>> {
+        let user = self.users.lock().unwrap().get(&id).cloned();
+        Box::pin(async move { Ok(user) })
+    }
+
+    fn find_by_email<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<User>, RepositoryError>> {
+        let user = self.find(|user| user.email == email);
+        Box::pin(async move { Ok(user) })
+    }
+
+    fn find_by_passkey<'a>(
+        &'a self,
+        credential_id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<User>, RepositoryError>> {
+        let user = self.find(|user| {
+            user.passkeys
+                .iter()
+                .flatten()
+                .any(|passkey| passkey.credential_id == credential_id)
+        });
+        Box::pin(async move { Ok(user) })
+    }
+
+    fn insert<'a>(
+        &'a self,
+        user: &'a User,
+    ) -> LocalBoxFuture<'a, Result<ObjectId, RepositoryError>> {
+        let result = self.insert_sync(user);
+        Box::pin(async move { result })
+    }
+
+    fn mark_verified<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        if let Some(user) = self
+            .users
+            .lock()
+            .unwrap()
+            .values_mut()
+            .find(|user| user.email == email)
+        {
+            user.is_verified = Some(true);
+        }
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn set_password<'a>(
+        &'a self,
+        id: ObjectId,
+        password_hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<User>, RepositoryError>> {
+        let user = self.update(id, |user| {
+            user.password = password_hash.to_string();
+            user.updated_at = Some(Utc::now().timestamp() as u64);
+        });
+        Box::pin(async move { Ok(user) })
+    }
+
+    fn mark_undeliverable<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        if let Some(user) = self
+            .users
+            .lock()
+            .unwrap()
+            .values_mut()
+            .find(|user| user.email == email)
+        {
+            user.email_deliverable = Some(false);
+            user.updated_at = Some(Utc::now().timestamp() as u64);
+        }
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn record_totp_step(
+        &self,
+        id: ObjectId,
+        step: u64,
+    ) -> LocalBoxFuture<'_, Result<bool, RepositoryError>> {
+        let recorded = self.update_if(
+            id,
+            |user| user.totp_last_step.is_none_or(|last| last < step),
+            |user| {
+                user.totp_last_step = Some(step);
+            },
+        );
+        Box::pin(async move { Ok(recorded) })
+    }
+
+    fn set_totp_pending_secret<'a>(
+        &'a self,
+        id: ObjectId,
+        secret: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        self.update(id, |user| {
+            user.totp_pending_secret = Some(secret.to_string());
+            user.updated_at = Some(Utc::now().timestamp() as u64);
+        });
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn enable_totp<'a>(
+        &'a self,
+        id: ObjectId,
+        pending: &'a str,
+        step: u64,
+        recovery_codes: Option<&'a [String]>,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>> {
+        let enabled = self.update_if(
+            id,
+            |user| user.totp_pending_secret.as_deref() == Some(pending),
+            |user| {
+                user.totp_secret = user.totp_pending_secret.take();
+                user.totp_enabled = Some(true);
+                user.totp_last_step = Some(step);
+                if let Some(hashes) = recovery_codes {
+                    user.recovery_codes = Some(hashes.to_vec());
+                }
+                user.updated_at = Some(Utc::now().timestamp() as u64);
+            },
+        );
+        Box::pin(async move { Ok(enabled) })
+    }
+
+    fn disable_totp(
+        &self,
+        id: ObjectId,
+        keep_recovery_codes: bool,
+    ) -> LocalBoxFuture<'_, Result<(), RepositoryError>> {
+        self.update(id, |user| {
+            user.totp_enabled = Some(false);
+            user.totp_secret = None;
+            user.totp_pending_secret = None;
+            user.totp_last_step = None;
+            if !keep_recovery_codes {
+                user.recovery_codes = None;
+            }
+            user.updated_at = Some(Utc::now().timestamp() as u64);
+        });
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn set_recovery_codes<'a>(
+        &'a self,
+        id: ObjectId,
+        hashes: &'a [String],
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        self.update(id, |user| {
+            user.recovery_codes = Some(hashes.to_vec());
+            user.updated_at = Some(Utc::now().timestamp() as u64);
+        });
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn redeem_recovery_code<'a>(
+        &'a self,
+        id: ObjectId,
+        hash: &'a str,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>> {
+        let redeemed = self.update_if(
+            id,
+            |user| {
+                user.recovery_codes
+                    .iter()
+                    .flatten()
+                    .any(|code| code == hash)
+            },
+            |user| {
+                if let Some(codes) = &mut user.recovery_codes {
+                    codes.retain(|code| code != hash);
+                }
+            },
+        );
+        Box::pin(async move { Ok(redeemed) })
+    }
+
+    fn add_passkey<'a>(
+        &'a self,
+        id: ObjectId,
+        passkey: &'a Passkey,
+        recovery_codes: Option<&'a [String]>,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>> {
+        let result = self.add_passkey_sync(id, passkey, recovery_codes);
+        Box::pin(async move { result })
+    }
+
+    fn record_passkey_use<'a>(
+        &'a self,
+        id: ObjectId,
+        passkey: &'a Passkey,
+        sign_count: u32,
+    ) -> LocalBoxFuture<'a, Result<bool, RepositoryError>> {
+        let matches = |stored: &Passkey| {
+            stored.credential_id == passkey.credential_id && stored.sign_count == passkey.sign_count
+        };
+        let recorded = self.update_if(
+            id,
+            |user| user.passkeys.iter().flatten().any(matches),
+            |user| {
+                if let Some(stored) = user.passkeys.iter_mut().flatten().find(|p| matches(p)) {
+                    stored.sign_count = sign_count;
+                    stored.last_used_at = Some(Utc::now().timestamp() as u64);
+                }
+            },
+        );
+        Box::pin(async move { Ok(recorded) })
+    }
+}
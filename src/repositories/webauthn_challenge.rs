@@ -0,0 +1,115 @@
+use super::RepositoryError;
+use crate::db;
+use crate::services::webauthn::{Ceremony, WebauthnChallenge};
+use futures::future::LocalBoxFuture;
+use mongodb::bson::{doc, DateTime};
+use mongodb::{Collection, Database};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::Instrument;
+
+/// Outstanding WebAuthn ceremonies, keyed by the hash of their challenge.
+pub trait WebauthnChallengeRepository: Send + Sync {
+    fn insert<'a>(
+        &'a self,
+        challenge: &'a WebauthnChallenge,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>>;
+
+    /// Removes and returns the unexpired challenge for `ceremony`.
+    fn take<'a>(
+        &'a self,
+        challenge_hash: &'a str,
+        ceremony: Ceremony,
+    ) -> LocalBoxFuture<'a, Result<Option<WebauthnChallenge>, RepositoryError>>;
+}
+
+pub struct MongoWebauthnChallengeRepository {
+    collection: Collection<WebauthnChallenge>,
+}
+
+impl MongoWebauthnChallengeRepository {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("webauthn_challenges"),
+        }
+    }
+}
+
+impl WebauthnChallengeRepository for MongoWebauthnChallengeRepository {
+    fn insert<'a>(
+        &'a self,
+        challenge: &'a WebauthnChallenge,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        Box::pin(async move {
+            self.collection
+                .insert_one(challenge, None)
+                .instrument(db::span("webauthn_challenges", "insert_one"))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn take<'a>(
+        &'a self,
+        challenge_hash: &'a str,
+        ceremony: Ceremony,
+    ) -> LocalBoxFuture<'a, Result<Option<WebauthnChallenge>, RepositoryError>> {
+        Box::pin(async move {
+            let filter = doc! {
+                "challenge_hash": challenge_hash,
+                "ceremony": ceremony.as_str(),
+                "expires_at": { "$gt": DateTime::now() },
+            };
+            Ok(self
+                .collection
+                .find_one_and_delete(filter, None)
+                .instrument(db::span("webauthn_challenges", "find_one_and_delete"))
+                .await?)
+        })
+    }
+}
+
+/// Ceremonies held in process memory, for tests and local runs.
+#[derive(Default)]
+pub struct MemoryWebauthnChallengeRepository {
+    challenges: Mutex<HashMap<String, WebauthnChallenge>>,
+}
+
+impl MemoryWebauthnChallengeRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn take_sync(&self, challenge_hash: &str, ceremony: Ceremony) -> Option<WebauthnChallenge> {
+        let mut challenges = self.challenges.lock().unwrap();
+        let live = challenges.get(challenge_hash).is_some_and(|challenge| {
+            challenge.ceremony == ceremony && challenge.expires_at > DateTime::now()
+        });
+        live.then(|| challenges.remove(challenge_hash)).flatten()
+    }
+}
+
+impl WebauthnChallengeRepository for MemoryWebauthnChallengeRepository {
+    fn insert<'a>(
+        &'a self,
+        challenge: &'a WebauthnChallenge,
+    ) -> LocalBoxFuture<'a, Result<(), RepositoryError>> {
+        let mut challenges = self.challenges.lock().unwrap();
+        let result = if challenges.contains_key(&challenge.challenge_hash) {
+            Err(RepositoryError::Duplicate(challenge.challenge_hash.clone()))
+        } else {
+            challenges.insert(challenge.challenge_hash.clone(), challenge.clone());
+            Ok(())
+        };
+        Box::pin(async move { result })
+    }
+
+    fn take<'a>(
+        &'a self,
+        challenge_hash: &'a str,
+        ceremony: Ceremony,
+    ) -> LocalBoxFuture<'a, Result<Option<WebauthnChallenge>, RepositoryError>> {
+        let challenge = self.take_sync(challenge_hash, ceremony);
+        Box::pin(async move { Ok(challenge) })
+    }
+}
@@ -0,0 +1,483 @@
+//! Hard bounces and spam complaints reported back by the mail provider. Each
+//! affected address goes on a suppression list that `MailService::send`
+//! checks before queueing anything, and the owning user is flagged as
+//! undeliverable.
+
+use crate::repositories::{suppression::SuppressionRepository, RepositoryError};
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum BounceError {
+    ParseError(String),
+}
+
+impl Display for BounceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            BounceError::ParseError(e) => write!(f, "ParseError: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BounceError {}
+
+impl From<serde_json::Error> for BounceError {
+    fn from(err: serde_json::Error) -> Self {
+        BounceError::ParseError(err.to_string())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BounceKind {
+    HardBounce,
+    Complaint,
+}
+
+impl BounceKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BounceKind::HardBounce => "hard_bounce",
+            BounceKind::Complaint => "complaint",
+        }
+    }
+}
+
+/// An address the provider says should no longer be mailed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BounceEvent {
+    pub email: String,
+    pub kind: BounceKind,
+    /// The diagnostic or feedback type given by the provider.
+    pub reason: Option<String>,
+}
+
+impl BounceEvent {
+    fn new(email: &str, kind: BounceKind, reason: Option<&str>) -> Self {
+        Self {
+            email: address(email),
+            kind,
+            reason: reason.map(str::to_string),
+        }
+    }
+}
+
+/// The bare, lowercased address from `Name <user@example.com>` or
+/// `<user@example.com>`.
+fn address(value: &str) -> String {
+    let value = match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        _ => value,
+    };
+    value.trim().to_lowercase()
+}
+
+/// Turns a provider's webhook payload into bounce events. Soft bounces,
+/// deliveries and other notifications parse to no events.
+pub trait BounceParser: Send + Sync {
+    /// The `{provider}` segment of the webhook path.
+    fn name(&self) -> &'static str;
+
+    fn parse(&self, body: &[u8]) -> Result<Vec<BounceEvent>, BounceError>;
+}
+
+fn missing(field: &str) -> BounceError {
+    BounceError::ParseError(format!("missing {}", field))
+}
+
+/// Amazon SES notifications, delivered through SNS or posted directly.
+pub struct SesParser;
+
+impl BounceParser for SesParser {
+    fn name(&self) -> &'static str {
+        "ses"
+    }
+
+    fn parse(&self, body: &[u8]) -> Result<Vec<BounceEvent>, BounceError> {
+        let envelope: Value = serde_json::from_slice(body)?;
+        let notification: Value = match envelope["Type"].as_str() {
+            Some("Notification") => serde_json::from_str(
+                envelope["Message"]
+                    .as_str()
+                    .ok_or_else(|| missing("Message"))?,
+            )?,
+            Some("SubscriptionConfirmation") => {
+                tracing::warn!(
+                    subscribe_url = %envelope["SubscribeURL"],
+                    "SNS subscription for the email webhook, confirm it at subscribe_url"
+                );
+                return Ok(Vec::new());
+            }
+            Some(_) => return Ok(Vec::new()),
+            None => envelope,
+        };
+
+        let notification_type = notification["notificationType"]
+            .as_str()
+            .or(notification["eventType"].as_str());
+        let (kind, recipients, reason) = match notification_type {
+            Some("Bounce") if notification["bounce"]["bounceType"] == "Permanent" => (
+                BounceKind::HardBounce,
+                &notification["bounce"]["bouncedRecipients"],
+                None,
+            ),
+            Some("Complaint") => (
+                BounceKind::Complaint,
+                &notification["complaint"]["complainedRecipients"],
+                notification["complaint"]["complaintFeedbackType"].as_str(),
+            ),
+            _ => return Ok(Vec::new()),
+        };
+
+        recipients
+            .as_array()
+            .ok_or_else(|| missing("recipients"))?
+            .iter()
+            .map(|recipient| {
+                let email = recipient["emailAddress"]
+                    .as_str()
+                    .ok_or_else(|| missing("emailAddress"))?;
+                let reason = recipient["diagnosticCode"].as_str().or(reason);
+                Ok(BounceEvent::new(email, kind, reason))
+            })
+            .collect()
+    }
+}
+
+/// SendGrid event webhook batches.
+pub struct SendGridParser;
+
+impl BounceParser for SendGridParser {
+    fn name(&self) -> &'static str {
+        "sendgrid"
+    }
+
+    fn parse(&self, body: &[u8]) -> Result<Vec<BounceEvent>, BounceError> {
+        let events: Vec<Value> = serde_json::from_slice(body)?;
+        let mut bounces = Vec::new();
+        for event in &events {
+            let kind = match event["event"].as_str() {
+                // `blocked` bounces are temporary refusals by the receiver.
+                Some("bounce") if event["type"] != "blocked" => BounceKind::HardBounce,
+                Some("spamreport") => BounceKind::Complaint,
+                _ => continue,
+            };
+            let email = event["email"].as_str().ok_or_else(|| missing("email"))?;
+            bounces.push(BounceEvent::new(email, kind, event["reason"].as_str()));
+        }
+        Ok(bounces)
+    }
+}
+
+/// Raw bounce messages, as forwarded by an inbound mail route: RFC 3464
+/// delivery status notifications and RFC 5965 abuse reports (ARF).
+pub struct DsnParser;
+
+/// Splits a message or MIME part into its header block and body.
+fn split_message(message: &str) -> (&str, &str) {
+    message.split_once("\n\n").unwrap_or((message, ""))
+}
+
+/// Header fields with continuation lines unfolded and names lowercased.
+fn parse_fields(block: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in block.lines() {
+        match fields.last_mut() {
+            Some((_, value)) if line.starts_with([' ', '\t']) => {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            _ => {
+                if let Some((name, value)) = line.split_once(':') {
+                    fields.push((name.trim().to_lowercase(), value.trim().to_string()));
+                }
+            }
+        }
+    }
+    fields
+}
+
+fn field<'a>(fields: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    fields
+        .iter()
+        .find(|(field, _)| field == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// A parameter such as `boundary` from a `Content-Type` value.
+fn parameter(content_type: &str, name: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+fn mime_type(content_type: Option<&str>) -> String {
+    content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .unwrap_or("text/plain")
+        .trim()
+        .to_lowercase()
+}
+
+impl BounceParser for DsnParser {
+    fn name(&self) -> &'static str {
+        "dsn"
+    }
+
+    fn parse(&self, body: &[u8]) -> Result<Vec<BounceEvent>, BounceError> {
+        let message = String::from_utf8_lossy(body).replace("\r\n", "\n");
+        let (head, body) = split_message(&message);
+        let fields = parse_fields(head);
+        let content_type = field(&fields, "content-type").unwrap_or_default();
+        if mime_type(Some(content_type)) != "multipart/report" {
+            return Err(BounceError::ParseError(
+                "not a multipart/report message".to_string(),
+            ));
+        }
+        let boundary = parameter(content_type, "boundary").ok_or_else(|| missing("boundary"))?;
+
+        let delimiter = format!("--{}", boundary);
+        let mut bounces = Vec::new();
+        let mut feedback_type = None;
+        let mut complainant = None;
+        // The first piece is the preamble, the last the epilogue.
+        for part in body.split(&delimiter).skip(1) {
+            if part.starts_with("--") {
+                break;
+            }
+            let (part_head, part_body) = split_message(part.trim_start_matches('\n'));
+            match mime_type(field(&parse_fields(part_head), "content-type")).as_str() {
+                "message/delivery-status" => {
+                    // Per-message fields, then one group per recipient.
+                    for group in part_body.split("\n\n").skip(1) {
+                        let fields = parse_fields(group);
+                        let failed = field(&fields, "action")
+                            .is_some_and(|action| action.eq_ignore_ascii_case("failed"));
+                        let permanent =
+                            field(&fields, "status").is_some_and(|status| status.starts_with('5'));
+                        let recipient = field(&fields, "final-recipient")
+                            .or(field(&fields, "original-recipient"))
+                            .map(|recipient| {
+                                recipient.split_once(';').map_or(recipient, |(_, r)| r)
+                            });
+                        if let (true, true, Some(recipient)) = (failed, permanent, recipient) {
+                            let reason =
+                                field(&fields, "diagnostic-code").or(field(&fields, "status"));
+                            bounces.push(BounceEvent::new(
+                                recipient,
+                                BounceKind::HardBounce,
+                                reason,
+                            ));
+                        }
+                    }
+                }
+                "message/feedback-report" => {
+                    let fields = parse_fields(part_body);
+                    feedback_type = Some(
+                        field(&fields, "feedback-type")
+                            .unwrap_or("abuse")
+                            .to_string(),
+                    );
+                    if let Some(recipient) = field(&fields, "original-rcpt-to") {
+                        complainant = Some(recipient.to_string());
+                    }
+                }
+                // The returned message, whose recipient complained when the
+                // report does not name them.
+                "message/rfc822" | "text/rfc822-headers" if complainant.is_none() => {
+                    let (returned_head, _) = split_message(part_body.trim_start_matches('\n'));
+                    complainant = field(&parse_fields(returned_head), "to").map(str::to_string);
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(feedback_type) = feedback_type {
+            let recipient = complainant.ok_or_else(|| missing("complaint recipient"))?;
+            bounces.push(BounceEvent::new(
+                &recipient,
+                BounceKind::Complaint,
+                Some(&feedback_type),
+            ));
+        }
+        Ok(bounces)
+    }
+}
+
+/// The parsers the bounce webhook dispatches to by name. Parsers for other
+/// providers can be added with [`BounceParsers::register`].
+#[derive(Clone)]
+pub struct BounceParsers {
+    parsers: Vec<Arc<dyn BounceParser>>,
+}
+
+impl Default for BounceParsers {
+    fn default() -> Self {
+        Self {
+            parsers: vec![
+                Arc::new(SesParser),
+                Arc::new(SendGridParser),
+                Arc::new(DsnParser),
+            ],
+        }
+    }
+}
+
+impl BounceParsers {
+    /// Adds `parser`, replacing any parser with the same name.
+    pub fn register(mut self, parser: impl BounceParser + 'static) -> Self {
+        self.parsers
+            .retain(|existing| existing.name() != parser.name());
+        self.parsers.push(Arc::new(parser));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn BounceParser> {
+        self.parsers
+            .iter()
+            .find(|parser| parser.name() == name)
+            .map(|parser| parser.as_ref())
+    }
+}
+
+/// An address that hard bounced or complained. Suppressions do not expire;
+/// an address is mailed again only once its record is removed.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Suppression {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub email: String,
+    /// The latest kind reported.
+    pub kind: BounceKind,
+    pub reason: Option<String>,
+    /// The parser that reported it.
+    pub source: String,
+    pub events: u32,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+impl Suppression {
+    pub async fn is_suppressed(
+        suppressions: &dyn SuppressionRepository,
+        email: &str,
+    ) -> Result<bool, RepositoryError> {
+        Ok(suppressions.find(&address(email)).await?.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parsers_report_only_permanent_failures() {
+        let parsers = BounceParsers::default();
+
+        let ses = serde_json::json!({
+            "Type": "Notification",
+            "Message": serde_json::json!({
+                "notificationType": "Bounce",
+                "bounce": {
+                    "bounceType": "Permanent",
+                    "bouncedRecipients": [
+                        { "emailAddress": "Gone@Example.com", "diagnosticCode": "smtp; 550 5.1.1 user unknown" }
+                    ]
+                }
+            }).to_string(),
+        });
+        let events = parsers
+            .get("ses")
+            .unwrap()
+            .parse(ses.to_string().as_bytes())
+            .unwrap();
+        assert_eq!(
+            events,
+            vec![BounceEvent::new(
+                "gone@example.com",
+                BounceKind::HardBounce,
+                Some("smtp; 550 5.1.1 user unknown")
+            )]
+        );
+
+        let sendgrid = br#"[
+            {"email": "full@example.com", "event": "bounce", "type": "blocked"},
+            {"email": "spam@example.com", "event": "spamreport"},
+            {"email": "ok@example.com", "event": "delivered"}
+        ]"#;
+        let events = parsers.get("sendgrid").unwrap().parse(sendgrid).unwrap();
+        assert_eq!(
+            events,
+            vec![BounceEvent::new(
+                "spam@example.com",
+                BounceKind::Complaint,
+                None
+            )]
+        );
+
+        let dsn = "From: MAILER-DAEMON@example.net\r\n\
+            Content-Type: multipart/report; report-type=delivery-status;\r\n\
+            \tboundary=\"b1\"\r\n\
+            \r\n\
+            --b1\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            Delivery failed.\r\n\
+            --b1\r\n\
+            Content-Type: message/delivery-status\r\n\
+            \r\n\
+            Reporting-MTA: dns; mx.example.net\r\n\
+            \r\n\
+            Final-Recipient: rfc822; gone@example.net\r\n\
+            Action: failed\r\n\
+            Status: 5.1.1\r\n\
+            Diagnostic-Code: smtp; 550 5.1.1 no such user\r\n\
+            \r\n\
+            Final-Recipient: rfc822; later@example.net\r\n\
+            Action: delayed\r\n\
+            Status: 4.2.2\r\n\
+            --b1--\r\n";
+        let events = parsers.get("dsn").unwrap().parse(dsn.as_bytes()).unwrap();
+        assert_eq!(
+            events,
+            vec![BounceEvent::new(
+                "gone@example.net",
+                BounceKind::HardBounce,
+                Some("smtp; 550 5.1.1 no such user")
+            )]
+        );
+
+        let arf = "Content-Type: multipart/report; report-type=feedback-report; boundary=b2\n\
+            \n\
+            --b2\n\
+            Content-Type: message/feedback-report\n\
+            \n\
+            Feedback-Type: abuse\n\
+            Version: 1\n\
+            --b2\n\
+            Content-Type: message/rfc822\n\
+            \n\
+            From: auth-rs <no-reply@localhost>\n\
+            To: User <Complainer@example.org>\n\
+            Subject: Reset your password\n\
+            \n\
+            body\n\
+            --b2--\n";
+        let events = parsers.get("dsn").unwrap().parse(arf.as_bytes()).unwrap();
+        assert_eq!(
+            events,
+            vec![BounceEvent::new(
+                "complainer@example.org",
+                BounceKind::Complaint,
+                Some("abuse")
+            )]
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+pub const SECRET_KEY_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum CryptoError {
+    InvalidKey,
+    EncryptError,
+    DecryptError,
+}
+
+impl Display for CryptoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            CryptoError::InvalidKey => write!(f, "InvalidKey: expected {} bytes", SECRET_KEY_LEN),
+            CryptoError::EncryptError => write!(f, "EncryptError"),
+            CryptoError::DecryptError => write!(f, "DecryptError"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// AES-256-GCM encryption for values stored at rest. Ciphertexts are encoded
+/// as base64 of `nonce || ciphertext || tag`. The associated data binds a
+/// ciphertext to its owner so it cannot be copied onto another record.
+#[derive(Clone)]
+pub struct SecretBox {
+    key: LessSafeKey,
+}
+
+impl SecretBox {
+    pub fn new(key: &[u8]) -> Result<Self, CryptoError> {
+        if key.len() != SECRET_KEY_LEN {
+            return Err(CryptoError::InvalidKey);
+        }
+        let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| CryptoError::InvalidKey)?;
+
+        Ok(Self {
+            key: LessSafeKey::new(key),
+        })
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<String, CryptoError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| CryptoError::EncryptError)?;
+
+        let mut in_out = plaintext.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(aad),
+                &mut in_out,
+            )
+            .map_err(|_| CryptoError::EncryptError)?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&in_out);
+        Ok(STANDARD.encode(sealed))
+    }
+
+    pub fn decrypt(&self, ciphertext: &str, aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let sealed = STANDARD
+            .decode(ciphertext)
+            .map_err(|_| CryptoError::DecryptError)?;
+        if sealed.len() < NONCE_LEN {
+            return Err(CryptoError::DecryptError);
+        }
+
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let nonce =
+            Nonce::try_assume_unique_for_key(nonce).map_err(|_| CryptoError::DecryptError)?;
+        let mut in_out = rest.to_vec();
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::from(aad), &mut in_out)
+            .map_err(|_| CryptoError::DecryptError)?;
+
+        Ok(plaintext.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_binds_associated_data() {
+        let secret_box = SecretBox::new(&[7u8; SECRET_KEY_LEN]).unwrap();
+
+        let sealed = secret_box.encrypt(b"secret", b"user-a").unwrap();
+        assert_eq!(secret_box.decrypt(&sealed, b"user-a").unwrap(), b"secret");
+        assert!(secret_box.decrypt(&sealed, b"user-b").is_err());
+    }
+}
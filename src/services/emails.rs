@@ -0,0 +1,197 @@
+//! The transactional emails the service sends. Each message is a struct whose
+//! fields are exactly the variables its template set uses, so a template
+//! cannot be sent with a missing or misspelled value.
+
+use serde::Serialize;
+use tera::Context;
+
+/// A typed email backed by the template set `TEMPLATE` in every locale.
+pub trait TransactionalEmail: Serialize {
+    const TEMPLATE: &'static str;
+
+    fn context(&self) -> Result<Context, tera::Error> {
+        Context::from_serialize(self)
+    }
+}
+
+macro_rules! catalogue {
+    ($($email:ident => $template:literal),+ $(,)?) => {
+        $(impl TransactionalEmail for $email {
+            const TEMPLATE: &'static str = $template;
+        })+
+
+        /// Template set names of every email in the catalogue.
+        pub const TEMPLATES: &[&str] = &[$($template),+];
+    };
+}
+
+catalogue! {
+    EmailConfirmation => "confirm_email",
+    PasswordReset => "reset_password",
+    PasswordChanged => "password_changed",
+    NewDeviceLogin => "new_device_login",
+    EmailChange => "email_change",
+    MfaEnabled => "mfa_enabled",
+    MfaDisabled => "mfa_disabled",
+    AccountLocked => "account_locked",
+    OrganizationInvitation => "organization_invitation",
+    PaymentReceipt => "payment_receipt",
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MfaMethod {
+    Totp,
+    Passkey,
+}
+
+#[derive(Serialize)]
+pub struct EmailConfirmation {
+    pub otp_code: String,
+    pub expires_in_minutes: i64,
+}
+
+#[derive(Serialize)]
+pub struct PasswordReset {
+    pub reset_link: String,
+    pub expires_in_minutes: i64,
+}
+
+#[derive(Serialize)]
+pub struct PasswordChanged {
+    pub changed_at: String,
+    pub ip: Option<String>,
+    /// Where to start a reset if the change was not made by the owner.
+    pub reset_link: String,
+}
+
+#[derive(Serialize)]
+pub struct NewDeviceLogin {
+    pub device: String,
+    pub ip: Option<String>,
+    pub logged_in_at: String,
+    pub reset_link: String,
+}
+
+/// Sent to the new address to confirm an email change.
+#[derive(Serialize)]
+pub struct EmailChange {
+    pub new_email: String,
+    pub confirm_link: String,
+    pub expires_in_minutes: i64,
+}
+
+#[derive(Serialize)]
+pub struct MfaEnabled {
+    pub method: MfaMethod,
+}
+
+#[derive(Serialize)]
+pub struct MfaDisabled {
+    pub method: MfaMethod,
+}
+
+#[derive(Serialize)]
+pub struct AccountLocked {
+    pub unlock_link: String,
+    pub locked_minutes: i64,
+}
+
+#[derive(Serialize)]
+pub struct OrganizationInvitation {
+    pub organization_name: String,
+    pub inviter_email: String,
+    pub accept_link: String,
+    pub expires_in_days: i64,
+}
+
+#[derive(Serialize)]
+pub struct PaymentReceipt {
+    pub receipt_number: String,
+    pub description: String,
+    /// Already formatted for display, e.g. `12.50`.
+    pub amount: String,
+    pub currency: String,
+    pub paid_at: String,
+}
+
+/// An example of every email in the catalogue, keyed by template set.
+pub fn samples() -> Result<Vec<(&'static str, Context)>, tera::Error> {
+    fn sample<E: TransactionalEmail>(email: E) -> Result<(&'static str, Context), tera::Error> {
+        Ok((E::TEMPLATE, email.context()?))
+    }
+
+    let link = |path: &str| format!("http://localhost:8080/{}?token=example", path);
+    Ok(vec![
+        sample(EmailConfirmation {
+            otp_code: "123456".to_string(),
+            expires_in_minutes: 10,
+        })?,
+        sample(PasswordReset {
+            reset_link: link("reset-password"),
+            expires_in_minutes: 30,
+        })?,
+        sample(PasswordChanged {
+            changed_at: "2024-01-01 12:00 UTC".to_string(),
+            ip: Some("203.0.113.7".to_string()),
+            reset_link: link("forgot-password"),
+        })?,
+        sample(NewDeviceLogin {
+            device: "Firefox on Linux".to_string(),
+            ip: Some("203.0.113.7".to_string()),
+            logged_in_at: "2024-01-01 12:00 UTC".to_string(),
+            reset_link: link("forgot-password"),
+        })?,
+        sample(EmailChange {
+            new_email: "new@example.com".to_string(),
+            confirm_link: link("confirm-email-change"),
+            expires_in_minutes: 60,
+        })?,
+        sample(MfaEnabled {
+            method: MfaMethod::Totp,
+        })?,
+        sample(MfaDisabled {
+            method: MfaMethod::Passkey,
+        })?,
+        sample(AccountLocked {
+            unlock_link: link("unlock-account"),
+            locked_minutes: 15,
+        })?,
+        sample(OrganizationInvitation {
+            organization_name: "Example Inc.".to_string(),
+            inviter_email: "owner@example.com".to_string(),
+            accept_link: link("accept-invitation"),
+            expires_in_days: 7,
+        })?,
+        sample(PaymentReceipt {
+            receipt_number: "R-0001".to_string(),
+            description: "Pro plan, monthly".to_string(),
+            amount: "12.50".to_string(),
+            currency: "USD".to_string(),
+            paid_at: "2024-01-01 12:00 UTC".to_string(),
+        })?,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::templates::{TemplateRegistry, TEMPLATE_DIR};
+
+    #[test]
+    fn test_every_email_renders_in_every_locale() {
+        let registry = TemplateRegistry::load(TEMPLATE_DIR, false).unwrap();
+        let samples = samples().unwrap();
+        assert_eq!(samples.len(), TEMPLATES.len());
+
+        for locale in registry.locales() {
+            for (template, context) in &samples {
+                let email = registry
+                    .render(Some(locale), template, context)
+                    .unwrap_or_else(|err| panic!("{}/{}: {}", locale, template, err));
+                assert!(!email.subject.is_empty());
+                assert!(email.html.is_some(), "{}/{} has no html", locale, template);
+            }
+        }
+    }
+}
@@ -0,0 +1,463 @@
+use crate::db::{self, is_duplicate_key};
+use crate::services::crypto::SecretBox;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use chrono::Utc;
+use futures::TryStreamExt;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+    EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType,
+    PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use mongodb::bson::{doc, oid::ObjectId};
+use mongodb::options::FindOptions;
+use mongodb::{Collection, Database};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rsa::pkcs1::EncodeRsaPrivateKey;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPrivateKey;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::{Arc, RwLock};
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// How long a key signs tokens before a successor takes over.
+pub const KEY_ROTATION_DAYS: u64 = 30;
+/// How long a new key is published in the JWKS before it signs anything, so
+/// every instance and downstream verifier has fetched it by then.
+pub const KEY_PUBLISH_LEAD_SECS: u64 = 60 * 60;
+/// How long a retired key stays verifiable. Must outlive every token it
+/// signed, i.e. the longest access token lifetime plus clock skew.
+pub const KEY_VERIFY_GRACE_SECS: u64 = 60 * 60;
+/// How often each instance reloads keys and checks whether rotation is due.
+pub const KEY_REFRESH_SECS: u64 = 5 * 60;
+
+const RSA_KEY_BITS: usize = 2048;
+
+#[derive(Debug)]
+pub enum KeyError {
+    JwtError(jsonwebtoken::errors::Error),
+    MongoError(mongodb::error::Error),
+    CryptoError(String),
+    UnsupportedAlgorithm(String),
+    NoActiveKey,
+    UnknownKey,
+}
+
+impl Display for KeyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            KeyError::JwtError(e) => write!(f, "JwtError: {}", e),
+            KeyError::MongoError(e) => write!(f, "MongoError: {}", e),
+            KeyError::CryptoError(e) => write!(f, "CryptoError: {}", e),
+            KeyError::UnsupportedAlgorithm(e) => write!(f, "UnsupportedAlgorithm: {}", e),
+            KeyError::NoActiveKey => write!(f, "NoActiveKey"),
+            KeyError::UnknownKey => write!(f, "UnknownKey"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// Parses the configured signing algorithm. Only asymmetric algorithms are
+/// accepted so downstream services can verify with the published JWKS.
+pub fn parse_algorithm(value: &str) -> Result<Algorithm, KeyError> {
+    match value {
+        "RS256" => Ok(Algorithm::RS256),
+        "ES256" => Ok(Algorithm::ES256),
+        "EdDSA" => Ok(Algorithm::EdDSA),
+        other => Err(KeyError::UnsupportedAlgorithm(other.to_string())),
+    }
+}
+
+/// A signing key as stored in the `signing_keys` collection.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SigningKey {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub kid: String,
+    pub algorithm: Algorithm,
+    /// Monotonic counter; the unique index on it stops two instances from
+    /// rotating at the same time.
+    pub generation: i64,
+    /// PKCS#1 (RSA) or PKCS#8 (EC, Ed25519) DER, encrypted with
+    /// `ENCRYPTION_KEY` and bound to the `kid`. Cleared once the key retires.
+    pub private_key: String,
+    /// False for keys stored before private keys were encrypted, whose
+    /// `private_key` is plain base64 DER. They are rotated out early.
+    #[serde(default)]
+    pub encrypted: bool,
+    pub public_jwk: Jwk,
+    pub activated_at: u64,
+    pub retired_at: Option<u64>,
+    pub verify_until: Option<u64>,
+    pub created_at: Option<u64>,
+}
+
+impl SigningKey {
+    fn collection(db: &Database) -> Collection<SigningKey> {
+        db.collection("signing_keys")
+    }
+
+    pub fn generate(
+        cipher: &SecretBox,
+        algorithm: Algorithm,
+        generation: i64,
+        activated_at: u64,
+    ) -> Result<Self, KeyError> {
+        let kid = Uuid::new_v4().to_string();
+        let (private_der, params) = match algorithm {
+            Algorithm::RS256 => Self::generate_rsa()?,
+            Algorithm::ES256 => Self::generate_ec()?,
+            Algorithm::EdDSA => Self::generate_ed()?,
+            other => return Err(KeyError::UnsupportedAlgorithm(format!("{:?}", other))),
+        };
+
+        let public_jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(match algorithm {
+                    Algorithm::RS256 => KeyAlgorithm::RS256,
+                    Algorithm::ES256 => KeyAlgorithm::ES256,
+                    _ => KeyAlgorithm::EdDSA,
+                }),
+                key_id: Some(kid.clone()),
+                ..Default::default()
+            },
+            algorithm: params,
+        };
+
+        let private_key = cipher
+            .encrypt(&private_der, kid.as_bytes())
+            .map_err(|e| KeyError::CryptoError(e.to_string()))?;
+
+        Ok(Self {
+            id: None,
+            kid,
+            algorithm,
+            generation,
+            private_key,
+            encrypted: true,
+            public_jwk,
+            activated_at,
+            retired_at: None,
+            verify_until: None,
+            created_at: Some(Utc::now().timestamp() as u64),
+        })
+    }
+
+    fn generate_rsa() -> Result<(Vec<u8>, AlgorithmParameters), KeyError> {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), RSA_KEY_BITS)
+            .map_err(|e| KeyError::CryptoError(e.to_string()))?;
+        let der = key
+            .to_pkcs1_der()
+            .map_err(|e| KeyError::CryptoError(e.to_string()))?;
+
+        Ok((
+            der.as_bytes().to_vec(),
+            AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+                e: URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+            }),
+        ))
+    }
+
+    fn generate_ec() -> Result<(Vec<u8>, AlgorithmParameters), KeyError> {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+            .map_err(|e| KeyError::CryptoError(e.to_string()))?;
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .map_err(|e| KeyError::CryptoError(e.to_string()))?;
+        // Uncompressed SEC1 point: 0x04 || x || y.
+        let point = pair.public_key().as_ref();
+
+        Ok((
+            pkcs8.as_ref().to_vec(),
+            AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                key_type: EllipticCurveKeyType::EC,
+                curve: EllipticCurve::P256,
+                x: URL_SAFE_NO_PAD.encode(&point[1..33]),
+                y: URL_SAFE_NO_PAD.encode(&point[33..65]),
+            }),
+        ))
+    }
+
+    fn generate_ed() -> Result<(Vec<u8>, AlgorithmParameters), KeyError> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|e| KeyError::CryptoError(e.to_string()))?;
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+            .map_err(|e| KeyError::CryptoError(e.to_string()))?;
+
+        Ok((
+            pkcs8.as_ref().to_vec(),
+            AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(pair.public_key().as_ref()),
+            }),
+        ))
+    }
+
+    fn encoding_key(&self, cipher: &SecretBox) -> Result<EncodingKey, KeyError> {
+        let der = if self.encrypted {
+            cipher
+                .decrypt(&self.private_key, self.kid.as_bytes())
+                .map_err(|e| KeyError::CryptoError(e.to_string()))?
+        } else {
+            STANDARD
+                .decode(&self.private_key)
+                .map_err(|e| KeyError::CryptoError(e.to_string()))?
+        };
+
+        Ok(match self.algorithm {
+            Algorithm::RS256 => EncodingKey::from_rsa_der(&der),
+            Algorithm::ES256 => EncodingKey::from_ec_der(&der),
+            _ => EncodingKey::from_ed_der(&der),
+        })
+    }
+
+    fn is_signing(&self, now: u64) -> bool {
+        self.activated_at <= now && self.retired_at.is_none_or(|retired| retired > now)
+    }
+
+    /// Loads every key that may still verify a token, newest first.
+    pub async fn load_valid(db: &Database, now: u64) -> Result<Vec<SigningKey>, KeyError> {
+        let filter = doc! {
+            "$or": [
+                { "verify_until": null },
+                { "verify_until": { "$gt": now as i64 } },
+            ]
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "generation": -1 })
+            .build();
+
+        Self::collection(db)
+            .find(filter, options)
+            .instrument(db::span("signing_keys", "find"))
+            .await
+            .map_err(KeyError::MongoError)?
+            .try_collect()
+            .await
+            .map_err(KeyError::MongoError)
+    }
+
+    /// Creates the first key, or schedules a successor once the newest key
+    /// has been signing for [`KEY_ROTATION_DAYS`] or was stored unencrypted.
+    /// The predecessor keeps signing until the successor activates and stays
+    /// verifiable for [`KEY_VERIFY_GRACE_SECS`] afterwards. A newest key that
+    /// `cipher` cannot decrypt, e.g. after a development restart with a
+    /// random `ENCRYPTION_KEY`, cannot sign at all, so its successor
+    /// activates at once.
+    pub async fn rotate_if_due(
+        db: &Database,
+        cipher: &SecretBox,
+        algorithm: Algorithm,
+        now: u64,
+    ) -> Result<(), KeyError> {
+        let collection = Self::collection(db);
+
+        // Retired keys never sign again, so their private half is dropped.
+        collection
+            .update_many(
+                doc! { "retired_at": { "$lte": now as i64 }, "private_key": { "$ne": "" } },
+                doc! { "$set": { "private_key": "" } },
+                None,
+            )
+            .instrument(db::span("signing_keys", "update_many"))
+            .await
+            .map_err(KeyError::MongoError)?;
+
+        let latest = Self::load_valid(db, now).await?.into_iter().next();
+        let next = match &latest {
+            None => Self::generate(cipher, algorithm, 1, now)?,
+            Some(latest) if latest.encoding_key(cipher).is_err() => {
+                Self::generate(cipher, algorithm, latest.generation + 1, now)?
+            }
+            Some(latest)
+                if !latest.encrypted || latest.activated_at + KEY_ROTATION_DAYS * 86_400 <= now =>
+            {
+                Self::generate(
+                    cipher,
+                    algorithm,
+                    latest.generation + 1,
+                    now + KEY_PUBLISH_LEAD_SECS,
+                )?
+            }
+            Some(_) => return Ok(()),
+        };
+
+        match collection
+            .insert_one(&next, None)
+            .instrument(db::span("signing_keys", "insert_one"))
+            .await
+        {
+            Ok(_) => {}
+            // Another instance rotated first; its key will be picked up on reload.
+            Err(err) if is_duplicate_key(&err) => return Ok(()),
+            Err(err) => return Err(KeyError::MongoError(err)),
+        }
+
+        let filter = doc! {
+            "generation": { "$lt": next.generation },
+            "retired_at": null,
+        };
+        let update = doc! {
+            "$set": {
+                "retired_at": next.activated_at as i64,
+                "verify_until": (next.activated_at + KEY_VERIFY_GRACE_SECS) as i64,
+            }
+        };
+        collection
+            .update_many(filter, update, None)
+            .instrument(db::span("signing_keys", "update_many"))
+            .await
+            .map_err(KeyError::MongoError)?;
+        Ok(())
+    }
+}
+
+struct KeySet {
+    signing: Option<(String, Algorithm, EncodingKey)>,
+    verifying: HashMap<String, (Algorithm, DecodingKey)>,
+    jwks: JwkSet,
+}
+
+/// In-memory view of the signing keys, shared by every worker and refreshed
+/// from MongoDB in the background. `cipher` decrypts the private keys.
+#[derive(Clone)]
+pub struct KeyRing {
+    inner: Arc<RwLock<KeySet>>,
+    cipher: SecretBox,
+}
+
+impl KeyRing {
+    pub fn from_keys(cipher: SecretBox, keys: &[SigningKey], now: u64) -> Result<Self, KeyError> {
+        Ok(Self {
+            inner: Arc::new(RwLock::new(Self::build(&cipher, keys, now)?)),
+            cipher,
+        })
+    }
+
+    fn build(cipher: &SecretBox, keys: &[SigningKey], now: u64) -> Result<KeySet, KeyError> {
+        let signing = match keys
+            .iter()
+            .filter(|key| key.is_signing(now))
+            .max_by_key(|key| key.generation)
+        {
+            Some(key) => Some((key.kid.clone(), key.algorithm, key.encoding_key(cipher)?)),
+            None => None,
+        };
+
+        let mut verifying = HashMap::new();
+        for key in keys {
+            let decoding = DecodingKey::from_jwk(&key.public_jwk).map_err(KeyError::JwtError)?;
+            verifying.insert(key.kid.clone(), (key.algorithm, decoding));
+        }
+
+        Ok(KeySet {
+            signing,
+            verifying,
+            jwks: JwkSet {
+                keys: keys.iter().map(|key| key.public_jwk.clone()).collect(),
+            },
+        })
+    }
+
+    pub fn replace(&self, keys: &[SigningKey], now: u64) -> Result<(), KeyError> {
+        let set = Self::build(&self.cipher, keys, now)?;
+        *self.inner.write().unwrap() = set;
+        Ok(())
+    }
+
+    /// Rotates if due and reloads the key set from MongoDB.
+    pub async fn refresh(&self, db: &Database, algorithm: Algorithm) -> Result<(), KeyError> {
+        let now = Utc::now().timestamp() as u64;
+        SigningKey::rotate_if_due(db, &self.cipher, algorithm, now).await?;
+        let keys = SigningKey::load_valid(db, now).await?;
+        self.replace(&keys, now)
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        self.inner.read().unwrap().jwks.clone()
+    }
+
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, KeyError> {
+        let set = self.inner.read().unwrap();
+        let (kid, algorithm, key) = set.signing.as_ref().ok_or(KeyError::NoActiveKey)?;
+
+        let mut header = Header::new(*algorithm);
+        header.kid = Some(kid.clone());
+        encode(&header, claims, key).map_err(KeyError::JwtError)
+    }
+
+    /// Verifies `token` against the key named by its `kid`, pinning the
+    /// algorithm to the one that key was generated for.
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        mut validation: Validation,
+    ) -> Result<T, KeyError> {
+        let header = decode_header(token).map_err(KeyError::JwtError)?;
+        let kid = header.kid.ok_or(KeyError::UnknownKey)?;
+
+        let set = self.inner.read().unwrap();
+        let (algorithm, key) = set.verifying.get(&kid).ok_or(KeyError::UnknownKey)?;
+        validation.algorithms = vec![*algorithm];
+
+        decode::<T>(token, key, &validation)
+            .map(|data| data.claims)
+            .map_err(KeyError::JwtError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::crypto::SECRET_KEY_LEN;
+
+    #[derive(Serialize, Deserialize)]
+    struct TestClaims {
+        sub: String,
+        exp: i64,
+    }
+
+    #[test]
+    fn test_retired_key_still_verifies() {
+        let now = Utc::now().timestamp() as u64;
+        let cipher = SecretBox::new(&[5u8; SECRET_KEY_LEN]).unwrap();
+        let mut old = SigningKey::generate(&cipher, Algorithm::EdDSA, 1, now - 100).unwrap();
+        let new = SigningKey::generate(&cipher, Algorithm::ES256, 2, now + 100).unwrap();
+        // The private key is stored encrypted and bound to its kid.
+        assert!(new.encoding_key(&cipher).is_ok());
+        let mut swapped = new.clone();
+        swapped.kid = old.kid.clone();
+        assert!(swapped.encoding_key(&cipher).is_err());
+        let claims = TestClaims {
+            sub: "user".to_string(),
+            exp: now as i64 + 600,
+        };
+
+        // The new key is published but not yet signing.
+        let ring = KeyRing::from_keys(cipher, &[new.clone(), old.clone()], now).unwrap();
+        assert_eq!(ring.jwks().keys.len(), 2);
+        let token = ring.encode(&claims).unwrap();
+        assert_eq!(decode_header(&token).unwrap().kid, Some(old.kid.clone()));
+
+        // After rotation the old key no longer signs but still verifies.
+        old.retired_at = Some(now + 100);
+        ring.replace(&[new.clone(), old], now + 200).unwrap();
+        let decoded: TestClaims = ring.decode(&token, Validation::default()).unwrap();
+        assert_eq!(decoded.sub, "user");
+        let rotated = ring.encode(&claims).unwrap();
+        assert_eq!(decode_header(&rotated).unwrap().kid, Some(new.kid));
+    }
+}
@@ -0,0 +1,198 @@
+use crate::repositories::{login_failure::LoginFailureRepository, RepositoryError};
+use chrono::{Duration, Utc};
+use mongodb::bson::DateTime;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const LOCKOUT_MINUTES: i64 = 15;
+pub const FAILURE_WINDOW_MINUTES: i64 = 15;
+
+/// How failed logins against one key are throttled. After `free_failures`
+/// each further failure doubles the wait before the next attempt, capped at
+/// `max_delay_secs`; `lockout_after` failures lock the key for
+/// [`LOCKOUT_MINUTES`].
+pub struct Policy {
+    pub free_failures: u32,
+    pub lockout_after: u32,
+    pub max_delay_secs: i64,
+}
+
+pub const ACCOUNT_POLICY: Policy = Policy {
+    free_failures: 1,
+    lockout_after: 5,
+    max_delay_secs: 60,
+};
+
+/// Looser than the account policy, since many users can share an address.
+pub const IP_POLICY: Policy = Policy {
+    free_failures: 10,
+    lockout_after: 50,
+    max_delay_secs: 60,
+};
+
+pub fn account_key(email: &str) -> String {
+    format!("account:{}", email.trim().to_lowercase())
+}
+
+pub fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+/// Recent failed logins against an account or an IP address. Accounts are
+/// keyed by email, so unknown emails are throttled exactly like real ones.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LoginFailures {
+    #[serde(rename = "_id")]
+    pub key: String,
+    pub failures: u32,
+    pub last_failure_at: DateTime,
+    pub locked_until: Option<DateTime>,
+    pub unlock_token_hash: Option<String>,
+    pub expires_at: DateTime,
+}
+
+impl LoginFailures {
+    fn hash(token: &str) -> String {
+        format!("{:x}", Sha256::digest(token.as_bytes()))
+    }
+
+    /// Seconds until the key may try again, if it must wait.
+    pub fn retry_after(&self, policy: &Policy, now: DateTime) -> Option<i64> {
+        let now = now.timestamp_millis();
+        if let Some(locked_until) = self.locked_until {
+            if locked_until.timestamp_millis() > now {
+                return Some((locked_until.timestamp_millis() - now + 999) / 1000);
+            }
+        }
+
+        let delayed = self.failures.saturating_sub(policy.free_failures);
+        if delayed == 0 {
+            return None;
+        }
+        let delay_secs = 2i64.saturating_pow(delayed - 1).min(policy.max_delay_secs);
+        let wait_until = self.last_failure_at.timestamp_millis() + delay_secs * 1000;
+        (wait_until > now).then(|| (wait_until - now + 999) / 1000)
+    }
+
+    pub async fn check(
+        failures: &dyn LoginFailureRepository,
+        key: &str,
+        policy: &Policy,
+    ) -> Result<Option<i64>, RepositoryError> {
+        let record = failures.find(key).await?;
+        Ok(record.and_then(|record| record.retry_after(policy, DateTime::now())))
+    }
+
+    /// Counts a failure and locks the key once it reaches the policy's limit.
+    /// Returns the unlock token when this failure caused the lockout; only
+    /// one of several concurrent failures can.
+    pub async fn record_failure(
+        failures: &dyn LoginFailureRepository,
+        key: &str,
+        policy: &Policy,
+    ) -> Result<Option<String>, RepositoryError> {
+        let now = Utc::now();
+        let expires_at = DateTime::from_millis(
+            (now + Duration::try_minutes(FAILURE_WINDOW_MINUTES).unwrap()).timestamp_millis(),
+        );
+        let record = failures.increment(key, expires_at).await?;
+        if record.is_none_or(|record| record.failures < policy.lockout_after) {
+            return Ok(None);
+        }
+
+        // The counter restarts with the lock, so backoff starts afresh once
+        // the lock has expired.
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 48);
+        let locked_until = DateTime::from_millis(
+            (now + Duration::try_minutes(LOCKOUT_MINUTES).unwrap()).timestamp_millis(),
+        );
+        let locked = failures
+            .lock(key, policy.lockout_after, locked_until, &Self::hash(&token))
+            .await?;
+        Ok(locked.then_some(token))
+    }
+
+    pub async fn clear(
+        failures: &dyn LoginFailureRepository,
+        key: &str,
+    ) -> Result<(), RepositoryError> {
+        failures.delete(key).await
+    }
+
+    /// Lifts a lockout with the token from the unlock email and returns the
+    /// key it applied to.
+    pub async fn unlock(
+        failures: &dyn LoginFailureRepository,
+        token: &str,
+    ) -> Result<Option<String>, RepositoryError> {
+        let record = failures.take_by_unlock_token(&Self::hash(token)).await?;
+        Ok(record.map(|record| record.key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::login_failure::MemoryLoginFailureRepository;
+
+    #[test]
+    fn test_backoff_doubles_until_capped() {
+        let now = DateTime::from_millis(1_700_000_000_000);
+        let mut record = LoginFailures {
+            key: account_key("user@example.com"),
+            failures: 1,
+            last_failure_at: now,
+            locked_until: None,
+            unlock_token_hash: None,
+            expires_at: now,
+        };
+
+        assert_eq!(record.retry_after(&ACCOUNT_POLICY, now), None);
+        record.failures = 2;
+        assert_eq!(record.retry_after(&ACCOUNT_POLICY, now), Some(1));
+        record.failures = 4;
+        assert_eq!(record.retry_after(&ACCOUNT_POLICY, now), Some(4));
+        record.failures = 40;
+        assert_eq!(record.retry_after(&ACCOUNT_POLICY, now), Some(60));
+
+        record.failures = 0;
+        record.locked_until = Some(DateTime::from_millis(now.timestamp_millis() + 90_000));
+        assert_eq!(record.retry_after(&ACCOUNT_POLICY, now), Some(90));
+    }
+
+    #[actix_web::test]
+    async fn test_lockout_issues_one_unlock_token() {
+        let failures = MemoryLoginFailureRepository::new();
+        let key = account_key("user@example.com");
+
+        let mut tokens = Vec::new();
+        for _ in 0..ACCOUNT_POLICY.lockout_after {
+            tokens.extend(
+                LoginFailures::record_failure(&failures, &key, &ACCOUNT_POLICY)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(tokens.len(), 1);
+        let retry_after = LoginFailures::check(&failures, &key, &ACCOUNT_POLICY)
+            .await
+            .unwrap();
+        assert!(retry_after.is_some_and(|secs| secs > ACCOUNT_POLICY.max_delay_secs));
+
+        assert_eq!(
+            LoginFailures::unlock(&failures, "wrong").await.unwrap(),
+            None
+        );
+        assert_eq!(
+            LoginFailures::unlock(&failures, &tokens[0]).await.unwrap(),
+            Some(key.clone())
+        );
+        assert_eq!(
+            LoginFailures::check(&failures, &key, &ACCOUNT_POLICY)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,346 @@
+use crate::models::user::User;
+use crate::repositories::{
+    mfa_challenge::MfaChallengeRepository, user::UserRepository, RepositoryError,
+};
+use crate::services::crypto::{CryptoError, SecretBox};
+use chrono::{Duration, Utc};
+use mongodb::bson::{oid::ObjectId, DateTime};
+use qrcodegen::{QrCode, QrCodeEcc};
+use rand::distributions::{Alphanumeric, DistString};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter, Result as FmtResult, Write};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+pub const TOTP_ISSUER: &str = "auth-rs";
+pub const TOTP_DIGITS: usize = 6;
+pub const TOTP_STEP: u64 = 30;
+pub const MFA_CHALLENGE_TTL_MINUTES: i64 = 5;
+pub const MFA_MAX_ATTEMPTS: u32 = 5;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+#[derive(Debug)]
+pub enum MfaError {
+    CryptoError(CryptoError),
+    RepositoryError(RepositoryError),
+    TotpError(String),
+    NotEnrolled,
+}
+
+impl Display for MfaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            MfaError::CryptoError(e) => write!(f, "CryptoError: {}", e),
+            MfaError::RepositoryError(e) => write!(f, "RepositoryError: {}", e),
+            MfaError::TotpError(e) => write!(f, "TotpError: {}", e),
+            MfaError::NotEnrolled => write!(f, "NotEnrolled"),
+        }
+    }
+}
+
+impl std::error::Error for MfaError {}
+
+/// What the client needs to add the account to an authenticator app.
+#[derive(Serialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub otpauth_url: String,
+    pub qr_png: String,
+    pub qr_svg: String,
+}
+
+fn build_totp(secret: Vec<u8>, email: &str) -> Result<TOTP, MfaError> {
+    TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        0,
+        TOTP_STEP,
+        secret,
+        Some(TOTP_ISSUER.to_string()),
+        email.to_string(),
+    )
+    .map_err(|e| MfaError::TotpError(e.to_string()))
+}
+
+fn qr_svg(data: &str) -> Result<String, MfaError> {
+    let qr = QrCode::encode_text(data, QrCodeEcc::Medium)
+        .map_err(|e| MfaError::TotpError(e.to_string()))?;
+    let border = 4;
+    let dimension = qr.size() + border * 2;
+
+    let mut path = String::new();
+    for y in 0..qr.size() {
+        for x in 0..qr.size() {
+            if qr.get_module(x, y) {
+                let _ = write!(path, "M{},{}h1v1h-1z", x + border, y + border);
+            }
+        }
+    }
+
+    Ok(format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {0} {0}\" shape-rendering=\"crispEdges\">\
+         <rect width=\"100%\" height=\"100%\" fill=\"#FFFFFF\"/>\
+         <path d=\"{1}\" fill=\"#000000\"/></svg>",
+        dimension, path
+    ))
+}
+
+/// Generates a fresh TOTP secret for `email`, returning the raw secret to be
+/// stored encrypted and the provisioning data for the client.
+pub fn new_enrollment(email: &str) -> Result<(Vec<u8>, TotpEnrollment), MfaError> {
+    let secret: [u8; 20] = rand::random();
+    let totp = build_totp(secret.to_vec(), email)?;
+    let otpauth_url = totp.get_url();
+
+    let enrollment = TotpEnrollment {
+        secret: Secret::Raw(secret.to_vec()).to_encoded().to_string(),
+        qr_png: totp.get_qr_base64().map_err(MfaError::TotpError)?,
+        qr_svg: qr_svg(&otpauth_url)?,
+        otpauth_url,
+    };
+
+    Ok((secret.to_vec(), enrollment))
+}
+
+/// Checks `code` against the current step and one step either side, and
+/// returns the matching step. Steps at or before `last_step` are rejected so
+/// a code cannot be replayed within its validity window.
+pub fn verify_code(
+    secret: &[u8],
+    email: &str,
+    code: &str,
+    last_step: Option<u64>,
+    now: u64,
+) -> Result<Option<u64>, MfaError> {
+    let totp = build_totp(secret.to_vec(), email)?;
+    let current = now / TOTP_STEP;
+
+    for step in [current - 1, current, current + 1] {
+        if last_step.is_some_and(|last| step <= last) {
+            continue;
+        }
+        if totp.check(code, step * TOTP_STEP) {
+            return Ok(Some(step));
+        }
+    }
+    Ok(None)
+}
+
+pub fn encrypt_secret(
+    cipher: &SecretBox,
+    user_id: ObjectId,
+    secret: &[u8],
+) -> Result<String, MfaError> {
+    cipher
+        .encrypt(secret, &user_id.bytes())
+        .map_err(MfaError::CryptoError)
+}
+
+pub fn decrypt_secret(
+    cipher: &SecretBox,
+    user_id: ObjectId,
+    secret: &str,
+) -> Result<Vec<u8>, MfaError> {
+    cipher
+        .decrypt(secret, &user_id.bytes())
+        .map_err(MfaError::CryptoError)
+}
+
+/// Verifies a code against the user's enabled TOTP secret and records the
+/// accepted step. Recording the step is conditional, so concurrent use of the
+/// same code succeeds at most once.
+pub async fn verify_user_totp(
+    users: &dyn UserRepository,
+    cipher: &SecretBox,
+    user: &User,
+    code: &str,
+) -> Result<bool, MfaError> {
+    let (user_id, encrypted) = match (user.id, &user.totp_secret) {
+        (Some(user_id), Some(encrypted)) if user.totp_enabled == Some(true) => (user_id, encrypted),
+        _ => return Err(MfaError::NotEnrolled),
+    };
+
+    let secret = decrypt_secret(cipher, user_id, encrypted)?;
+    let now = Utc::now().timestamp() as u64;
+    let step = match verify_code(&secret, &user.email, code, user.totp_last_step, now)? {
+        Some(step) => step,
+        None => return Ok(false),
+    };
+
+    users
+        .record_totp_step(user_id, step)
+        .await
+        .map_err(MfaError::RepositoryError)
+}
+
+/// Hashes a recovery code for storage. Codes are normalised so users may
+/// type them with or without the separator and in any case; the user id acts
+/// as a salt so identical codes never share a hash across accounts.
+pub fn hash_recovery_code(user_id: ObjectId, code: &str) -> String {
+    let normalised: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    let mut hasher = Sha256::new();
+    hasher.update(user_id.bytes());
+    hasher.update(normalised.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Generates a fresh set of recovery codes, returning the plaintext codes to
+/// show the user once and the hashes to store.
+pub fn generate_recovery_codes(user_id: ObjectId) -> (Vec<String>, Vec<String>) {
+    let mut rng = rand::thread_rng();
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let chars: String = (0..10)
+                .map(|_| {
+                    RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char
+                })
+                .collect();
+            format!("{}-{}", &chars[..5], &chars[5..])
+        })
+        .collect();
+    let hashes = codes
+        .iter()
+        .map(|code| hash_recovery_code(user_id, code))
+        .collect();
+
+    (codes, hashes)
+}
+
+/// Consumes a recovery code. Returns false when the user does not hold it,
+/// including when a concurrent request has just redeemed it.
+pub async fn redeem_recovery_code(
+    users: &dyn UserRepository,
+    user_id: ObjectId,
+    code: &str,
+) -> Result<bool, MfaError> {
+    users
+        .redeem_recovery_code(user_id, &hash_recovery_code(user_id, code))
+        .await
+        .map_err(MfaError::RepositoryError)
+}
+
+/// A pending second-factor step of a login whose password was accepted.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MfaChallenge {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub token_hash: String,
+    pub user_id: ObjectId,
+    pub issue_tokens: bool,
+    pub attempts: u32,
+    pub created_at: Option<u64>,
+    pub expires_at: DateTime,
+}
+
+impl MfaChallenge {
+    fn hash(token: &str) -> String {
+        format!("{:x}", Sha256::digest(token.as_bytes()))
+    }
+
+    /// Starts a challenge and returns the opaque token the client presents
+    /// with its code.
+    pub async fn create(
+        challenges: &dyn MfaChallengeRepository,
+        user_id: ObjectId,
+        issue_tokens: bool,
+    ) -> Result<String, MfaError> {
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 48);
+        let now = Utc::now();
+        let challenge = MfaChallenge {
+            id: None,
+            token_hash: Self::hash(&token),
+            user_id,
+            issue_tokens,
+            attempts: 0,
+            created_at: Some(now.timestamp() as u64),
+            expires_at: DateTime::from_millis(
+                (now + Duration::try_minutes(MFA_CHALLENGE_TTL_MINUTES).unwrap())
+                    .timestamp_millis(),
+            ),
+        };
+
+        challenges
+            .insert(&challenge)
+            .await
+            .map_err(MfaError::RepositoryError)?;
+        Ok(token)
+    }
+
+    /// Looks up a live challenge without counting an attempt, for steps that
+    /// only prepare the second factor.
+    pub async fn find(
+        challenges: &dyn MfaChallengeRepository,
+        token: &str,
+    ) -> Result<Option<Self>, MfaError> {
+        challenges
+            .find(&Self::hash(token))
+            .await
+            .map_err(MfaError::RepositoryError)
+    }
+
+    /// Looks up a live challenge and counts the attempt against it up front,
+    /// so parallel guesses cannot exceed [`MFA_MAX_ATTEMPTS`].
+    pub async fn begin_attempt(
+        challenges: &dyn MfaChallengeRepository,
+        token: &str,
+    ) -> Result<Option<Self>, MfaError> {
+        challenges
+            .begin_attempt(&Self::hash(token))
+            .await
+            .map_err(MfaError::RepositoryError)
+    }
+
+    pub async fn complete(
+        challenges: &dyn MfaChallengeRepository,
+        token: &str,
+    ) -> Result<(), MfaError> {
+        challenges
+            .delete(&Self::hash(token))
+            .await
+            .map_err(MfaError::RepositoryError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_code_rejects_replayed_step() {
+        let secret = [3u8; 20];
+        let now = 1_700_000_000;
+        let totp = build_totp(secret.to_vec(), "user@example.com").unwrap();
+        let code = totp.generate(now);
+
+        let step = verify_code(&secret, "user@example.com", &code, None, now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(step, now / TOTP_STEP);
+        assert_eq!(
+            verify_code(&secret, "user@example.com", &code, Some(step), now).unwrap(),
+            None
+        );
+        assert_eq!(
+            verify_code(&secret, "user@example.com", "000000", None, now).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_recovery_code_hash_is_normalised_and_salted() {
+        let user_id = ObjectId::new();
+        let (codes, hashes) = generate_recovery_codes(user_id);
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+
+        let typed = codes[0].replace('-', " ").to_uppercase();
+        assert_eq!(hash_recovery_code(user_id, &typed), hashes[0]);
+        assert_ne!(hash_recovery_code(ObjectId::new(), &codes[0]), hashes[0]);
+    }
+}
@@ -1,2 +1,16 @@
+pub mod bounce;
+pub mod crypto;
+pub mod emails;
+pub mod keys;
+pub mod lockout;
 pub mod mail;
-pub mod otp;
\ No newline at end of file
+pub mod mfa;
+pub mod otp;
+pub mod outbox;
+pub mod password_reset;
+pub mod rate_limit;
+pub mod security_event;
+pub mod session;
+pub mod templates;
+pub mod token;
+pub mod webauthn;
@@ -1,15 +1,23 @@
+use crate::repositories::{otp::OtpRepository, RepositoryError};
+use crate::services::emails::EmailConfirmation;
+use crate::services::mail::{EmailError, MailService};
 use chrono::{Duration, Utc};
-use mongodb::bson::doc;
-use mongodb::{Collection, Database};
+use mongodb::bson::{oid::ObjectId, DateTime};
 use rand::prelude::*;
+use ring::{hkdf, hmac};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::{Arc, Mutex};
 use validator::Validate;
 
+pub const OTP_TTL_MINUTES: i64 = 10;
+pub const OTP_MAX_ATTEMPTS: u32 = 5;
+
 #[derive(Debug)]
 pub enum OtpError {
     ValidationError(String),
-    MongoError(mongodb::error::Error),
+    RepositoryError(RepositoryError),
     ChronoError(String),
 }
 
@@ -17,109 +25,288 @@ impl Display for OtpError {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         match self {
             OtpError::ValidationError(e) => write!(f, "ValidationError: {}", e),
-            OtpError::MongoError(e) => write!(f, "MongoError: {}", e),
+            OtpError::RepositoryError(e) => write!(f, "RepositoryError: {}", e),
             OtpError::ChronoError(e) => write!(f, "ChronoError: {}", e),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Validate)]
+impl std::error::Error for OtpError {}
+
+/// Keyed hashing for OTP codes. With only 900k possible codes a plain hash
+/// is reversed by enumeration, so codes are hashed with HMAC-SHA256 under a
+/// key derived from the application encryption key.
+#[derive(Clone)]
+pub struct OtpHasher {
+    key: hmac::Key,
+}
+
+impl OtpHasher {
+    pub fn derive(master_key: &[u8]) -> Self {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, b"auth-rs otp").extract(master_key);
+        let okm = prk
+            .expand(&[b"otp-hmac"], hmac::HMAC_SHA256)
+            .expect("HMAC-SHA256 key length is a valid HKDF output length");
+
+        Self {
+            key: hmac::Key::from(okm),
+        }
+    }
+
+    fn message(email: &str, code: u32) -> String {
+        format!("{}:{}", email, code)
+    }
+
+    pub fn hash(&self, email: &str, code: u32) -> String {
+        let tag = hmac::sign(&self.key, Self::message(email, code).as_bytes());
+        tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Checks `code` against a stored hash in constant time.
+    pub fn verify(&self, email: &str, code: u32, code_hash: &str) -> bool {
+        let tag = match (0..code_hash.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(code_hash.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+        {
+            Some(tag) => tag,
+            None => return false,
+        };
+        hmac::verify(&self.key, Self::message(email, code).as_bytes(), &tag).is_ok()
+    }
+}
+
+/// A code held by [`OtpDelivery::Capture`] instead of being mailed.
+#[derive(Clone, Debug, Serialize)]
+pub struct CapturedOtp {
+    pub email: String,
+    pub code: u32,
+    pub sent_at: i64,
+}
+
+/// The last code delivered to each address.
+#[derive(Clone, Default)]
+pub struct CapturedOtps {
+    codes: Arc<Mutex<HashMap<String, CapturedOtp>>>,
+}
+
+impl CapturedOtps {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(&self, email: &str, code: u32) {
+        let email = email.trim().to_lowercase();
+        let captured = CapturedOtp {
+            email: email.clone(),
+            code,
+            sent_at: Utc::now().timestamp(),
+        };
+        self.codes.lock().unwrap().insert(email, captured);
+    }
+
+    pub fn latest(&self, email: &str) -> Option<CapturedOtp> {
+        self.codes
+            .lock()
+            .unwrap()
+            .get(&email.trim().to_lowercase())
+            .cloned()
+    }
+}
+
+/// How OTP codes reach the user. Codes never appear in API responses.
+#[derive(Clone)]
+pub enum OtpDelivery {
+    /// Send the code by email. The only mode allowed in production.
+    Email,
+    /// Keep the code in memory for `GET /dev/otp`, so local runs and test
+    /// suites work without an inbox.
+    Capture(CapturedOtps),
+}
+
+impl OtpDelivery {
+    pub fn captured(&self) -> Option<&CapturedOtps> {
+        match self {
+            OtpDelivery::Capture(captured) => Some(captured),
+            OtpDelivery::Email => None,
+        }
+    }
+
+    pub async fn deliver(
+        &self,
+        mail: &MailService,
+        email: &str,
+        locale: Option<&str>,
+        code: u32,
+    ) -> Result<(), EmailError> {
+        match self {
+            OtpDelivery::Email => {
+                let confirmation = EmailConfirmation {
+                    otp_code: code.to_string(),
+                    expires_in_minutes: OTP_TTL_MINUTES,
+                };
+                mail.send(email, locale, &confirmation).await
+            }
+            OtpDelivery::Capture(captured) => {
+                captured.record(email, code);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Validate)]
 pub struct Otp {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
     #[validate(email)]
     pub email: String,
-    pub code: u32,
+    pub code_hash: String,
+    /// Wrong guesses so far; the code stops working at [`OTP_MAX_ATTEMPTS`].
+    #[serde(default)]
+    pub attempts: u32,
     pub is_used: Option<bool>,
     pub created_at: Option<u64>,
-    pub expired_at: Option<u64>,
+    /// A BSON date so the TTL index can purge stale codes.
+    pub expired_at: DateTime,
 }
 
 impl Otp {
     fn generate_code() -> u32 {
         let mut rng = rand::thread_rng();
-        return rng.gen_range(100000..=999999);
+        rng.gen_range(100000..=999999)
     }
 
-    fn util_time(mins: u64) -> Result<(u64, u64), OtpError> {
-        let current_time = Utc::now().timestamp() as u64;
-        let expired_at =
-            current_time + Duration::try_minutes(mins as i64).unwrap().num_seconds() as u64;
-
-        if expired_at < current_time {
-            return Err(OtpError::ChronoError(
-                "OtpError: Expired time cannot be less than current time".to_string(),
-            ));
-        }
+    fn util_time(mins: i64) -> Result<(u64, DateTime), OtpError> {
+        let now = Utc::now();
+        let expired_at = Duration::try_minutes(mins)
+            .and_then(|ttl| now.checked_add_signed(ttl))
+            .ok_or_else(|| {
+                OtpError::ChronoError("OtpError: Expired time is out of range".to_string())
+            })?;
 
-        Ok((current_time, expired_at))
+        Ok((
+            now.timestamp() as u64,
+            DateTime::from_millis(expired_at.timestamp_millis()),
+        ))
     }
 
-    pub fn new(email: String) -> Result<Self, OtpError> {
-        let (current_time, expired_at) = Self::util_time(10).unwrap();
+    /// Creates an OTP for `email` and returns it with the plaintext code,
+    /// which is only ever sent to the user.
+    pub fn new(email: String, hasher: &OtpHasher) -> Result<(Self, u32), OtpError> {
+        let (current_time, expired_at) = Self::util_time(OTP_TTL_MINUTES)?;
         let email = email.to_lowercase().trim().to_string();
+        let code = Self::generate_code();
 
         let otp = Otp {
+            id: None,
+            code_hash: hasher.hash(&email, code),
             email,
-            code: Self::generate_code(),
+            attempts: 0,
             is_used: Some(false),
             created_at: Some(current_time),
-            expired_at: Some(expired_at),
+            expired_at,
         };
 
         match otp.validate() {
-            Ok(_) => Ok(otp),
+            Ok(_) => Ok((otp, code)),
             Err(e) => Err(OtpError::ValidationError(e.to_string())),
         }
     }
 
-    pub async fn insert_otp(&self, db: &Database) -> Result<(), OtpError> {
-        let collection: Collection<Otp> = db.collection("otp");
-        match collection.insert_one(self, None).await {
-            Ok(_) => Ok(()),
-            Err(e) => return Err(OtpError::MongoError(e)),
-        }
+    pub async fn insert_otp(&self, otps: &dyn OtpRepository) -> Result<(), OtpError> {
+        otps.insert(self).await.map_err(OtpError::RepositoryError)
     }
 
-    pub async fn verify_otp(code: u32, email: String, db: &Database) -> Result<bool, OtpError> {
-        let current_time = Utc::now().timestamp() as i64;
+    /// Checks a code. Every check counts as an attempt before the comparison,
+    /// so concurrent guesses cannot exceed [`OTP_MAX_ATTEMPTS`].
+    pub async fn verify_otp(
+        code: u32,
+        email: String,
+        hasher: &OtpHasher,
+        otps: &dyn OtpRepository,
+    ) -> Result<bool, OtpError> {
         let email = email.to_lowercase().trim().to_string();
-
-        let collection: Collection<Otp> = db.collection("otp");
-        let filter = doc! {
-            "email": email.clone(),
-            "code": code,
-            "is_used": false,
-            "expired_at": { "$gt": current_time },
+        let otp = match otps.begin_attempt(&email).await {
+            Ok(Some(otp)) => otp,
+            Ok(None) => return Ok(false),
+            Err(e) => return Err(OtpError::RepositoryError(e)),
         };
-        let update = doc! { "$set": { "is_used": true }};
-
-        match collection.update_one(filter, update, None).await {
-            Ok(update_result) => {
-                if update_result.modified_count == 1 {
-                    Ok(true)
-                } else {
-                    Ok(false)
-                }
-            }
-            Err(e) => return Err(OtpError::MongoError(e)),
+
+        if !hasher.verify(&email, code, &otp.code_hash) {
+            return Ok(false);
+        }
+
+        match otp.id {
+            Some(id) => otps.mark_used(id).await.map_err(OtpError::RepositoryError),
+            None => Ok(false),
         }
     }
 
-    pub async fn update_otp(email: String, db: &Database) -> Result<(String, u32), OtpError> {
-        let collection: Collection<Otp> = db.collection("otp");
-        let (_, expired_at) = Self::util_time(10).unwrap();
+    /// Replaces the unused code for `email`, resetting its attempts and
+    /// expiry. Fails with [`RepositoryError::NotFound`] when there is none.
+    pub async fn update_otp(
+        email: String,
+        hasher: &OtpHasher,
+        otps: &dyn OtpRepository,
+    ) -> Result<(String, u32), OtpError> {
+        let (_, expired_at) = Self::util_time(OTP_TTL_MINUTES)?;
         let code = Self::generate_code();
-        let filter = doc! { "email": email.clone() };
-        let update = doc! {
-            "$set": {
-                "is_used": false,
-                "code": code,
-                "expired_at": expired_at as i64,
-            },
-        };
 
-        match collection.update_one(filter, update, None).await {
+        match otps
+            .replace(&email, &hasher.hash(&email, code), expired_at)
+            .await
+        {
             Ok(_) => Ok((email, code)),
-            Err(e) => Err(OtpError::MongoError(e)),
+            Err(e) => Err(OtpError::RepositoryError(e)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::{session::MemorySessionStore, Repositories};
+    use crate::services::crypto::SecretBox;
+    use crate::services::outbox::Outbox;
+    use crate::services::templates::{TemplateRegistry, TEMPLATE_DIR};
+
+    #[test]
+    fn test_hash_is_keyed_and_bound_to_email() {
+        let hasher = OtpHasher::derive(&[1u8; 32]);
+        let code_hash = hasher.hash("user@example.com", 123456);
+
+        assert!(hasher.verify("user@example.com", 123456, &code_hash));
+        assert!(!hasher.verify("user@example.com", 123457, &code_hash));
+        assert!(!hasher.verify("other@example.com", 123456, &code_hash));
+        assert!(!OtpHasher::derive(&[2u8; 32]).verify("user@example.com", 123456, &code_hash));
+    }
+
+    #[actix_web::test]
+    async fn test_capture_delivery_keeps_latest_code() {
+        let repos = Repositories::memory(MemorySessionStore::new());
+        let mail = MailService::new(
+            TemplateRegistry::load(TEMPLATE_DIR, false).unwrap(),
+            Outbox::new(
+                repos.outbox.clone(),
+                "auth-rs <no-reply@localhost>".parse().unwrap(),
+                SecretBox::new(&[1u8; 32]).unwrap(),
+            ),
+            repos.suppressions.clone(),
+        );
+        let delivery = OtpDelivery::Capture(CapturedOtps::new());
+        delivery
+            .deliver(&mail, "User@example.com", None, 111111)
+            .await
+            .unwrap();
+        delivery
+            .deliver(&mail, "user@example.com", None, 222222)
+            .await
+            .unwrap();
+
+        let captured = delivery.captured().unwrap();
+        assert_eq!(captured.latest("user@example.com").unwrap().code, 222222);
+        assert!(captured.latest("other@example.com").is_none());
+        assert!(OtpDelivery::Email.captured().is_none());
+    }
+}
@@ -0,0 +1,438 @@
+use crate::repositories::{outbox::OutboxRepository, RepositoryError};
+use crate::services::crypto::SecretBox;
+use crate::services::mail::Mailer;
+use chrono::{Duration, Utc};
+use lettre::address::Envelope;
+use lettre::message::{Mailbox, Message};
+use lettre::Address;
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+use tracing::Instrument;
+
+/// Deliveries tried before a message is dead-lettered.
+pub const OUTBOX_MAX_ATTEMPTS: u32 = 8;
+const RETRY_BASE_SECS: i64 = 30;
+const RETRY_MAX_SECS: i64 = 3600;
+/// How long a claimed message is held before another worker may retry it.
+const CLAIM_LEASE_SECS: i64 = 300;
+const POLL_INTERVAL_SECS: u64 = 2;
+const SENT_RETENTION_DAYS: i64 = 7;
+const DEAD_RETENTION_DAYS: i64 = 30;
+
+#[derive(Debug)]
+pub enum OutboxError {
+    RepositoryError(RepositoryError),
+    InvalidMessage(String),
+    CryptoError(String),
+}
+
+impl Display for OutboxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            OutboxError::RepositoryError(e) => write!(f, "RepositoryError: {}", e),
+            OutboxError::InvalidMessage(e) => write!(f, "InvalidMessage: {}", e),
+            OutboxError::CryptoError(e) => write!(f, "CryptoError: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OutboxError {}
+
+impl From<RepositoryError> for OutboxError {
+    fn from(err: RepositoryError) -> Self {
+        OutboxError::RepositoryError(err)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutboxStatus {
+    Pending,
+    Sending,
+    Sent,
+    /// Gave up after [`OUTBOX_MAX_ATTEMPTS`]; kept with the message itself,
+    /// for inspection, until purged by the TTL index.
+    Dead,
+}
+
+/// A rendered email waiting for delivery.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OutboxMessage {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub from: String,
+    pub to: Vec<String>,
+    pub subject: String,
+    /// The formatted RFC 5322 message, encrypted with `ENCRYPTION_KEY` and
+    /// bound to the message id. Removed once the message is sent; a dead
+    /// message keeps it until it expires.
+    #[serde(default)]
+    pub raw: String,
+    /// False for messages queued before `raw` was encrypted.
+    #[serde(default)]
+    pub encrypted: bool,
+    pub status: OutboxStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: DateTime,
+    pub created_at: DateTime,
+    pub sent_at: Option<DateTime>,
+    /// Set once sent or dead so the message is purged by the TTL index.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime>,
+}
+
+/// Delay before retrying after `attempts` failed deliveries.
+pub fn retry_delay(attempts: u32) -> Duration {
+    let secs = RETRY_BASE_SECS.saturating_mul(1 << attempts.saturating_sub(1).min(20));
+    Duration::try_seconds(secs.min(RETRY_MAX_SECS)).unwrap()
+}
+
+fn after(delay: Duration) -> DateTime {
+    DateTime::from_millis((Utc::now() + delay).timestamp_millis())
+}
+
+impl OutboxMessage {
+    fn raw(&self, cipher: &SecretBox) -> Result<Vec<u8>, OutboxError> {
+        if !self.encrypted {
+            return Ok(self.raw.as_bytes().to_vec());
+        }
+        cipher
+            .decrypt(&self.raw, self.id()?.to_hex().as_bytes())
+            .map_err(|e| OutboxError::CryptoError(e.to_string()))
+    }
+
+    fn envelope(&self) -> Result<Envelope, OutboxError> {
+        let parse = |address: &str| {
+            address
+                .parse::<Address>()
+                .map_err(|err| OutboxError::InvalidMessage(err.to_string()))
+        };
+        let to = self
+            .to
+            .iter()
+            .map(|address| parse(address))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Envelope::new(Some(parse(&self.from)?), to)
+            .map_err(|err| OutboxError::InvalidMessage(err.to_string()))
+    }
+
+    fn id(&self) -> Result<ObjectId, OutboxError> {
+        self.id
+            .ok_or_else(|| OutboxError::InvalidMessage("message has no id".into()))
+    }
+
+    async fn mark_sent(&self, messages: &dyn OutboxRepository) -> Result<(), OutboxError> {
+        messages
+            .mark_sent(
+                self.id()?,
+                self.attempts,
+                after(Duration::try_days(SENT_RETENTION_DAYS).unwrap()),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn mark_failed(
+        &self,
+        messages: &dyn OutboxRepository,
+        error: &str,
+    ) -> Result<(), OutboxError> {
+        let id = self.id()?;
+        if self.attempts >= OUTBOX_MAX_ATTEMPTS {
+            messages
+                .mark_dead(
+                    id,
+                    self.attempts,
+                    error,
+                    after(Duration::try_days(DEAD_RETENTION_DAYS).unwrap()),
+                )
+                .await?;
+        } else {
+            messages
+                .retry(id, self.attempts, error, after(retry_delay(self.attempts)))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Queues outgoing email in MongoDB. Handlers only ever enqueue, so a mail
+/// outage cannot fail a request; [`run_worker`] does the delivery.
+#[derive(Clone)]
+pub struct Outbox {
+    messages: Arc<dyn OutboxRepository>,
+    from: Mailbox,
+    cipher: SecretBox,
+}
+
+impl Outbox {
+    pub fn new(messages: Arc<dyn OutboxRepository>, from: Mailbox, cipher: SecretBox) -> Self {
+        Self {
+            messages,
+            from,
+            cipher,
+        }
+    }
+
+    /// Address used in the `From` header of every message.
+    pub fn sender(&self) -> &Mailbox {
+        &self.from
+    }
+
+    pub async fn enqueue(&self, message: &Message) -> Result<ObjectId, OutboxError> {
+        let envelope = message.envelope();
+        let id = ObjectId::new();
+        let raw = self
+            .cipher
+            .encrypt(&message.formatted(), id.to_hex().as_bytes())
+            .map_err(|e| OutboxError::CryptoError(e.to_string()))?;
+        let outbox_message = OutboxMessage {
+            id: Some(id),
+            from: envelope
+                .from()
+                .map(|address| address.to_string())
+                .unwrap_or_else(|| self.from.email.to_string()),
+            to: envelope
+                .to()
+                .iter()
+                .map(|address| address.to_string())
+                .collect(),
+            subject: message
+                .headers()
+                .get_raw("Subject")
+                .unwrap_or_default()
+                .to_string(),
+            raw,
+            encrypted: true,
+            status: OutboxStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            next_attempt_at: DateTime::now(),
+            created_at: DateTime::now(),
+            sent_at: None,
+            expires_at: None,
+        };
+
+        self.messages.insert(&outbox_message).await?;
+        Ok(id)
+    }
+}
+
+async fn deliver(
+    messages: &dyn OutboxRepository,
+    mailer: &dyn Mailer,
+    cipher: &SecretBox,
+    message: &OutboxMessage,
+) {
+    let result = match message
+        .envelope()
+        .and_then(|envelope| Ok((envelope, message.raw(cipher)?)))
+    {
+        Ok((envelope, raw)) => mailer
+            .send(&envelope, &raw)
+            .await
+            .map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+
+    let recorded = match result {
+        Ok(_) => message.mark_sent(messages).await,
+        Err(err) => {
+            tracing::warn!(error = %err, "Error delivering email");
+            message.mark_failed(messages, &err).await
+        }
+    };
+    if let Err(err) = recorded {
+        tracing::error!(error = %err, "Error updating outbox message");
+    }
+}
+
+/// Delivers queued messages until the process exits, polling when the queue
+/// is empty.
+pub async fn run_worker(
+    messages: Arc<dyn OutboxRepository>,
+    mailer: Arc<dyn Mailer>,
+    cipher: SecretBox,
+) {
+    let mut interval = actix_web::rt::time::interval(StdDuration::from_secs(POLL_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        loop {
+            // Claimed messages are leased rather than locked, so one left
+            // behind by a crashed worker is retried later.
+            let lease_until = after(Duration::try_seconds(CLAIM_LEASE_SECS).unwrap());
+            match messages.claim(lease_until).await {
+                Ok(Some(message)) => {
+                    let span = tracing::info_span!(
+                        "outbox",
+                        message_id = ?message.id,
+                        attempt = message.attempts
+                    );
+                    deliver(messages.as_ref(), mailer.as_ref(), &cipher, &message)
+                        .instrument(span)
+                        .await
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    tracing::error!(error = %err, "Error claiming outbox message");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::outbox::MemoryOutboxRepository;
+    use crate::services::mail::MemoryMailer;
+
+    #[test]
+    fn test_retry_delay_backs_off_to_cap() {
+        assert_eq!(retry_delay(1).num_seconds(), 30);
+        assert_eq!(retry_delay(2).num_seconds(), 60);
+        assert_eq!(retry_delay(4).num_seconds(), 240);
+        assert_eq!(retry_delay(OUTBOX_MAX_ATTEMPTS).num_seconds(), 3600);
+        assert_eq!(retry_delay(64).num_seconds(), 3600);
+    }
+
+    #[test]
+    fn test_raw_is_bound_to_message_id() {
+        let cipher = SecretBox::new(&[1u8; 32]).unwrap();
+        let id = ObjectId::new();
+        let mut message = OutboxMessage {
+            id: Some(id),
+            from: "no-reply@localhost".to_string(),
+            to: vec!["user@example.com".to_string()],
+            subject: "Verify your email".to_string(),
+            raw: cipher
+                .encrypt(b"Subject: hi", id.to_hex().as_bytes())
+                .unwrap(),
+            encrypted: true,
+            status: OutboxStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            next_attempt_at: DateTime::now(),
+            created_at: DateTime::now(),
+            sent_at: None,
+            expires_at: None,
+        };
+        assert_eq!(message.raw(&cipher).unwrap(), b"Subject: hi");
+
+        message.id = Some(ObjectId::new());
+        assert!(message.raw(&cipher).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_delivered_message_drops_its_contents() {
+        let cipher = SecretBox::new(&[1u8; 32]).unwrap();
+        let messages = Arc::new(MemoryOutboxRepository::new());
+        let outbox = Outbox::new(
+            messages.clone(),
+            "auth-rs <no-reply@localhost>".parse().unwrap(),
+            cipher.clone(),
+        );
+        let message = Message::builder()
+            .from(outbox.sender().clone())
+            .to("user@example.com".parse().unwrap())
+            .subject("Verify your email")
+            .body("123456".to_string())
+            .unwrap();
+        let id = outbox.enqueue(&message).await.unwrap();
+
+        let lease_until = after(Duration::try_seconds(CLAIM_LEASE_SECS).unwrap());
+        let claimed = messages.claim(lease_until).await.unwrap().unwrap();
+        assert_eq!(claimed.attempts, 1);
+        assert!(messages.claim(lease_until).await.unwrap().is_none());
+
+        let mailer = MemoryMailer::new();
+        deliver(messages.as_ref(), &mailer, &cipher, &claimed).await;
+        assert_eq!(mailer.messages()[0].subject, "Verify your email");
+
+        let sent = messages.find(id).await.unwrap().unwrap();
+        assert_eq!(sent.status, OutboxStatus::Sent);
+        assert!(sent.raw.is_empty());
+        assert!(sent.expires_at.is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_dead_message_keeps_its_contents() {
+        let cipher = SecretBox::new(&[1u8; 32]).unwrap();
+        let messages = Arc::new(MemoryOutboxRepository::new());
+        let outbox = Outbox::new(
+            messages.clone(),
+            "auth-rs <no-reply@localhost>".parse().unwrap(),
+            cipher.clone(),
+        );
+        let message = Message::builder()
+            .from(outbox.sender().clone())
+            .to("user@example.com".parse().unwrap())
+            .subject("Verify your email")
+            .body("123456".to_string())
+            .unwrap();
+        let id = outbox.enqueue(&message).await.unwrap();
+
+        // Leases that lapse at once let the message be claimed again straight
+        // away, counting an attempt each time.
+        let mut claimed = None;
+        for _ in 0..OUTBOX_MAX_ATTEMPTS {
+            claimed = messages.claim(DateTime::now()).await.unwrap();
+        }
+        let claimed = claimed.unwrap();
+        assert_eq!(claimed.attempts, OUTBOX_MAX_ATTEMPTS);
+        claimed
+            .mark_failed(messages.as_ref(), "mailbox unavailable")
+            .await
+            .unwrap();
+
+        let dead = messages.find(id).await.unwrap().unwrap();
+        assert_eq!(dead.status, OutboxStatus::Dead);
+        assert!(dead.expires_at.is_some());
+        assert_eq!(dead.raw(&cipher).unwrap(), message.formatted());
+    }
+
+    #[actix_web::test]
+    async fn test_stale_worker_cannot_update_reclaimed_message() {
+        let cipher = SecretBox::new(&[1u8; 32]).unwrap();
+        let messages = Arc::new(MemoryOutboxRepository::new());
+        let outbox = Outbox::new(
+            messages.clone(),
+            "auth-rs <no-reply@localhost>".parse().unwrap(),
+            cipher.clone(),
+        );
+        let message = Message::builder()
+            .from(outbox.sender().clone())
+            .to("user@example.com".parse().unwrap())
+            .subject("Verify your email")
+            .body("123456".to_string())
+            .unwrap();
+        let id = outbox.enqueue(&message).await.unwrap();
+
+        // The first worker's lease lapses and a second worker takes over.
+        let stale = messages.claim(DateTime::now()).await.unwrap().unwrap();
+        let lease_until = after(Duration::try_seconds(CLAIM_LEASE_SECS).unwrap());
+        let current = messages.claim(lease_until).await.unwrap().unwrap();
+        assert_eq!(current.attempts, stale.attempts + 1);
+
+        stale.mark_sent(messages.as_ref()).await.unwrap();
+        stale
+            .mark_failed(messages.as_ref(), "mailbox unavailable")
+            .await
+            .unwrap();
+        let leased = messages.find(id).await.unwrap().unwrap();
+        assert_eq!(leased.status, OutboxStatus::Sending);
+        assert_eq!(leased.next_attempt_at, lease_until);
+        assert!(leased.last_error.is_none());
+        assert!(!leased.raw.is_empty());
+
+        current.mark_sent(messages.as_ref()).await.unwrap();
+        let sent = messages.find(id).await.unwrap().unwrap();
+        assert_eq!(sent.status, OutboxStatus::Sent);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::repositories::{password_reset::PasswordResetRepository, RepositoryError};
+use chrono::{Duration, Utc};
+use mongodb::bson::{oid::ObjectId, DateTime};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const PASSWORD_RESET_TTL_MINUTES: i64 = 30;
+
+/// A password reset link sent by email. Only the hash of the token is
+/// stored, so a database read cannot be turned into an account takeover.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PasswordReset {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub token_hash: String,
+    pub user_id: ObjectId,
+    pub email: String,
+    pub is_used: bool,
+    pub created_at: Option<u64>,
+    pub used_at: Option<u64>,
+    pub expires_at: DateTime,
+}
+
+impl PasswordReset {
+    fn hash(token: &str) -> String {
+        format!("{:x}", Sha256::digest(token.as_bytes()))
+    }
+
+    /// Issues a reset token for the user, retiring any earlier unused ones so
+    /// only the most recent email works.
+    pub async fn create(
+        resets: &dyn PasswordResetRepository,
+        user_id: ObjectId,
+        email: &str,
+    ) -> Result<String, RepositoryError> {
+        resets.delete_unused(user_id).await?;
+
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 48);
+        let now = Utc::now();
+        let reset = PasswordReset {
+            id: None,
+            token_hash: Self::hash(&token),
+            user_id,
+            email: email.to_string(),
+            is_used: false,
+            created_at: Some(now.timestamp() as u64),
+            used_at: None,
+            expires_at: DateTime::from_millis(
+                (now + Duration::try_minutes(PASSWORD_RESET_TTL_MINUTES).unwrap())
+                    .timestamp_millis(),
+            ),
+        };
+
+        resets.insert(&reset).await?;
+        Ok(token)
+    }
+
+    /// Marks a live token used and returns it. A token can be redeemed at
+    /// most once, even by concurrent requests.
+    pub async fn redeem(
+        resets: &dyn PasswordResetRepository,
+        token: &str,
+    ) -> Result<Option<Self>, RepositoryError> {
+        resets.redeem(&Self::hash(token)).await
+    }
+}
@@ -0,0 +1,404 @@
+use crate::db;
+use futures::future::LocalBoxFuture;
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use mongodb::{Collection, Database};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::Mutex;
+use tracing::Instrument;
+
+/// Entries beyond this many trigger a sweep of expired ones in [`MemoryStore`].
+const MEMORY_STORE_SWEEP_LEN: usize = 10_000;
+
+/// How hits against one key are limited.
+#[derive(Clone, Debug)]
+pub enum Strategy {
+    /// Allows bursts of up to `capacity`, refilled at `refill_per_sec`.
+    TokenBucket { capacity: u32, refill_per_sec: f64 },
+    /// Allows `limit` hits in any `window_secs`, estimated from the current
+    /// and previous fixed windows.
+    SlidingWindow { limit: u32, window_secs: u64 },
+}
+
+#[derive(Debug)]
+pub enum RateLimitError {
+    MongoError(mongodb::error::Error),
+}
+
+impl Display for RateLimitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            RateLimitError::MongoError(err) => write!(f, "MongoError: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RateLimitError {}
+
+/// Backing store for rate-limit counters. `hit` counts one request against
+/// `key` and returns the seconds to wait when it is over the limit.
+pub trait RateLimitStore: Send + Sync {
+    fn hit<'a>(
+        &'a self,
+        key: &'a str,
+        strategy: &'a Strategy,
+        now_ms: i64,
+    ) -> LocalBoxFuture<'a, Result<Option<u64>, RateLimitError>>;
+}
+
+fn ceil_secs(ms: f64) -> u64 {
+    ((ms / 1000.0).ceil() as u64).max(1)
+}
+
+fn refill(tokens: f64, updated_ms: i64, capacity: u32, refill_per_sec: f64, now_ms: i64) -> f64 {
+    let elapsed_secs = (now_ms - updated_ms).max(0) as f64 / 1000.0;
+    (tokens + elapsed_secs * refill_per_sec).min(capacity as f64)
+}
+
+fn token_bucket_retry(tokens: f64, refill_per_sec: f64) -> u64 {
+    ceil_secs((1.0 - tokens) / refill_per_sec * 1000.0)
+}
+
+/// Seconds until one more hit fits, given `prev` hits in the previous window
+/// and `curr` in the current one, `elapsed_ms` into it.
+fn sliding_window_retry(
+    prev: u32,
+    curr: u32,
+    elapsed_ms: i64,
+    window_ms: i64,
+    limit: u32,
+) -> Option<u64> {
+    let (prev, curr, elapsed, window, limit) = (
+        prev as f64,
+        curr as f64,
+        elapsed_ms as f64,
+        window_ms as f64,
+        limit as f64,
+    );
+    if prev * (window - elapsed) / window + curr + 1.0 <= limit {
+        return None;
+    }
+
+    let wait_ms = if curr + 1.0 > limit {
+        // Only the next window has room; by then this window is the previous
+        // one and its weight has to decay enough.
+        let decay = if curr > 0.0 {
+            (window * (1.0 - (limit - 1.0) / curr)).max(0.0)
+        } else {
+            0.0
+        };
+        window - elapsed + decay
+    } else {
+        window - elapsed - window * (limit - curr - 1.0) / prev
+    };
+    Some(ceil_secs(wait_ms))
+}
+
+enum Entry {
+    Bucket { tokens: f64, updated_ms: i64 },
+    Window { index: i64, prev: u32, curr: u32 },
+}
+
+/// Counters held in process memory. Suits a single instance; counters are
+/// lost on restart and not shared between instances.
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, (Entry, i64)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn hit_sync(&self, key: &str, strategy: &Strategy, now_ms: i64) -> Option<u64> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() > MEMORY_STORE_SWEEP_LEN {
+            entries.retain(|_, (_, expires_ms)| *expires_ms > now_ms);
+        }
+
+        match strategy {
+            Strategy::TokenBucket {
+                capacity,
+                refill_per_sec,
+            } => {
+                let expires_ms = now_ms + (*capacity as f64 / refill_per_sec * 1000.0) as i64;
+                let (entry, entry_expires_ms) = entries.entry(key.to_string()).or_insert((
+                    Entry::Bucket {
+                        tokens: *capacity as f64,
+                        updated_ms: now_ms,
+                    },
+                    expires_ms,
+                ));
+                let tokens = match entry {
+                    Entry::Bucket { tokens, updated_ms } => {
+                        refill(*tokens, *updated_ms, *capacity, *refill_per_sec, now_ms)
+                    }
+                    Entry::Window { .. } => *capacity as f64,
+                };
+                let (tokens, retry_after) = if tokens >= 1.0 {
+                    (tokens - 1.0, None)
+                } else {
+                    (tokens, Some(token_bucket_retry(tokens, *refill_per_sec)))
+                };
+                *entry = Entry::Bucket {
+                    tokens,
+                    updated_ms: now_ms,
+                };
+                *entry_expires_ms = expires_ms;
+                retry_after
+            }
+            Strategy::SlidingWindow { limit, window_secs } => {
+                let window_ms = (*window_secs * 1000) as i64;
+                let index = now_ms / window_ms;
+                let (entry, entry_expires_ms) = entries.entry(key.to_string()).or_insert((
+                    Entry::Window {
+                        index,
+                        prev: 0,
+                        curr: 0,
+                    },
+                    0,
+                ));
+                let (prev, curr) = match entry {
+                    Entry::Window {
+                        index: i,
+                        prev,
+                        curr,
+                    } if *i == index => (*prev, *curr),
+                    Entry::Window { index: i, curr, .. } if *i == index - 1 => (*curr, 0),
+                    _ => (0, 0),
+                };
+                let retry_after =
+                    sliding_window_retry(prev, curr, now_ms % window_ms, window_ms, *limit);
+                *entry = Entry::Window {
+                    index,
+                    prev,
+                    curr: if retry_after.is_none() {
+                        curr + 1
+                    } else {
+                        curr
+                    },
+                };
+                *entry_expires_ms = (index + 2) * window_ms;
+                retry_after
+            }
+        }
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn hit<'a>(
+        &'a self,
+        key: &'a str,
+        strategy: &'a Strategy,
+        now_ms: i64,
+    ) -> LocalBoxFuture<'a, Result<Option<u64>, RateLimitError>> {
+        let retry_after = self.hit_sync(key, strategy, now_ms);
+        Box::pin(async move { Ok(retry_after) })
+    }
+}
+
+/// Counters in the `rate_limits` collection, shared by every instance.
+/// Each hit is a single atomic update, so concurrent requests cannot both
+/// take the last slot.
+pub struct MongoStore {
+    collection: Collection<Document>,
+}
+
+impl MongoStore {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("rate_limits"),
+        }
+    }
+
+    async fn token_bucket(
+        &self,
+        key: &str,
+        capacity: u32,
+        refill_per_sec: f64,
+        now_ms: i64,
+    ) -> Result<Option<u64>, mongodb::error::Error> {
+        let capacity = capacity as f64;
+        let expires_at =
+            DateTime::from_millis(now_ms + (capacity / refill_per_sec * 1000.0) as i64);
+        // Refill and take in one pipeline update; the second stage sees the
+        // refilled balance.
+        let pipeline = vec![
+            doc! {
+                "$set": {
+                    "tokens": {
+                        "$min": [
+                            capacity,
+                            {
+                                "$add": [
+                                    { "$ifNull": ["$tokens", capacity] },
+                                    {
+                                        "$multiply": [
+                                            {
+                                                "$divide": [
+                                                    { "$subtract": [now_ms, { "$ifNull": ["$updated_ms", now_ms] }] },
+                                                    1000.0,
+                                                ]
+                                            },
+                                            refill_per_sec,
+                                        ]
+                                    },
+                                ]
+                            },
+                        ]
+                    },
+                    "updated_ms": now_ms,
+                    "expires_at": expires_at,
+                }
+            },
+            doc! {
+                "$set": {
+                    "allowed": { "$gte": ["$tokens", 1.0] },
+                    "tokens": {
+                        "$cond": [
+                            { "$gte": ["$tokens", 1.0] },
+                            { "$subtract": ["$tokens", 1.0] },
+                            "$tokens",
+                        ]
+                    },
+                }
+            },
+        ];
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let record = self
+            .collection
+            .find_one_and_update(doc! { "_id": key }, pipeline, options)
+            .instrument(db::span("rate_limits", "find_one_and_update"))
+            .await?
+            .unwrap_or_default();
+
+        if record.get_bool("allowed").unwrap_or(true) {
+            return Ok(None);
+        }
+        let tokens = record.get_f64("tokens").unwrap_or(0.0);
+        Ok(Some(token_bucket_retry(tokens, refill_per_sec)))
+    }
+
+    async fn sliding_window(
+        &self,
+        key: &str,
+        limit: u32,
+        window_secs: u64,
+        now_ms: i64,
+    ) -> Result<Option<u64>, mongodb::error::Error> {
+        let window_ms = (window_secs * 1000) as i64;
+        let index = now_ms / window_ms;
+        let current_id = format!("{}:{}", key, index);
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let current = self
+            .collection
+            .find_one_and_update(
+                doc! { "_id": &current_id },
+                doc! {
+                    "$inc": { "count": 1 },
+                    "$setOnInsert": { "expires_at": DateTime::from_millis((index + 2) * window_ms) },
+                },
+                options,
+            )
+            .instrument(db::span("rate_limits", "find_one_and_update"))
+            .await?
+            .unwrap_or_default();
+        let previous = self
+            .collection
+            .find_one(doc! { "_id": format!("{}:{}", key, index - 1) }, None)
+            .instrument(db::span("rate_limits", "find_one"))
+            .await?
+            .unwrap_or_default();
+
+        let count = |record: &Document| match record.get("count") {
+            Some(mongodb::bson::Bson::Int32(count)) => *count as u32,
+            Some(mongodb::bson::Bson::Int64(count)) => *count as u32,
+            _ => 0,
+        };
+        let retry_after = sliding_window_retry(
+            count(&previous),
+            count(&current).saturating_sub(1),
+            now_ms % window_ms,
+            window_ms,
+            limit,
+        );
+
+        // Rejected hits do not count towards the limit.
+        if retry_after.is_some() {
+            self.collection
+                .update_one(
+                    doc! { "_id": &current_id },
+                    doc! { "$inc": { "count": -1 } },
+                    None,
+                )
+                .instrument(db::span("rate_limits", "update_one"))
+                .await?;
+        }
+        Ok(retry_after)
+    }
+}
+
+impl RateLimitStore for MongoStore {
+    fn hit<'a>(
+        &'a self,
+        key: &'a str,
+        strategy: &'a Strategy,
+        now_ms: i64,
+    ) -> LocalBoxFuture<'a, Result<Option<u64>, RateLimitError>> {
+        Box::pin(async move {
+            match strategy {
+                Strategy::TokenBucket {
+                    capacity,
+                    refill_per_sec,
+                } => {
+                    self.token_bucket(key, *capacity, *refill_per_sec, now_ms)
+                        .await
+                }
+                Strategy::SlidingWindow { limit, window_secs } => {
+                    self.sliding_window(key, *limit, *window_secs, now_ms).await
+                }
+            }
+            .map_err(RateLimitError::MongoError)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_store_strategies() {
+        let store = MemoryStore::new();
+        let now = 1_700_000_000_000;
+
+        let bucket = Strategy::TokenBucket {
+            capacity: 2,
+            refill_per_sec: 0.5,
+        };
+        assert_eq!(store.hit_sync("bucket", &bucket, now), None);
+        assert_eq!(store.hit_sync("bucket", &bucket, now), None);
+        assert_eq!(store.hit_sync("bucket", &bucket, now), Some(2));
+        assert_eq!(store.hit_sync("bucket", &bucket, now + 2_000), None);
+
+        let window = Strategy::SlidingWindow {
+            limit: 2,
+            window_secs: 60,
+        };
+        let start = now - now % 60_000;
+        assert_eq!(store.hit_sync("window", &window, start), None);
+        assert_eq!(store.hit_sync("window", &window, start + 1_000), None);
+        assert_eq!(store.hit_sync("window", &window, start + 2_000), Some(88));
+        // Halfway into the next window the previous two hits weigh one.
+        assert_eq!(store.hit_sync("window", &window, start + 90_000), None);
+        assert!(store.hit_sync("window", &window, start + 90_000).is_some());
+    }
+}
@@ -0,0 +1,56 @@
+use crate::repositories::security_event::SecurityEventRepository;
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityEventKind {
+    AccountLocked,
+    IpLocked,
+    AccountUnlocked,
+}
+
+/// An audit record for ops. Events are logged and stored in the
+/// `security_events` collection, where they can be queried by kind and time.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SecurityEvent {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub kind: SecurityEventKind,
+    pub email: Option<String>,
+    pub user_id: Option<ObjectId>,
+    pub ip: Option<String>,
+    /// The user who caused the event, when it was not the account owner.
+    pub actor_id: Option<ObjectId>,
+    pub created_at: DateTime,
+}
+
+impl SecurityEvent {
+    pub fn new(kind: SecurityEventKind) -> Self {
+        Self {
+            id: None,
+            kind,
+            email: None,
+            user_id: None,
+            ip: None,
+            actor_id: None,
+            created_at: DateTime::now(),
+        }
+    }
+
+    /// Records the event. Failing to store it must not fail the request that
+    /// caused it, so errors are only logged.
+    pub async fn emit(self, events: &dyn SecurityEventRepository) {
+        tracing::info!(
+            kind = ?self.kind,
+            email = ?self.email,
+            ip = ?self.ip,
+            user_id = ?self.user_id,
+            actor_id = ?self.actor_id,
+            "Security event"
+        );
+        if let Err(err) = events.insert(&self).await {
+            tracing::error!(error = %err, "Error storing security event");
+        }
+    }
+}
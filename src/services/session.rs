@@ -0,0 +1,204 @@
+use crate::db;
+use actix_session::config::{CookieContentSecurity, PersistentSession, TtlExtensionPolicy};
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_session::SessionMiddleware;
+use actix_web::cookie::{time::Duration, Key, SameSite};
+use chrono::Utc;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::{error::Error, Collection, Database};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tracing::Instrument;
+
+pub const SESSION_COOKIE_NAME: &str = "session_id";
+pub const SESSION_TTL_HOURS: i64 = 24;
+pub const SESSION_USER_KEY: &str = "user_id";
+pub const SESSION_EMAIL_KEY: &str = "email";
+
+/// Server-side record of a session. The cookie only ever carries the opaque
+/// session key; we persist its SHA-256 digest so a database dump cannot be
+/// replayed as live cookies.
+#[derive(Serialize, Deserialize)]
+pub struct SessionRecord {
+    #[serde(rename = "_id")]
+    pub key_hash: String,
+    pub user_id: Option<ObjectId>,
+    pub state: HashMap<String, String>,
+    pub expires_at: DateTime,
+    pub created_at: Option<i64>,
+    pub updated_at: Option<i64>,
+}
+
+#[derive(Clone)]
+pub struct MongoSessionStore {
+    collection: Collection<SessionRecord>,
+}
+
+impl MongoSessionStore {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("sessions"),
+        }
+    }
+
+    /// Destroys every server-side session owned by `user_id`.
+    pub async fn delete_for_user(&self, user_id: ObjectId) -> Result<u64, Error> {
+        let result = self
+            .collection
+            .delete_many(doc! { "user_id": user_id }, None)
+            .instrument(db::span("sessions", "delete_many"))
+            .await?;
+        Ok(result.deleted_count)
+    }
+}
+
+pub(crate) fn hash_key(session_key: &str) -> String {
+    format!("{:x}", Sha256::digest(session_key.as_bytes()))
+}
+
+pub(crate) fn expires_at(ttl: &Duration) -> DateTime {
+    DateTime::from_millis(Utc::now().timestamp_millis() + ttl.whole_milliseconds() as i64)
+}
+
+/// The user a session belongs to, once it is logged in.
+pub(crate) fn user_id(state: &HashMap<String, String>) -> Option<ObjectId> {
+    state
+        .get(SESSION_USER_KEY)
+        .and_then(|value| serde_json::from_str::<String>(value).ok())
+        .and_then(|value| ObjectId::parse_str(value).ok())
+}
+
+pub(crate) fn generate_key() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 64)
+}
+
+impl SessionStore for MongoSessionStore {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        let filter = doc! {
+            "_id": hash_key(session_key.as_ref()),
+            "expires_at": { "$gt": DateTime::now() },
+        };
+
+        match self
+            .collection
+            .find_one(filter, None)
+            .instrument(db::span("sessions", "find_one"))
+            .await
+        {
+            Ok(record) => Ok(record.map(|record| record.state)),
+            Err(err) => Err(LoadError::Other(err.into())),
+        }
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let session_key = generate_key();
+        let current_time = Utc::now().timestamp();
+        let record = SessionRecord {
+            key_hash: hash_key(&session_key),
+            user_id: user_id(&session_state),
+            state: session_state,
+            expires_at: expires_at(ttl),
+            created_at: Some(current_time),
+            updated_at: Some(current_time),
+        };
+
+        if let Err(err) = self
+            .collection
+            .insert_one(&record, None)
+            .instrument(db::span("sessions", "insert_one"))
+            .await
+        {
+            return Err(SaveError::Other(err.into()));
+        }
+
+        SessionKey::try_from(session_key).map_err(|err| SaveError::Other(err.into()))
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let state = mongodb::bson::to_bson(&session_state)
+            .map_err(|err| UpdateError::Serialization(err.into()))?;
+        let filter = doc! { "_id": hash_key(session_key.as_ref()) };
+        let update = doc! {
+            "$set": {
+                "user_id": user_id(&session_state),
+                "state": state,
+                "expires_at": expires_at(ttl),
+                "updated_at": Utc::now().timestamp(),
+            }
+        };
+
+        match self
+            .collection
+            .update_one(filter, update, None)
+            .instrument(db::span("sessions", "update_one"))
+            .await
+        {
+            Ok(result) if result.matched_count == 1 => Ok(session_key),
+            // The record expired or was revoked between load and update, so
+            // start over with a fresh key rather than resurrecting it.
+            Ok(_) => self
+                .save(session_state, ttl)
+                .await
+                .map_err(|err| UpdateError::Other(err.into())),
+            Err(err) => Err(UpdateError::Other(err.into())),
+        }
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &Duration,
+    ) -> Result<(), anyhow::Error> {
+        let filter = doc! { "_id": hash_key(session_key.as_ref()) };
+        let update = doc! { "$set": { "expires_at": expires_at(ttl) } };
+        self.collection
+            .update_one(filter, update, None)
+            .instrument(db::span("sessions", "update_one"))
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        let filter = doc! { "_id": hash_key(session_key.as_ref()) };
+        self.collection
+            .delete_one(filter, None)
+            .instrument(db::span("sessions", "delete_one"))
+            .await?;
+        Ok(())
+    }
+}
+
+/// Builds the session middleware: a signed, HTTP-only cookie holding the
+/// session key, with a sliding expiry renewed on every request.
+pub fn session_middleware<S: SessionStore>(
+    store: S,
+    key: Key,
+    secure: bool,
+) -> SessionMiddleware<S> {
+    SessionMiddleware::builder(store, key)
+        .cookie_name(SESSION_COOKIE_NAME.to_string())
+        .cookie_secure(secure)
+        .cookie_http_only(true)
+        .cookie_same_site(SameSite::Lax)
+        .cookie_content_security(CookieContentSecurity::Signed)
+        .session_lifecycle(
+            PersistentSession::default()
+                .session_ttl(Duration::hours(SESSION_TTL_HOURS))
+                .session_ttl_extension_policy(TtlExtensionPolicy::OnEveryRequest),
+        )
+        .build()
+}
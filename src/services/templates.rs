@@ -0,0 +1,192 @@
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::{Arc, RwLock};
+use tera::{Context, Tera};
+
+pub const TEMPLATE_DIR: &str = "src/templates";
+pub const DEFAULT_LOCALE: &str = "en";
+
+#[derive(Debug)]
+pub enum TemplateError {
+    LoadError(String),
+    NotFound(String),
+    RenderError(String),
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            TemplateError::LoadError(e) => write!(f, "LoadError: {}", e),
+            TemplateError::NotFound(e) => write!(f, "NotFound: {}", e),
+            TemplateError::RenderError(e) => write!(f, "RenderError: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// The parts of an email rendered from one template set.
+#[derive(Debug)]
+pub struct RenderedEmail {
+    pub subject: String,
+    pub text: String,
+    /// `None` for text-only templates.
+    pub html: Option<String>,
+}
+
+/// Email templates parsed once at startup, laid out as
+/// `<dir>/<locale>/<name>.{subject,txt,html}`.
+#[derive(Clone)]
+pub struct TemplateRegistry {
+    tera: Arc<RwLock<Tera>>,
+    locales: Arc<BTreeSet<String>>,
+    /// Re-read templates from disk before each render, for development.
+    hot_reload: bool,
+}
+
+fn tera_error(err: tera::Error) -> String {
+    // Tera puts the useful part (the failing line) in the error source.
+    match std::error::Error::source(&err) {
+        Some(source) => format!("{}: {}", err, source),
+        None => err.to_string(),
+    }
+}
+
+impl TemplateRegistry {
+    pub fn load(dir: &str, hot_reload: bool) -> Result<Self, TemplateError> {
+        let tera = Tera::new(&format!("{}/**/*", dir.trim_end_matches('/')))
+            .map_err(|err| TemplateError::LoadError(tera_error(err)))?;
+        let locales = tera
+            .get_template_names()
+            .filter_map(|name| name.split_once('/').map(|(locale, _)| locale.to_string()))
+            .collect::<BTreeSet<_>>();
+        if !locales.contains(DEFAULT_LOCALE) {
+            return Err(TemplateError::LoadError(format!(
+                "no {} templates in {}",
+                DEFAULT_LOCALE, dir
+            )));
+        }
+
+        Ok(Self {
+            tera: Arc::new(RwLock::new(tera)),
+            locales: Arc::new(locales),
+            hot_reload,
+        })
+    }
+
+    pub fn locales(&self) -> impl Iterator<Item = &str> {
+        self.locales.iter().map(String::as_str)
+    }
+
+    /// The supported locale for a language tag such as `ja-JP`, if any.
+    pub fn supported(&self, tag: &str) -> Option<&str> {
+        let language = tag.trim().split(['-', '_']).next()?.to_lowercase();
+        self.locales().find(|locale| *locale == language)
+    }
+
+    /// Picks the first supported language from an `Accept-Language` header,
+    /// honouring `q` weights.
+    pub fn negotiate(&self, accept_language: &str) -> Option<&str> {
+        let mut tags = accept_language
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let tag = parts.next()?.trim();
+                let q = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+                (q > 0.0).then_some((tag, q))
+            })
+            .collect::<Vec<_>>();
+        tags.sort_by(|a, b| b.1.total_cmp(&a.1));
+        tags.into_iter().find_map(|(tag, _)| self.supported(tag))
+    }
+
+    fn has(tera: &Tera, name: &str) -> bool {
+        tera.get_template_names().any(|template| template == name)
+    }
+
+    /// Renders template set `name` in `locale`, falling back to
+    /// [`DEFAULT_LOCALE`] when the locale or the set is missing. `subject` is
+    /// available to the bodies.
+    pub fn render(
+        &self,
+        locale: Option<&str>,
+        name: &str,
+        context: &Context,
+    ) -> Result<RenderedEmail, TemplateError> {
+        if self.hot_reload {
+            self.tera
+                .write()
+                .unwrap()
+                .full_reload()
+                .map_err(|err| TemplateError::LoadError(tera_error(err)))?;
+        }
+        let tera = self.tera.read().unwrap();
+
+        let locale = locale
+            .and_then(|locale| self.supported(locale))
+            .filter(|locale| Self::has(&tera, &format!("{}/{}.subject", locale, name)))
+            .unwrap_or(DEFAULT_LOCALE);
+        let path = |extension: &str| format!("{}/{}.{}", locale, name, extension);
+        let render = |template: &str, context: &Context| {
+            tera.render(template, context)
+                .map_err(|err| TemplateError::RenderError(tera_error(err)))
+        };
+
+        if !Self::has(&tera, &path("subject")) || !Self::has(&tera, &path("txt")) {
+            return Err(TemplateError::NotFound(path("{subject,txt}")));
+        }
+        let subject = render(&path("subject"), context)?.trim().to_string();
+
+        let mut context = context.clone();
+        context.insert("subject", &subject);
+        let text = render(&path("txt"), &context)?;
+        let html = if Self::has(&tera, &path("html")) {
+            Some(render(&path("html"), &context)?)
+        } else {
+            None
+        };
+
+        Ok(RenderedEmail {
+            subject,
+            text,
+            html,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_picks_locale_with_fallback() {
+        let registry = TemplateRegistry::load(TEMPLATE_DIR, false).unwrap();
+        let mut context = Context::new();
+        context.insert("otp_code", "123456");
+        context.insert("expires_in_minutes", &10);
+
+        for locale in registry.locales() {
+            let email = registry
+                .render(Some(locale), "confirm_email", &context)
+                .unwrap();
+            assert!(email.text.contains("123456"));
+            assert!(email.html.unwrap().contains(&email.subject));
+        }
+
+        let en = registry.render(None, "confirm_email", &context).unwrap();
+        let fallback = registry
+            .render(Some("fr-FR"), "confirm_email", &context)
+            .unwrap();
+        assert_eq!(en.subject, fallback.subject);
+        let es = registry
+            .render(Some("es-MX"), "confirm_email", &context)
+            .unwrap();
+        assert_ne!(en.subject, es.subject);
+
+        assert_eq!(registry.negotiate("fr;q=1, ja;q=0.5, es;q=0.8"), Some("es"));
+        assert_eq!(registry.negotiate("ja-JP"), Some("ja"));
+        assert_eq!(registry.negotiate("fr, de"), None);
+    }
+}
@@ -0,0 +1,258 @@
+use crate::repositories::{refresh_token::RefreshTokenRepository, RepositoryError};
+use crate::services::keys::{KeyError, KeyRing};
+use chrono::{Duration, Utc};
+use jsonwebtoken::Validation;
+use mongodb::bson::{oid::ObjectId, DateTime};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use uuid::Uuid;
+
+pub const ACCESS_TOKEN_TTL_MINUTES: i64 = 15;
+pub const REFRESH_TOKEN_TTL_DAYS: i64 = 30;
+const TOKEN_ISSUER: &str = "auth-rs";
+
+#[derive(Debug)]
+pub enum TokenError {
+    KeyError(KeyError),
+    RepositoryError(RepositoryError),
+    InvalidToken,
+    ReuseDetected,
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            TokenError::KeyError(e) => write!(f, "KeyError: {}", e),
+            TokenError::RepositoryError(e) => write!(f, "RepositoryError: {}", e),
+            TokenError::InvalidToken => write!(f, "InvalidToken"),
+            TokenError::ReuseDetected => write!(f, "ReuseDetected: refresh token family revoked"),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub email: String,
+    pub iss: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: String,
+}
+
+impl Claims {
+    pub fn issue(keys: &KeyRing, user_id: ObjectId, email: &str) -> Result<String, TokenError> {
+        let now = Utc::now();
+        let claims = Claims {
+            sub: user_id.to_hex(),
+            email: email.to_string(),
+            iss: TOKEN_ISSUER.to_string(),
+            iat: now.timestamp(),
+            exp: (now + Duration::try_minutes(ACCESS_TOKEN_TTL_MINUTES).unwrap()).timestamp(),
+            jti: Uuid::new_v4().to_string(),
+        };
+
+        keys.encode(&claims).map_err(TokenError::KeyError)
+    }
+
+    pub fn verify(keys: &KeyRing, token: &str) -> Result<Claims, TokenError> {
+        let mut validation = Validation::default();
+        validation.set_issuer(&[TOKEN_ISSUER]);
+
+        keys.decode::<Claims>(token, validation)
+            .map_err(TokenError::KeyError)
+    }
+}
+
+/// A persisted refresh token. Tokens are single use: each refresh marks the
+/// presented token as used and issues a successor in the same family, so a
+/// second presentation of a used token reveals theft and revokes the family.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RefreshToken {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub token_hash: String,
+    pub family_id: String,
+    pub user_id: ObjectId,
+    pub email: String,
+    pub is_used: bool,
+    pub is_revoked: bool,
+    pub created_at: Option<u64>,
+    pub expires_at: DateTime,
+}
+
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+}
+
+impl RefreshToken {
+    fn hash(token: &str) -> String {
+        format!("{:x}", Sha256::digest(token.as_bytes()))
+    }
+
+    /// Persists a new refresh token and returns its plaintext value, which is
+    /// only ever handed to the client.
+    async fn insert(
+        tokens: &dyn RefreshTokenRepository,
+        user_id: ObjectId,
+        email: &str,
+        family_id: String,
+    ) -> Result<String, TokenError> {
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 64);
+        let now = Utc::now();
+        let record = RefreshToken {
+            id: None,
+            token_hash: Self::hash(&token),
+            family_id,
+            user_id,
+            email: email.to_string(),
+            is_used: false,
+            is_revoked: false,
+            created_at: Some(now.timestamp() as u64),
+            expires_at: DateTime::from_millis(
+                (now + Duration::try_days(REFRESH_TOKEN_TTL_DAYS).unwrap()).timestamp_millis(),
+            ),
+        };
+
+        tokens
+            .insert(&record)
+            .await
+            .map_err(TokenError::RepositoryError)?;
+        Ok(token)
+    }
+
+    /// Issues an access token and starts a new refresh token family.
+    pub async fn issue(
+        tokens: &dyn RefreshTokenRepository,
+        keys: &KeyRing,
+        user_id: ObjectId,
+        email: &str,
+    ) -> Result<TokenPair, TokenError> {
+        let refresh_token =
+            Self::insert(tokens, user_id, email, Uuid::new_v4().to_string()).await?;
+
+        Ok(TokenPair {
+            access_token: Claims::issue(keys, user_id, email)?,
+            refresh_token,
+            expires_in: ACCESS_TOKEN_TTL_MINUTES * 60,
+        })
+    }
+
+    /// Exchanges a refresh token for a new pair, revoking the whole family
+    /// if the presented token was already used.
+    pub async fn rotate(
+        tokens: &dyn RefreshTokenRepository,
+        keys: &KeyRing,
+        token: &str,
+    ) -> Result<TokenPair, TokenError> {
+        let token_hash = Self::hash(token);
+        let current = tokens
+            .use_live(&token_hash)
+            .await
+            .map_err(TokenError::RepositoryError)?;
+
+        let current = match current {
+            Some(current) => current,
+            None => {
+                return match tokens
+                    .find(&token_hash)
+                    .await
+                    .map_err(TokenError::RepositoryError)?
+                {
+                    Some(existing) if existing.is_used => {
+                        Self::revoke_family(tokens, &existing.family_id).await?;
+                        Err(TokenError::ReuseDetected)
+                    }
+                    _ => Err(TokenError::InvalidToken),
+                };
+            }
+        };
+
+        let refresh_token =
+            Self::insert(tokens, current.user_id, &current.email, current.family_id).await?;
+
+        Ok(TokenPair {
+            access_token: Claims::issue(keys, current.user_id, &current.email)?,
+            refresh_token,
+            expires_in: ACCESS_TOKEN_TTL_MINUTES * 60,
+        })
+    }
+
+    pub async fn revoke_family(
+        tokens: &dyn RefreshTokenRepository,
+        family_id: &str,
+    ) -> Result<(), TokenError> {
+        tokens
+            .revoke_family(family_id)
+            .await
+            .map_err(TokenError::RepositoryError)
+    }
+
+    pub async fn revoke_for_user(
+        tokens: &dyn RefreshTokenRepository,
+        user_id: ObjectId,
+    ) -> Result<(), TokenError> {
+        tokens
+            .revoke_for_user(user_id)
+            .await
+            .map_err(TokenError::RepositoryError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::refresh_token::MemoryRefreshTokenRepository;
+    use crate::services::crypto::{SecretBox, SECRET_KEY_LEN};
+    use crate::services::keys::SigningKey;
+    use jsonwebtoken::Algorithm;
+
+    #[test]
+    fn test_access_token_round_trip() {
+        let now = Utc::now().timestamp() as u64;
+        let cipher = SecretBox::new(&[5u8; SECRET_KEY_LEN]).unwrap();
+        let key = SigningKey::generate(&cipher, Algorithm::ES256, 1, now).unwrap();
+        let keys = KeyRing::from_keys(cipher.clone(), &[key], now).unwrap();
+        let user_id = ObjectId::new();
+
+        let token = Claims::issue(&keys, user_id, "user@example.com").unwrap();
+        let claims = Claims::verify(&keys, &token).unwrap();
+        assert_eq!(claims.sub, user_id.to_hex());
+        assert_eq!(claims.email, "user@example.com");
+
+        let other = SigningKey::generate(&cipher, Algorithm::ES256, 1, now).unwrap();
+        let other = KeyRing::from_keys(cipher, &[other], now).unwrap();
+        assert!(Claims::verify(&other, &token).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_reused_refresh_token_revokes_family() {
+        let now = Utc::now().timestamp() as u64;
+        let cipher = SecretBox::new(&[5u8; SECRET_KEY_LEN]).unwrap();
+        let key = SigningKey::generate(&cipher, Algorithm::ES256, 1, now).unwrap();
+        let keys = KeyRing::from_keys(cipher, &[key], now).unwrap();
+        let tokens = MemoryRefreshTokenRepository::new();
+
+        let first = RefreshToken::issue(&tokens, &keys, ObjectId::new(), "user@example.com")
+            .await
+            .unwrap();
+        let second = RefreshToken::rotate(&tokens, &keys, &first.refresh_token)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            RefreshToken::rotate(&tokens, &keys, &first.refresh_token).await,
+            Err(TokenError::ReuseDetected)
+        ));
+        assert!(matches!(
+            RefreshToken::rotate(&tokens, &keys, &second.refresh_token).await,
+            Err(TokenError::InvalidToken)
+        ));
+    }
+}
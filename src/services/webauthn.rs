@@ -0,0 +1,737 @@
+use crate::models::user::Passkey;
+use crate::repositories::{webauthn_challenge::WebauthnChallengeRepository, RepositoryError};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use ciborium::Value;
+use mongodb::bson::{oid::ObjectId, DateTime};
+use rand::RngCore;
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, ED25519};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+pub const WEBAUTHN_CHALLENGE_TTL_MINUTES: i64 = 5;
+pub const WEBAUTHN_TIMEOUT_MS: u64 = 300_000;
+
+const COSE_ALG_ES256: i64 = -7;
+const COSE_ALG_EDDSA: i64 = -8;
+const COSE_KTY_OKP: i64 = 1;
+const COSE_KTY_EC2: i64 = 2;
+const COSE_CRV_P256: i64 = 1;
+const COSE_CRV_ED25519: i64 = 6;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+#[derive(Debug)]
+pub enum WebauthnError {
+    RepositoryError(RepositoryError),
+    InvalidResponse(String),
+    VerificationFailed(String),
+    UnsupportedAlgorithm(i64),
+}
+
+impl Display for WebauthnError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            WebauthnError::RepositoryError(err) => write!(f, "RepositoryError: {}", err),
+            WebauthnError::InvalidResponse(err) => write!(f, "InvalidResponse: {}", err),
+            WebauthnError::VerificationFailed(err) => write!(f, "VerificationFailed: {}", err),
+            WebauthnError::UnsupportedAlgorithm(alg) => {
+                write!(f, "UnsupportedAlgorithm: {}", alg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WebauthnError {}
+
+fn invalid(reason: &str) -> WebauthnError {
+    WebauthnError::InvalidResponse(reason.to_string())
+}
+
+fn failed(reason: &str) -> WebauthnError {
+    WebauthnError::VerificationFailed(reason.to_string())
+}
+
+fn decode(value: &str, field: &str) -> Result<Vec<u8>, WebauthnError> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|_| invalid(&format!("{} is not base64url", field)))
+}
+
+#[derive(Serialize)]
+pub struct RpEntity {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Serialize)]
+pub struct CredentialParameter {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub alg: i64,
+}
+
+#[derive(Serialize)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transports: Option<Vec<String>>,
+}
+
+impl From<&Passkey> for CredentialDescriptor {
+    fn from(passkey: &Passkey) -> Self {
+        Self {
+            kind: "public-key",
+            id: passkey.credential_id.clone(),
+            transports: passkey.transports.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    pub resident_key: &'static str,
+    pub user_verification: &'static str,
+}
+
+/// `PublicKeyCredentialCreationOptions` in the JSON form accepted by
+/// `PublicKeyCredential.parseCreationOptionsFromJSON`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+    pub rp: RpEntity,
+    pub user: UserEntity,
+    pub challenge: String,
+    pub pub_key_cred_params: Vec<CredentialParameter>,
+    pub timeout: u64,
+    pub exclude_credentials: Vec<CredentialDescriptor>,
+    pub authenticator_selection: AuthenticatorSelection,
+    pub attestation: &'static str,
+}
+
+/// `PublicKeyCredentialRequestOptions` in the JSON form accepted by
+/// `PublicKeyCredential.parseRequestOptionsFromJSON`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+    pub challenge: String,
+    pub timeout: u64,
+    pub rp_id: String,
+    pub allow_credentials: Vec<CredentialDescriptor>,
+    pub user_verification: &'static str,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+    #[serde(default)]
+    pub transports: Option<Vec<String>>,
+}
+
+/// A `PublicKeyCredential` returned by `navigator.credentials.create()`,
+/// serialised with `toJSON()`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationCredential {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(default)]
+    pub user_handle: Option<String>,
+}
+
+/// A `PublicKeyCredential` returned by `navigator.credentials.get()`,
+/// serialised with `toJSON()`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticationCredential {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+/// Returns the challenge a client signed, so the stored ceremony can be
+/// looked up before the response is verified against it.
+pub fn client_challenge(client_data_json: &str) -> Result<String, WebauthnError> {
+    let raw = decode(client_data_json, "clientDataJSON")?;
+    let client_data: ClientData =
+        serde_json::from_slice(&raw).map_err(|_| invalid("malformed clientDataJSON"))?;
+    Ok(client_data.challenge)
+}
+
+/// The parts of authenticator data the ceremonies check.
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    rest: &'a [u8],
+}
+
+impl<'a> AuthenticatorData<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, WebauthnError> {
+        if data.len() < 37 {
+            return Err(invalid("authenticator data is too short"));
+        }
+        Ok(Self {
+            rp_id_hash: &data[..32],
+            flags: data[32],
+            sign_count: u32::from_be_bytes([data[33], data[34], data[35], data[36]]),
+            rest: &data[37..],
+        })
+    }
+}
+
+fn cose_field(key: &[(Value, Value)], label: i64) -> Option<&Value> {
+    key.iter()
+        .find(|(name, _)| name.as_integer() == Some(label.into()))
+        .map(|(_, value)| value)
+}
+
+fn cose_int(key: &[(Value, Value)], label: i64) -> Result<i64, WebauthnError> {
+    cose_field(key, label)
+        .and_then(Value::as_integer)
+        .and_then(|value| i64::try_from(value).ok())
+        .ok_or_else(|| invalid("malformed credential public key"))
+}
+
+fn cose_bytes(key: &[(Value, Value)], label: i64) -> Result<&[u8], WebauthnError> {
+    cose_field(key, label)
+        .and_then(Value::as_bytes)
+        .map(Vec::as_slice)
+        .filter(|bytes| bytes.len() == 32)
+        .ok_or_else(|| invalid("malformed credential public key"))
+}
+
+/// Extracts the algorithm and raw public key from a COSE_Key. Only ES256
+/// and EdDSA are offered in the creation options, so only they are accepted.
+fn parse_public_key(cose_key: &[u8]) -> Result<(i64, Vec<u8>), WebauthnError> {
+    let value: Value = ciborium::de::from_reader(cose_key)
+        .map_err(|_| invalid("malformed credential public key"))?;
+    let key = value
+        .as_map()
+        .ok_or_else(|| invalid("malformed credential public key"))?;
+
+    let algorithm = cose_int(key, 3)?;
+    match (algorithm, cose_int(key, 1)?, cose_int(key, -1)?) {
+        (COSE_ALG_ES256, COSE_KTY_EC2, COSE_CRV_P256) => {
+            let mut point = vec![0x04];
+            point.extend_from_slice(cose_bytes(key, -2)?);
+            point.extend_from_slice(cose_bytes(key, -3)?);
+            Ok((algorithm, point))
+        }
+        (COSE_ALG_EDDSA, COSE_KTY_OKP, COSE_CRV_ED25519) => {
+            Ok((algorithm, cose_bytes(key, -2)?.to_vec()))
+        }
+        _ => Err(WebauthnError::UnsupportedAlgorithm(algorithm)),
+    }
+}
+
+/// The relying party this server acts as. `origin` is the exact origin the
+/// browser reports for the front end.
+#[derive(Clone)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+    pub origin: String,
+}
+
+impl RelyingParty {
+    pub fn creation_options(
+        &self,
+        user_id: ObjectId,
+        email: &str,
+        challenge: String,
+        existing: &[Passkey],
+    ) -> CreationOptions {
+        CreationOptions {
+            rp: RpEntity {
+                id: self.id.clone(),
+                name: self.name.clone(),
+            },
+            user: UserEntity {
+                id: URL_SAFE_NO_PAD.encode(user_id.bytes()),
+                name: email.to_string(),
+                display_name: email.to_string(),
+            },
+            challenge,
+            pub_key_cred_params: vec![
+                CredentialParameter {
+                    kind: "public-key",
+                    alg: COSE_ALG_ES256,
+                },
+                CredentialParameter {
+                    kind: "public-key",
+                    alg: COSE_ALG_EDDSA,
+                },
+            ],
+            timeout: WEBAUTHN_TIMEOUT_MS,
+            exclude_credentials: existing.iter().map(CredentialDescriptor::from).collect(),
+            authenticator_selection: AuthenticatorSelection {
+                resident_key: "preferred",
+                user_verification: "preferred",
+            },
+            attestation: "none",
+        }
+    }
+
+    /// Passwordless logins offer no credentials so the authenticator picks a
+    /// discoverable one, and require user verification since the passkey is
+    /// the only factor.
+    pub fn request_options(&self, challenge: String, allowed: &[Passkey]) -> RequestOptions {
+        RequestOptions {
+            challenge,
+            timeout: WEBAUTHN_TIMEOUT_MS,
+            rp_id: self.id.clone(),
+            allow_credentials: allowed.iter().map(CredentialDescriptor::from).collect(),
+            user_verification: if allowed.is_empty() {
+                "required"
+            } else {
+                "preferred"
+            },
+        }
+    }
+
+    fn verify_client_data(
+        &self,
+        client_data_json: &[u8],
+        kind: &str,
+        challenge: &str,
+    ) -> Result<(), WebauthnError> {
+        let client_data: ClientData = serde_json::from_slice(client_data_json)
+            .map_err(|_| invalid("malformed clientDataJSON"))?;
+        if client_data.kind != kind {
+            return Err(failed("unexpected ceremony type"));
+        }
+        if client_data.challenge != challenge {
+            return Err(failed("challenge mismatch"));
+        }
+        if client_data.origin != self.origin {
+            return Err(failed("origin mismatch"));
+        }
+        Ok(())
+    }
+
+    fn verify_authenticator_data(
+        &self,
+        data: &AuthenticatorData,
+        require_user_verification: bool,
+    ) -> Result<(), WebauthnError> {
+        if data.rp_id_hash != Sha256::digest(self.id.as_bytes()).as_slice() {
+            return Err(failed("relying party mismatch"));
+        }
+        if data.flags & FLAG_USER_PRESENT == 0 {
+            return Err(failed("user not present"));
+        }
+        if require_user_verification && data.flags & FLAG_USER_VERIFIED == 0 {
+            return Err(failed("user not verified"));
+        }
+        Ok(())
+    }
+
+    /// Verifies a registration ceremony and returns the credential to store.
+    /// Attestation is not requested, so any attestation statement is ignored.
+    pub fn verify_registration(
+        &self,
+        credential: &RegistrationCredential,
+        challenge: &str,
+    ) -> Result<Passkey, WebauthnError> {
+        if credential.kind != "public-key" {
+            return Err(invalid("unexpected credential type"));
+        }
+        let client_data_json = decode(&credential.response.client_data_json, "clientDataJSON")?;
+        self.verify_client_data(&client_data_json, "webauthn.create", challenge)?;
+
+        let attestation_object =
+            decode(&credential.response.attestation_object, "attestationObject")?;
+        let attestation: Value = ciborium::de::from_reader(attestation_object.as_slice())
+            .map_err(|_| invalid("malformed attestationObject"))?;
+        let auth_data = attestation
+            .as_map()
+            .and_then(|fields| {
+                fields
+                    .iter()
+                    .find(|(name, _)| name.as_text() == Some("authData"))
+            })
+            .and_then(|(_, value)| value.as_bytes())
+            .ok_or_else(|| invalid("malformed attestationObject"))?;
+
+        let data = AuthenticatorData::parse(auth_data)?;
+        self.verify_authenticator_data(&data, false)?;
+        if data.flags & FLAG_ATTESTED_CREDENTIAL == 0 {
+            return Err(invalid("no attested credential data"));
+        }
+
+        // aaguid (16) || credential id length (2) || credential id || COSE_Key
+        if data.rest.len() < 18 {
+            return Err(invalid("attested credential data is too short"));
+        }
+        let id_len = u16::from_be_bytes([data.rest[16], data.rest[17]]) as usize;
+        let credential_id = data
+            .rest
+            .get(18..18 + id_len)
+            .ok_or_else(|| invalid("attested credential data is too short"))?;
+        if URL_SAFE_NO_PAD.encode(credential_id) != credential.id.trim_end_matches('=') {
+            return Err(invalid("credential id mismatch"));
+        }
+
+        // The COSE_Key may be followed by extension data, so read exactly one
+        // CBOR item and keep the bytes it spanned.
+        let mut remaining = &data.rest[18 + id_len..];
+        let before = remaining.len();
+        let _: Value = ciborium::de::from_reader(&mut remaining)
+            .map_err(|_| invalid("malformed credential public key"))?;
+        let cose_key = &data.rest[18 + id_len..18 + id_len + before - remaining.len()];
+        let (algorithm, _) = parse_public_key(cose_key)?;
+
+        Ok(Passkey {
+            credential_id: URL_SAFE_NO_PAD.encode(credential_id),
+            public_key: URL_SAFE_NO_PAD.encode(cose_key),
+            algorithm,
+            sign_count: data.sign_count,
+            transports: credential.response.transports.clone(),
+            name: None,
+            created_at: Some(Utc::now().timestamp() as u64),
+            last_used_at: None,
+        })
+    }
+
+    /// Verifies an authentication ceremony against a stored credential and
+    /// returns the new signature counter.
+    pub fn verify_authentication(
+        &self,
+        passkey: &Passkey,
+        credential: &AuthenticationCredential,
+        challenge: &str,
+        require_user_verification: bool,
+    ) -> Result<u32, WebauthnError> {
+        if credential.kind != "public-key" {
+            return Err(invalid("unexpected credential type"));
+        }
+        let client_data_json = decode(&credential.response.client_data_json, "clientDataJSON")?;
+        self.verify_client_data(&client_data_json, "webauthn.get", challenge)?;
+
+        let auth_data = decode(&credential.response.authenticator_data, "authenticatorData")?;
+        let data = AuthenticatorData::parse(&auth_data)?;
+        self.verify_authenticator_data(&data, require_user_verification)?;
+
+        let cose_key = decode(&passkey.public_key, "public key")?;
+        let (algorithm, public_key) = parse_public_key(&cose_key)?;
+        let signature = decode(&credential.response.signature, "signature")?;
+        let mut signed = auth_data.clone();
+        signed.extend_from_slice(&Sha256::digest(&client_data_json));
+        let verified = match algorithm {
+            COSE_ALG_ES256 => UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &public_key)
+                .verify(&signed, &signature),
+            COSE_ALG_EDDSA => {
+                UnparsedPublicKey::new(&ED25519, &public_key).verify(&signed, &signature)
+            }
+            _ => return Err(WebauthnError::UnsupportedAlgorithm(algorithm)),
+        };
+        if verified.is_err() {
+            return Err(failed("bad signature"));
+        }
+
+        // A counter that fails to advance suggests a cloned authenticator.
+        // Authenticators that do not count always report zero.
+        if (data.sign_count != 0 || passkey.sign_count != 0)
+            && data.sign_count <= passkey.sign_count
+        {
+            return Err(failed("signature counter did not increase"));
+        }
+
+        Ok(data.sign_count)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Ceremony {
+    Registration,
+    Authentication,
+}
+
+impl Ceremony {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Ceremony::Registration => "registration",
+            Ceremony::Authentication => "authentication",
+        }
+    }
+}
+
+/// An outstanding ceremony. The challenge is handed to the client and comes
+/// back inside `clientDataJSON`, so it doubles as the lookup key.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WebauthnChallenge {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub challenge_hash: String,
+    pub ceremony: Ceremony,
+    /// The user the ceremony is bound to. `None` for passwordless logins,
+    /// where the credential identifies the user.
+    pub user_id: Option<ObjectId>,
+    pub issue_tokens: bool,
+    pub created_at: Option<u64>,
+    pub expires_at: DateTime,
+}
+
+impl WebauthnChallenge {
+    fn hash(challenge: &str) -> String {
+        format!("{:x}", Sha256::digest(challenge.as_bytes()))
+    }
+
+    pub async fn create(
+        challenges: &dyn WebauthnChallengeRepository,
+        ceremony: Ceremony,
+        user_id: Option<ObjectId>,
+        issue_tokens: bool,
+    ) -> Result<String, WebauthnError> {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let challenge = URL_SAFE_NO_PAD.encode(bytes);
+        let now = Utc::now();
+        let record = WebauthnChallenge {
+            id: None,
+            challenge_hash: Self::hash(&challenge),
+            ceremony,
+            user_id,
+            issue_tokens,
+            created_at: Some(now.timestamp() as u64),
+            expires_at: DateTime::from_millis(
+                (now + Duration::try_minutes(WEBAUTHN_CHALLENGE_TTL_MINUTES).unwrap())
+                    .timestamp_millis(),
+            ),
+        };
+
+        challenges
+            .insert(&record)
+            .await
+            .map_err(WebauthnError::RepositoryError)?;
+        Ok(challenge)
+    }
+
+    /// Consumes a live challenge, so each one can be answered at most once.
+    pub async fn take(
+        challenges: &dyn WebauthnChallengeRepository,
+        challenge: &str,
+        ceremony: Ceremony,
+    ) -> Result<Option<Self>, WebauthnError> {
+        challenges
+            .take(&Self::hash(challenge), ceremony)
+            .await
+            .map_err(WebauthnError::RepositoryError)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+    /// A software authenticator holding a single ES256 credential, playing the
+    /// browser's part of both ceremonies.
+    pub struct SoftAuthenticator {
+        key_pair: EcdsaKeyPair,
+        credential_id: Vec<u8>,
+        sign_count: u32,
+    }
+
+    impl SoftAuthenticator {
+        pub fn new() -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 =
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+            let key_pair =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+                    .unwrap();
+            Self {
+                key_pair,
+                credential_id: rand::random::<[u8; 16]>().to_vec(),
+                sign_count: 0,
+            }
+        }
+
+        fn client_data(kind: &str, challenge: &str, origin: &str) -> Vec<u8> {
+            serde_json::json!({ "type": kind, "challenge": challenge, "origin": origin })
+                .to_string()
+                .into_bytes()
+        }
+
+        fn authenticator_data(&mut self, rp_id: &str, flags: u8) -> Vec<u8> {
+            self.sign_count += 1;
+            let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+            data.push(flags);
+            data.extend_from_slice(&self.sign_count.to_be_bytes());
+            data
+        }
+
+        pub fn create(
+            &mut self,
+            rp_id: &str,
+            challenge: &str,
+            origin: &str,
+        ) -> RegistrationCredential {
+            let point = self.key_pair.public_key().as_ref();
+            let cose_key = Value::Map(vec![
+                (Value::from(1), Value::from(COSE_KTY_EC2)),
+                (Value::from(3), Value::from(COSE_ALG_ES256)),
+                (Value::from(-1), Value::from(COSE_CRV_P256)),
+                (Value::from(-2), Value::Bytes(point[1..33].to_vec())),
+                (Value::from(-3), Value::Bytes(point[33..].to_vec())),
+            ]);
+
+            let mut auth_data = self.authenticator_data(
+                rp_id,
+                FLAG_USER_PRESENT | FLAG_USER_VERIFIED | FLAG_ATTESTED_CREDENTIAL,
+            );
+            auth_data.extend_from_slice(&[0u8; 16]);
+            auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            auth_data.extend_from_slice(&self.credential_id);
+            ciborium::ser::into_writer(&cose_key, &mut auth_data).unwrap();
+
+            let attestation = Value::Map(vec![
+                (Value::from("fmt"), Value::from("none")),
+                (Value::from("attStmt"), Value::Map(vec![])),
+                (Value::from("authData"), Value::Bytes(auth_data)),
+            ]);
+            let mut attestation_object = Vec::new();
+            ciborium::ser::into_writer(&attestation, &mut attestation_object).unwrap();
+
+            RegistrationCredential {
+                id: URL_SAFE_NO_PAD.encode(&self.credential_id),
+                kind: "public-key".to_string(),
+                response: AttestationResponse {
+                    client_data_json: URL_SAFE_NO_PAD.encode(Self::client_data(
+                        "webauthn.create",
+                        challenge,
+                        origin,
+                    )),
+                    attestation_object: URL_SAFE_NO_PAD.encode(attestation_object),
+                    transports: Some(vec!["internal".to_string()]),
+                },
+            }
+        }
+
+        pub fn get(
+            &mut self,
+            rp_id: &str,
+            challenge: &str,
+            origin: &str,
+        ) -> AuthenticationCredential {
+            let client_data = Self::client_data("webauthn.get", challenge, origin);
+            let auth_data = self.authenticator_data(rp_id, FLAG_USER_PRESENT | FLAG_USER_VERIFIED);
+            let mut signed = auth_data.clone();
+            signed.extend_from_slice(&Sha256::digest(&client_data));
+            let signature = self.key_pair.sign(&SystemRandom::new(), &signed).unwrap();
+
+            AuthenticationCredential {
+                id: URL_SAFE_NO_PAD.encode(&self.credential_id),
+                kind: "public-key".to_string(),
+                response: AssertionResponse {
+                    client_data_json: URL_SAFE_NO_PAD.encode(client_data),
+                    authenticator_data: URL_SAFE_NO_PAD.encode(auth_data),
+                    signature: URL_SAFE_NO_PAD.encode(signature.as_ref()),
+                    user_handle: None,
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::SoftAuthenticator;
+    use super::*;
+
+    const ORIGIN: &str = "https://auth.example.com";
+
+    fn relying_party() -> RelyingParty {
+        RelyingParty {
+            id: "auth.example.com".to_string(),
+            name: "auth-rs".to_string(),
+            origin: ORIGIN.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_software_authenticator_ceremonies() {
+        let rp = relying_party();
+        let mut authenticator = SoftAuthenticator::new();
+
+        let registration = authenticator.create(&rp.id, "register-challenge", ORIGIN);
+        assert_eq!(
+            client_challenge(&registration.response.client_data_json).unwrap(),
+            "register-challenge"
+        );
+        assert!(rp
+            .verify_registration(&registration, "other-challenge")
+            .is_err());
+        let mut passkey = rp
+            .verify_registration(&registration, "register-challenge")
+            .unwrap();
+        assert_eq!(passkey.algorithm, COSE_ALG_ES256);
+        assert_eq!(passkey.sign_count, 1);
+
+        let assertion = authenticator.get(&rp.id, "login-challenge", ORIGIN);
+        let sign_count = rp
+            .verify_authentication(&passkey, &assertion, "login-challenge", true)
+            .unwrap();
+        assert_eq!(sign_count, 2);
+
+        // Replaying the same assertion fails once the counter is stored.
+        passkey.sign_count = sign_count;
+        assert!(rp
+            .verify_authentication(&passkey, &assertion, "login-challenge", true)
+            .is_err());
+    }
+
+    #[test]
+    fn test_rejects_foreign_origin_and_relying_party() {
+        let rp = relying_party();
+        let mut authenticator = SoftAuthenticator::new();
+
+        let phished = authenticator.create(&rp.id, "challenge", "https://evil.example.com");
+        assert!(rp.verify_registration(&phished, "challenge").is_err());
+
+        let other_rp = authenticator.create("evil.example.com", "challenge", ORIGIN);
+        assert!(rp.verify_registration(&other_rp, "challenge").is_err());
+    }
+}
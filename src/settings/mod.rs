@@ -0,0 +1,787 @@
+//! Typed settings, loaded once at startup from layers: built-in defaults,
+//! an optional TOML or YAML file named by `CONFIG_FILE`, the encrypted
+//! secrets file, and the environment (including `.env`), where any variable
+//! may instead be read from the file named by `<NAME>_FILE`. Every missing or
+//! malformed value is collected into one report rather than failing on the
+//! first.
+
+pub mod secrets;
+
+pub use secrets::Secret;
+
+use crate::services::crypto::SECRET_KEY_LEN;
+use crate::services::keys::parse_algorithm;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dotenvy::dotenv;
+use jsonwebtoken::Algorithm;
+use lettre::message::dkim::DkimSigningAlgorithm;
+use lettre::message::Mailbox;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::Path;
+use std::str::FromStr;
+use std::{env, fs};
+use tracing_subscriber::EnvFilter;
+
+const DEV_FROM_EMAIL: &str = "auth-rs <no-reply@localhost>";
+
+/// Every setting: its key in the settings file and the environment variable
+/// that overrides it.
+const KEYS: &[(&str, &str)] = &[
+    ("rust_env", "RUST_ENV"),
+    ("server.host", "SERVER_HOST"),
+    ("server.port", "SERVER_PORT"),
+    ("mongo.uri", "MONGO_URI"),
+    ("mongo.database", "MONGO_DATABASE"),
+    ("session.secret", "SESSION_SECRET"),
+    ("jwt.algorithm", "JWT_ALGORITHM"),
+    ("encryption.key", "ENCRYPTION_KEY"),
+    ("webauthn.rp_id", "WEBAUTHN_RP_ID"),
+    ("webauthn.rp_name", "WEBAUTHN_RP_NAME"),
+    ("webauthn.rp_origin", "WEBAUTHN_RP_ORIGIN"),
+    ("app_url", "APP_URL"),
+    ("otp.delivery", "OTP_DELIVERY"),
+    ("email_webhook.secret", "EMAIL_WEBHOOK_SECRET"),
+    ("rate_limit.store", "RATE_LIMIT_STORE"),
+    ("mail.transport", "MAIL_TRANSPORT"),
+    ("mail.dir", "MAIL_DIR"),
+    ("mail.from", "FROM_EMAIL"),
+    ("smtp.server", "SMTP_SERVER"),
+    ("smtp.port", "SMTP_PORT"),
+    ("smtp.username", "SMTP_USERNAME"),
+    ("smtp.password", "SMTP_PASSWORD"),
+    ("smtp.pool_size", "SMTP_POOL_SIZE"),
+    ("dkim.private_key", "DKIM_PRIVATE_KEY"),
+    ("dkim.algorithm", "DKIM_ALGORITHM"),
+    ("dkim.selector", "DKIM_SELECTOR"),
+    ("dkim.domain", "DKIM_DOMAIN"),
+    ("log.level", "LOG_LEVEL"),
+    ("log.format", "LOG_FORMAT"),
+];
+
+const DEFAULTS: &[(&str, &str)] = &[
+    ("rust_env", "development"),
+    ("server.host", "127.0.0.1"),
+    ("server.port", "8080"),
+    ("jwt.algorithm", "ES256"),
+    ("webauthn.rp_name", "auth-rs"),
+    ("rate_limit.store", "memory"),
+    ("mail.dir", "target/mail"),
+    ("smtp.pool_size", "10"),
+    ("dkim.algorithm", "rsa"),
+    ("log.level", "info"),
+];
+
+#[derive(Debug)]
+pub enum SettingsError {
+    FileError(String),
+    /// Every problem found, one per line.
+    Invalid(Vec<String>),
+}
+
+impl Display for SettingsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            SettingsError::FileError(e) => write!(f, "FileError: {}", e),
+            SettingsError::Invalid(problems) => {
+                write!(f, "Invalid: {} problem(s)", problems.len())?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OtpDeliveryMode {
+    Email,
+    Capture,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MailTransport {
+    Smtp,
+    File,
+    Memory,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitBackend {
+    Memory,
+    Mongo,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerSettings {
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Clone, Debug)]
+pub struct MongoSettings {
+    /// Usually carries credentials.
+    pub uri: Secret,
+    pub database: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct WebauthnSettings {
+    pub rp_name: String,
+    pub rp_id: Option<String>,
+    pub rp_origin: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SmtpSettings {
+    pub server: String,
+    pub port: u16,
+    pub username: String,
+    pub password: Secret,
+    pub pool_size: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct DkimSettings {
+    pub private_key: Secret,
+    pub algorithm: DkimSigningAlgorithm,
+    pub selector: String,
+    /// Defaults to the domain of the sender.
+    pub domain: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MailSettings {
+    pub transport: MailTransport,
+    /// Where the file transport writes messages.
+    pub dir: String,
+    pub from: Mailbox,
+    /// Set whenever the transport is SMTP.
+    pub smtp: Option<SmtpSettings>,
+    pub dkim: Option<DkimSettings>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LogSettings {
+    /// An `EnvFilter` directive, e.g. `info` or `info,hello_world=debug`.
+    pub level: String,
+    /// Defaults to JSON in production and text elsewhere.
+    pub format: LogFormat,
+}
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub rust_env: String,
+    pub server: ServerSettings,
+    pub mongo: MongoSettings,
+    /// At least 64 bytes. Required in production; elsewhere a random key is
+    /// generated and sessions do not survive a restart.
+    pub session_secret: Option<Secret>,
+    pub jwt_algorithm: Algorithm,
+    /// Decoded `ENCRYPTION_KEY`. Required in production.
+    pub encryption_key: Option<Secret<Vec<u8>>>,
+    pub webauthn: WebauthnSettings,
+    /// Public base URL of the front end, without a trailing slash.
+    /// Required in production.
+    pub app_url: Option<String>,
+    /// Email unless `OTP_DELIVERY=capture`, which is refused in production.
+    pub otp_delivery: OtpDeliveryMode,
+    /// At least 32 bytes. Without it the bounce webhook is not mounted.
+    pub email_webhook_secret: Option<Secret>,
+    pub rate_limit_store: RateLimitBackend,
+    pub mail: MailSettings,
+    pub log: LogSettings,
+}
+
+/// Where a value came from, so problems point at what to fix.
+#[derive(Clone)]
+enum Source {
+    Default,
+    File,
+    SecretsFile,
+    Env(&'static str),
+    EnvFile(&'static str),
+}
+
+/// The merged layers as raw strings, keyed like the settings file.
+struct Layers {
+    values: HashMap<&'static str, (String, Source)>,
+    problems: Vec<String>,
+}
+
+impl Layers {
+    fn new(
+        file: Option<Value>,
+        secrets: Option<Value>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Self {
+        let mut layers = Layers {
+            values: HashMap::new(),
+            problems: Vec::new(),
+        };
+        for (key, value) in DEFAULTS {
+            layers
+                .values
+                .insert(key, (value.to_string(), Source::Default));
+        }
+        if let Some(file) = file {
+            layers.merge_file("", &file, Source::File);
+        }
+        if let Some(secrets) = secrets {
+            layers.merge_file("", &secrets, Source::SecretsFile);
+        }
+        for (key, name) in KEYS {
+            match secrets::env_or_file(name, &var) {
+                Ok(Some((value, false))) => {
+                    layers.values.insert(key, (value, Source::Env(name)));
+                }
+                Ok(Some((value, true))) => {
+                    layers.values.insert(key, (value, Source::EnvFile(name)));
+                }
+                Ok(None) => {}
+                Err(err) => layers.problems.push(format!("{}: {}", key, err)),
+            }
+        }
+        layers
+    }
+
+    fn merge_file(&mut self, prefix: &str, value: &Value, source: Source) {
+        match value {
+            Value::Object(map) => {
+                for (name, value) in map {
+                    let path = if prefix.is_empty() {
+                        name.clone()
+                    } else {
+                        format!("{}.{}", prefix, name)
+                    };
+                    self.merge_file(&path, value, source.clone());
+                }
+            }
+            _ => {
+                let Some((key, _)) = KEYS.iter().find(|(key, _)| *key == prefix) else {
+                    self.problems.push(format!(
+                        "{}: unknown setting",
+                        Self::describe(prefix, &source)
+                    ));
+                    return;
+                };
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    Value::Number(value) => value.to_string(),
+                    Value::Bool(value) => value.to_string(),
+                    _ => {
+                        self.problems.push(format!(
+                            "{}: expected a value",
+                            Self::describe(key, &source)
+                        ));
+                        return;
+                    }
+                };
+                self.values.insert(key, (value, source));
+            }
+        }
+    }
+
+    fn describe(key: &str, source: &Source) -> String {
+        match source {
+            Source::Default => format!("{} (default)", key),
+            Source::File => format!("{} in the settings file", key),
+            Source::SecretsFile => format!("{} in the secrets file", key),
+            Source::Env(name) => format!("{} from {}", key, name),
+            Source::EnvFile(name) => format!("{} from {}_FILE", key, name),
+        }
+    }
+
+    fn env_name(key: &str) -> &'static str {
+        KEYS.iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, env)| *env)
+            .unwrap_or_default()
+    }
+
+    fn invalid(&mut self, key: &str, message: impl Display) {
+        let source = self
+            .values
+            .get(key)
+            .map(|(_, source)| source.clone())
+            .unwrap_or(Source::Default);
+        self.problems
+            .push(format!("{}: {}", Self::describe(key, &source), message));
+    }
+
+    fn missing(&mut self, key: &str, reason: &str) {
+        self.problems.push(format!(
+            "{}: missing{}, set {} or {} in the settings file",
+            key,
+            reason,
+            Self::env_name(key),
+            key
+        ));
+    }
+
+    fn optional<T>(
+        &mut self,
+        key: &str,
+        parse: impl FnOnce(&str) -> Result<T, String>,
+    ) -> Option<T> {
+        let (value, source) = self.values.get(key)?.clone();
+        match parse(&value) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.problems
+                    .push(format!("{}: {}", Self::describe(key, &source), err));
+                None
+            }
+        }
+    }
+
+    fn string(&mut self, key: &str) -> Option<String> {
+        self.optional(key, |value| Ok(value.to_string()))
+    }
+
+    fn parsed<T: FromStr>(&mut self, key: &str) -> Option<T>
+    where
+        T::Err: Display,
+    {
+        self.optional(key, |value| {
+            value.parse::<T>().map_err(|err| err.to_string())
+        })
+    }
+
+    /// A value that must be present, with `reason` saying when.
+    fn required<T>(
+        &mut self,
+        key: &str,
+        reason: &str,
+        value: impl FnOnce(&mut Self, &str) -> Option<T>,
+    ) -> Option<T> {
+        if !self.values.contains_key(key) {
+            self.missing(key, reason);
+            return None;
+        }
+        value(self, key)
+    }
+}
+
+fn one_of<'a, T: Copy>(choices: &'a [(&'a str, T)]) -> impl Fn(&str) -> Result<T, String> + 'a {
+    move |value| {
+        choices
+            .iter()
+            .find(|(name, _)| *name == value)
+            .map(|(_, choice)| *choice)
+            .ok_or_else(|| {
+                let names: Vec<&str> = choices.iter().map(|(name, _)| *name).collect();
+                format!(
+                    "unknown value {}, expected one of {}",
+                    value,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+fn min_len(min: usize) -> impl Fn(&str) -> Result<String, String> {
+    move |value| {
+        if value.len() >= min {
+            Ok(value.to_string())
+        } else {
+            Err(format!("must be at least {} bytes long", min))
+        }
+    }
+}
+
+impl Settings {
+    /// Loads `.env`, the file named by `CONFIG_FILE`, the secrets file named
+    /// by `SECRETS_FILE` and the environment.
+    pub fn load() -> Result<Self, SettingsError> {
+        dotenv().ok();
+        let var = |name: &str| env::var(name).ok();
+        let file = match env::var("CONFIG_FILE") {
+            Ok(path) => Some(read_file(Path::new(&path))?),
+            Err(_) => None,
+        };
+        let secrets = match (env::var("SECRETS_FILE"), secrets::master_key(&var)?) {
+            (Ok(path), Some(key)) => {
+                Some(secrets::decrypt_file(&read_file(Path::new(&path))?, &key)?)
+            }
+            (Ok(_), None) => {
+                return Err(SettingsError::FileError(
+                    "SECRETS_FILE is set but SECRETS_KEY is not".to_string(),
+                ))
+            }
+            (Err(_), _) => None,
+        };
+        Self::from_layers(file, secrets, var)
+    }
+
+    /// Builds settings from the parsed settings file, the decrypted secrets
+    /// file and an environment lookup.
+    pub fn from_layers(
+        file: Option<Value>,
+        secrets: Option<Value>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, SettingsError> {
+        let mut layers = Layers::new(file, secrets, var);
+        let l = &mut layers;
+
+        let rust_env = l.string("rust_env").unwrap_or_default();
+        let production = rust_env == "production";
+        let in_production = " in production";
+
+        let host = l.string("server.host");
+        let port = l.parsed::<u16>("server.port");
+        let mongo_uri = l.required("mongo.uri", "", Layers::string);
+        let mongo_database = l.required("mongo.database", "", Layers::string);
+
+        let session_secret = if production {
+            l.required("session.secret", in_production, |l, key| {
+                l.optional(key, min_len(64))
+            })
+            .map(Some)
+        } else {
+            Some(l.optional("session.secret", min_len(64)))
+        };
+        let jwt_algorithm = l.optional("jwt.algorithm", |value| {
+            parse_algorithm(value).map_err(|err| err.to_string())
+        });
+        let decode_key = |value: &str| {
+            let key = STANDARD.decode(value).map_err(|err| err.to_string())?;
+            if key.len() != SECRET_KEY_LEN {
+                return Err(format!("must decode to {} bytes", SECRET_KEY_LEN));
+            }
+            Ok(key)
+        };
+        let encryption_key = if production {
+            l.required("encryption.key", in_production, |l, key| {
+                l.optional(key, decode_key)
+            })
+            .map(Some)
+        } else {
+            Some(l.optional("encryption.key", decode_key))
+        };
+
+        let rp_name = l.string("webauthn.rp_name");
+        let (rp_id, rp_origin) = if production {
+            (
+                l.required("webauthn.rp_id", in_production, Layers::string),
+                l.required("webauthn.rp_origin", in_production, Layers::string),
+            )
+        } else {
+            (l.string("webauthn.rp_id"), l.string("webauthn.rp_origin"))
+        };
+        let app_url = if production {
+            l.required("app_url", in_production, Layers::string)
+        } else {
+            l.string("app_url")
+        }
+        .map(|url| url.trim_end_matches('/').to_string());
+
+        // Capturing codes exposes them over HTTP, so it is never the default.
+        let otp_delivery = l
+            .optional(
+                "otp.delivery",
+                one_of(&[
+                    ("email", OtpDeliveryMode::Email),
+                    ("capture", OtpDeliveryMode::Capture),
+                ]),
+            )
+            .unwrap_or(OtpDeliveryMode::Email);
+        if production && otp_delivery == OtpDeliveryMode::Capture {
+            l.invalid("otp.delivery", "capture is not allowed in production");
+        }
+        let email_webhook_secret = l.optional("email_webhook.secret", min_len(32));
+        let rate_limit_store = l.optional(
+            "rate_limit.store",
+            one_of(&[
+                ("memory", RateLimitBackend::Memory),
+                ("mongo", RateLimitBackend::Mongo),
+            ]),
+        );
+
+        let mail = Self::mail_settings(l, production);
+
+        let log_level = l.optional("log.level", |value| {
+            EnvFilter::try_new(value)
+                .map(|_| value.to_string())
+                .map_err(|err| err.to_string())
+        });
+        let log_format = l
+            .optional(
+                "log.format",
+                one_of(&[("text", LogFormat::Text), ("json", LogFormat::Json)]),
+            )
+            .unwrap_or(if production {
+                LogFormat::Json
+            } else {
+                LogFormat::Text
+            });
+
+        let problems = std::mem::take(&mut layers.problems);
+        let (
+            Some(host),
+            Some(port),
+            Some(uri),
+            Some(database),
+            Some(session_secret),
+            Some(jwt_algorithm),
+            Some(encryption_key),
+            Some(rp_name),
+            Some(rate_limit_store),
+            Some(mail),
+            Some(log_level),
+        ) = (
+            host,
+            port,
+            mongo_uri,
+            mongo_database,
+            session_secret,
+            jwt_algorithm,
+            encryption_key,
+            rp_name,
+            rate_limit_store,
+            mail,
+            log_level,
+        )
+        else {
+            return Err(SettingsError::Invalid(problems));
+        };
+        if !problems.is_empty() {
+            return Err(SettingsError::Invalid(problems));
+        }
+
+        Ok(Settings {
+            rust_env,
+            server: ServerSettings { host, port },
+            mongo: MongoSettings {
+                uri: Secret::new(uri),
+                database,
+            },
+            session_secret: session_secret.map(Secret::new),
+            jwt_algorithm,
+            encryption_key: encryption_key.map(Secret::new),
+            webauthn: WebauthnSettings {
+                rp_name,
+                rp_id,
+                rp_origin,
+            },
+            app_url,
+            otp_delivery,
+            email_webhook_secret: email_webhook_secret.map(Secret::new),
+            rate_limit_store,
+            mail,
+            log: LogSettings {
+                level: log_level,
+                format: log_format,
+            },
+        })
+    }
+
+    fn mail_settings(l: &mut Layers, production: bool) -> Option<MailSettings> {
+        // Production defaults to SMTP; elsewhere SMTP is used only when a
+        // server is configured, otherwise messages go to files.
+        let transport = l
+            .optional(
+                "mail.transport",
+                one_of(&[
+                    ("smtp", MailTransport::Smtp),
+                    ("file", MailTransport::File),
+                    ("memory", MailTransport::Memory),
+                ]),
+            )
+            .or_else(|| {
+                if production || l.values.contains_key("smtp.server") {
+                    Some(MailTransport::Smtp)
+                } else {
+                    Some(MailTransport::File)
+                }
+            });
+        let dir = l.string("mail.dir");
+        let parse_mailbox = |value: &str| value.parse::<Mailbox>().map_err(|err| err.to_string());
+        let from = if production {
+            l.required("mail.from", " in production", |l, key| {
+                l.optional(key, parse_mailbox)
+            })
+        } else {
+            l.optional("mail.from", parse_mailbox)
+                .or_else(|| DEV_FROM_EMAIL.parse().ok())
+        };
+
+        let smtp = if transport == Some(MailTransport::Smtp) {
+            let when = " for the smtp transport";
+            let server = l.required("smtp.server", when, Layers::string);
+            let port = l.required("smtp.port", when, Layers::parsed::<u16>);
+            let username = l.required("smtp.username", when, Layers::string);
+            let password = l.required("smtp.password", when, Layers::string);
+            let pool_size = l.parsed::<u32>("smtp.pool_size");
+            match (server, port, username, password, pool_size) {
+                (Some(server), Some(port), Some(username), Some(password), Some(pool_size)) => {
+                    Some(Some(SmtpSettings {
+                        server,
+                        port,
+                        username,
+                        password: Secret::new(password),
+                        pool_size,
+                    }))
+                }
+                _ => None,
+            }
+        } else {
+            Some(None)
+        };
+
+        let dkim = match l.string("dkim.private_key") {
+            Some(private_key) => {
+                let algorithm = l.optional(
+                    "dkim.algorithm",
+                    one_of(&[
+                        ("rsa", DkimSigningAlgorithm::Rsa),
+                        ("ed25519", DkimSigningAlgorithm::Ed25519),
+                    ]),
+                );
+                let selector = l.required(
+                    "dkim.selector",
+                    " when dkim.private_key is set",
+                    Layers::string,
+                );
+                let domain = l.string("dkim.domain");
+                match (algorithm, selector) {
+                    (Some(algorithm), Some(selector)) => Some(Some(DkimSettings {
+                        private_key: Secret::new(private_key),
+                        algorithm,
+                        selector,
+                        domain,
+                    })),
+                    _ => None,
+                }
+            }
+            None => Some(None),
+        };
+
+        Some(MailSettings {
+            transport: transport?,
+            dir: dir?,
+            from: from?,
+            smtp: smtp?,
+            dkim: dkim?,
+        })
+    }
+
+    pub fn is_production(&self) -> bool {
+        self.rust_env == "production"
+    }
+}
+
+/// Parses a settings file as TOML or YAML, by extension.
+fn read_file(path: &Path) -> Result<Value, SettingsError> {
+    let contents = fs::read_to_string(path).map_err(|err| {
+        SettingsError::FileError(format!("Error reading {}: {}", path.display(), err))
+    })?;
+    let parsed = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str::<Value>(&contents).map_err(|err| err.to_string()),
+        Some("yaml") | Some("yml") => {
+            serde_yaml::from_str::<Value>(&contents).map_err(|err| err.to_string())
+        }
+        _ => Err("expected a .toml, .yaml or .yml file".to_string()),
+    };
+    parsed.map_err(|err| {
+        SettingsError::FileError(format!("Error parsing {}: {}", path.display(), err))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_environment_overrides_file_overrides_defaults() {
+        let file = toml::from_str::<Value>(
+            r#"
+            [server]
+            port = 9000
+            host = "0.0.0.0"
+
+            [mongo]
+            uri = "mongodb://file:27017"
+            database = "auth"
+            "#,
+        )
+        .unwrap();
+        let settings = Settings::from_layers(
+            Some(file),
+            None,
+            vars(&[
+                ("SERVER_PORT", "9100"),
+                ("APP_URL", "https://app.example.com/"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(settings.server.port, 9100);
+        assert_eq!(settings.server.host, "0.0.0.0");
+        assert_eq!(settings.mongo.uri.expose(), "mongodb://file:27017");
+        assert_eq!(settings.jwt_algorithm, Algorithm::ES256);
+        assert_eq!(settings.app_url.as_deref(), Some("https://app.example.com"));
+        assert_eq!(settings.otp_delivery, OtpDeliveryMode::Email);
+        assert_eq!(settings.mail.transport, MailTransport::File);
+        assert_eq!(settings.log.format, LogFormat::Text);
+    }
+
+    #[test]
+    fn test_report_lists_every_problem() {
+        let file = serde_yaml::from_str::<Value>("server:\n  prot: 9000\n").unwrap();
+        let err = Settings::from_layers(
+            Some(file),
+            None,
+            vars(&[
+                ("RUST_ENV", "production"),
+                ("SERVER_PORT", "http"),
+                ("SESSION_SECRET", "short"),
+                ("OTP_DELIVERY", "capture"),
+                ("SMTP_SERVER", "smtp.example.com"),
+            ]),
+        )
+        .err()
+        .unwrap();
+
+        let SettingsError::Invalid(problems) = err else {
+            panic!("expected a report");
+        };
+        let report = problems.join("\n");
+        for expected in [
+            "server.prot in the settings file: unknown setting",
+            "server.port from SERVER_PORT: invalid digit",
+            "mongo.uri: missing, set MONGO_URI",
+            "session.secret from SESSION_SECRET: must be at least 64 bytes long",
+            "encryption.key: missing in production",
+            "app_url: missing in production",
+            "otp.delivery from OTP_DELIVERY: capture is not allowed in production",
+            "smtp.port: missing for the smtp transport",
+            "mail.from: missing in production",
+        ] {
+            assert!(
+                report.contains(expected),
+                "{} not in:\n{}",
+                expected,
+                report
+            );
+        }
+    }
+}
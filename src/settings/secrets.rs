@@ -0,0 +1,175 @@
+//! Secret values: the redacting [`Secret`] wrapper, `*_FILE` variables as
+//! Docker and Kubernetes mount secrets, and the encrypted secrets file.
+//!
+//! The secrets file has the layout of the settings file, but every value is
+//! a [`SecretBox`] ciphertext bound to its setting name, so values cannot be
+//! swapped between settings. It is named by `SECRETS_FILE` and decrypted
+//! with the base64 key in `SECRETS_KEY`.
+
+use super::SettingsError;
+use crate::services::crypto::{CryptoError, SecretBox};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::Value;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::fs;
+
+/// A value that must never be logged. `Debug` prints a placeholder; the
+/// value itself is only reachable through [`Secret::expose`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret<T = String>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> Debug for Secret<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "Secret([REDACTED])")
+    }
+}
+
+/// Reads `name`, or the file named by `name_FILE` with trailing newlines
+/// removed. Returns the value and whether it came from a file.
+pub(super) fn env_or_file(
+    name: &str,
+    var: &impl Fn(&str) -> Option<String>,
+) -> Result<Option<(String, bool)>, String> {
+    let file_var = format!("{}_FILE", name);
+    match (
+        var(name).filter(|value| !value.is_empty()),
+        var(&file_var).filter(|path| !path.is_empty()),
+    ) {
+        (Some(_), Some(_)) => Err(format!("both {} and {} are set", name, file_var)),
+        (Some(value), None) => Ok(Some((value, false))),
+        (None, Some(path)) => fs::read_to_string(&path)
+            .map(|value| Some((value.trim_end_matches(['\r', '\n']).to_string(), true)))
+            .map_err(|err| format!("{}: Error reading {}: {}", file_var, path, err)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// The secrets file key from `SECRETS_KEY` or `SECRETS_KEY_FILE`.
+pub fn master_key(
+    var: &impl Fn(&str) -> Option<String>,
+) -> Result<Option<SecretBox>, SettingsError> {
+    let Some((key, _)) = env_or_file("SECRETS_KEY", var).map_err(SettingsError::FileError)? else {
+        return Ok(None);
+    };
+    STANDARD
+        .decode(key.trim())
+        .map_err(|err| err.to_string())
+        .and_then(|key| SecretBox::new(&key).map_err(|err| err.to_string()))
+        .map(Some)
+        .map_err(|err| SettingsError::FileError(format!("Error parsing SECRETS_KEY: {}", err)))
+}
+
+/// Encrypts `value` for `setting`, e.g. `smtp.password`, as it is stored in
+/// the secrets file.
+pub fn encrypt_value(key: &SecretBox, setting: &str, value: &str) -> Result<String, CryptoError> {
+    key.encrypt(value.as_bytes(), setting.as_bytes())
+}
+
+/// Decrypts every value of a parsed secrets file, keeping its layout.
+pub(super) fn decrypt_file(value: &Value, key: &SecretBox) -> Result<Value, SettingsError> {
+    let mut problems = Vec::new();
+    let decrypted = decrypt_values("", value, key, &mut problems);
+    if problems.is_empty() {
+        Ok(decrypted)
+    } else {
+        Err(SettingsError::Invalid(problems))
+    }
+}
+
+fn decrypt_values(
+    prefix: &str,
+    value: &Value,
+    key: &SecretBox,
+    problems: &mut Vec<String>,
+) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(name, value)| {
+                    let path = if prefix.is_empty() {
+                        name.clone()
+                    } else {
+                        format!("{}.{}", prefix, name)
+                    };
+                    (name.clone(), decrypt_values(&path, value, key, problems))
+                })
+                .collect(),
+        ),
+        Value::String(ciphertext) => {
+            match key
+                .decrypt(ciphertext, prefix.as_bytes())
+                .map_err(|err| err.to_string())
+                .and_then(|plaintext| String::from_utf8(plaintext).map_err(|err| err.to_string()))
+            {
+                Ok(plaintext) => Value::String(plaintext),
+                Err(err) => {
+                    problems.push(format!("{} in the secrets file: {}", prefix, err));
+                    Value::Null
+                }
+            }
+        }
+        _ => {
+            problems.push(format!(
+                "{} in the secrets file: expected an encrypted value",
+                prefix
+            ));
+            Value::Null
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::crypto::SECRET_KEY_LEN;
+    use crate::settings::Settings;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_secrets_resolve_from_files_and_encrypted_file() {
+        let key = SecretBox::new(&[3u8; SECRET_KEY_LEN]).unwrap();
+        let secrets = serde_json::json!({
+            "smtp": { "password": encrypt_value(&key, "smtp.password", "hunter2").unwrap() },
+            // Encrypted for another setting, so it must not decrypt here.
+            "session": { "secret": encrypt_value(&key, "smtp.password", "x").unwrap() },
+        });
+        let err = decrypt_file(&secrets, &key).err().unwrap().to_string();
+        assert!(err.contains("session.secret in the secrets file: DecryptError"));
+
+        let secrets = serde_json::json!({
+            "smtp": { "password": encrypt_value(&key, "smtp.password", "hunter2").unwrap() },
+        });
+        let secrets = decrypt_file(&secrets, &key).unwrap();
+
+        let path = std::env::temp_dir().join(format!("auth-rs-mongo-uri-{}", std::process::id()));
+        fs::write(&path, "mongodb://user:pass@db:27017\n").unwrap();
+        let vars: HashMap<&str, String> = [
+            ("MONGO_URI_FILE", path.display().to_string()),
+            ("MONGO_DATABASE", "auth".to_string()),
+            ("SMTP_SERVER", "smtp.example.com".to_string()),
+            ("SMTP_PORT", "587".to_string()),
+            ("SMTP_USERNAME", "auth".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let settings =
+            Settings::from_layers(None, Some(secrets), |name| vars.get(name).cloned()).unwrap();
+
+        assert_eq!(settings.mongo.uri.expose(), "mongodb://user:pass@db:27017");
+        let smtp = settings.mail.smtp.as_ref().unwrap();
+        assert_eq!(smtp.password.expose(), "hunter2");
+        let debug = format!("{:?}", settings);
+        assert!(!debug.contains("hunter2") && !debug.contains("user:pass"));
+        assert!(debug.contains("Secret([REDACTED])"));
+    }
+}